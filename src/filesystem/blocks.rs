@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use zeroize::Zeroize;
+use zeroize::Zeroizing;
+
+/// File contents are stored in blocks of this size, except for the last block
+/// of a file, which is only as long as its data.
+pub const BLOCK_SIZE: u64 = 64 * 1024;
+
+/// Number of decrypted blocks kept in memory, 64 MiB in total.
+pub const DEFAULT_CACHE_BLOCKS: usize = 1024;
+
+/// Identifies a block by inode number and index within the file.
+pub type BlockKey = (u64, u64);
+
+/// Decrypted file blocks, with the blocks that were written but not yet
+/// stored marked as dirty.
+///
+/// A bounded cache evicts the least recently used block when it is full. An
+/// unbounded one holds filesystems that have no storage behind them.
+///
+/// Blocks hold plaintext, so their buffers are wiped whenever a block leaves
+/// the cache. Each buffer is allocated at full block size up front, so that
+/// writes never move a block and leave a copy behind.
+#[derive(Default)]
+pub struct BlockCache {
+    blocks: HashMap<BlockKey, CachedBlock>,
+    capacity: Option<usize>,
+    clock: u64,
+}
+
+struct CachedBlock {
+    data: Vec<u8>,
+    dirty: bool,
+    last_used: u64,
+}
+
+impl Drop for CachedBlock {
+    fn drop(&mut self) {
+        self.data.zeroize();
+    }
+}
+
+impl BlockCache {
+    pub fn bounded(capacity: usize) -> Self {
+        Self {
+            capacity: Some(capacity.max(1)),
+            ..Default::default()
+        }
+    }
+
+    pub fn contains(&self, key: &BlockKey) -> bool {
+        self.blocks.contains_key(key)
+    }
+
+    pub fn get(&mut self, key: &BlockKey) -> Option<&[u8]> {
+        let now = self.tick();
+        self.blocks.get_mut(key).map(|block| {
+            block.last_used = now;
+            &block.data[..]
+        })
+    }
+
+    /// Returns a block for writing, marking it as dirty.
+    pub fn get_mut(&mut self, key: &BlockKey) -> Option<&mut Vec<u8>> {
+        let now = self.tick();
+        self.blocks.get_mut(key).map(|block| {
+            block.last_used = now;
+            block.dirty = true;
+            &mut block.data
+        })
+    }
+
+    /// Inserts a block as it was read from storage, wiping `data` once it is
+    /// copied. Returns the dirty blocks that were evicted to make room, which
+    /// must be written back.
+    pub fn insert(
+        &mut self,
+        key: BlockKey,
+        mut data: Vec<u8>,
+    ) -> Vec<(BlockKey, Zeroizing<Vec<u8>>)> {
+        let mut evicted = Vec::new();
+        if let Some(capacity) = self.capacity {
+            while self.blocks.len() >= capacity {
+                let oldest = *self
+                    .blocks
+                    .iter()
+                    .min_by_key(|(_, block)| block.last_used)
+                    .map(|(key, _)| key)
+                    .expect("a full cache has blocks");
+                let mut block = self.blocks.remove(&oldest).expect("block was found");
+                if block.dirty {
+                    evicted.push((oldest, Zeroizing::new(std::mem::take(&mut block.data))));
+                }
+            }
+        }
+
+        let mut buffer = Vec::with_capacity(BLOCK_SIZE as usize);
+        buffer.extend_from_slice(&data[..data.len().min(BLOCK_SIZE as usize)]);
+        data.zeroize();
+
+        let last_used = self.tick();
+        self.blocks.insert(
+            key,
+            CachedBlock {
+                data: buffer,
+                dirty: false,
+                last_used,
+            },
+        );
+        evicted
+    }
+
+    /// Drops a block, discarding any unwritten changes.
+    pub fn remove(&mut self, key: &BlockKey) {
+        self.blocks.remove(key);
+    }
+
+    /// Drops and wipes all blocks, discarding any unwritten changes.
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+    }
+
+    /// Returns the keys of all dirty blocks in sorted order.
+    pub fn dirty(&self) -> Vec<BlockKey> {
+        let mut keys: Vec<BlockKey> = self
+            .blocks
+            .iter()
+            .filter(|(_, block)| block.dirty)
+            .map(|(key, _)| *key)
+            .collect();
+        keys.sort_unstable();
+        keys
+    }
+
+    /// Returns a block without counting it as used.
+    pub fn peek(&self, key: &BlockKey) -> Option<&[u8]> {
+        self.blocks.get(key).map(|block| &block.data[..])
+    }
+
+    pub fn mark_clean(&mut self, key: &BlockKey) {
+        if let Some(block) = self.blocks.get_mut(key) {
+            block.dirty = false;
+        }
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+}
+
+impl fmt::Debug for BlockCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BlockCache")
+            .field("blocks", &self.blocks.len())
+            .field("dirty", &self.dirty().len())
+            .field("capacity", &self.capacity)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bounded_cache_evicts_least_recently_used() {
+        let mut cache = BlockCache::bounded(2);
+        assert!(cache.insert((1, 0), vec![1]).is_empty());
+        assert!(cache.insert((1, 1), vec![2]).is_empty());
+        cache.get_mut(&(1, 0)).unwrap().push(3);
+        cache.get(&(1, 1));
+
+        // The block written first was used least recently and is dirty.
+        let evicted = cache.insert((2, 0), vec![4]);
+        assert_eq!(evicted.len(), 1);
+        assert_eq!(evicted[0].0, (1, 0));
+        assert_eq!(*evicted[0].1, [1, 3]);
+        assert!(cache.contains(&(1, 1)));
+
+        // Clean blocks are dropped without being handed back.
+        assert!(cache.insert((2, 1), vec![5]).is_empty());
+        assert!(!cache.contains(&(1, 1)));
+    }
+
+    #[test]
+    fn test_dirty_blocks_until_marked_clean() {
+        let mut cache = BlockCache::default();
+        cache.insert((1, 1), Vec::new());
+        cache.insert((1, 0), Vec::new());
+        cache.insert((2, 0), Vec::new());
+        cache.get_mut(&(1, 1)).unwrap().push(1);
+        cache.get_mut(&(1, 0)).unwrap().push(2);
+        assert_eq!(cache.dirty(), [(1, 0), (1, 1)]);
+
+        cache.mark_clean(&(1, 0));
+        assert_eq!(cache.dirty(), [(1, 1)]);
+        assert_eq!(cache.peek(&(1, 1)), Some(&[1][..]));
+
+        cache.clear();
+        assert!(cache.dirty().is_empty());
+        assert!(!cache.contains(&(1, 1)));
+    }
+
+    #[test]
+    fn test_blocks_never_reallocate() {
+        let mut cache = BlockCache::default();
+        cache.insert((1, 0), vec![1; 16]);
+        let before = cache.peek(&(1, 0)).unwrap().as_ptr();
+
+        cache
+            .get_mut(&(1, 0))
+            .unwrap()
+            .resize(BLOCK_SIZE as usize, 2);
+        assert_eq!(cache.peek(&(1, 0)).unwrap().as_ptr(), before);
+    }
+}
@@ -0,0 +1,574 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+use std::mem;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixListener;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use libc::geteuid;
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::error;
+use tracing::info;
+
+use crate::filesystem::rekey::BackgroundRekey;
+use crate::filesystem::shared::SharedFs;
+use crate::log::set_level;
+use crate::paths::mount_id;
+use crate::paths::runtime_dir;
+use crate::vault::Keyring;
+use crate::vault::MasterKey;
+use crate::vault::rekey::RekeyProgress;
+
+/// A request sent to a mount's daemon over its control socket.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    /// Report the daemon's state.
+    Status,
+    /// Write all dirty data and metadata to storage.
+    Flush,
+    /// Drop the master keys and all decrypted data, and deny filesystem
+    /// requests until unlocked.
+    Lock,
+    /// Serve filesystem requests again after `Lock`, with the keyring that
+    /// the client unlocked the vault with.
+    Unlock { keyring: Keyring },
+    /// Reload the daemon's runtime configuration, currently its log level,
+    /// which is left as it is if `log_level` is `None`.
+    Reload {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        log_level: Option<String>,
+    },
+    /// Flush and unmount if no handles are open, the daemon exits afterwards.
+    Unmount,
+    /// Encrypt new objects under a new master key generation and re-encrypt
+    /// the existing ones in the background. Sent after `Vault::begin_rekey`.
+    Rekey { generation: u32, key: MasterKey },
+}
+
+/// The daemon's answer to a `Request`.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Response {
+    Status(DaemonStatus),
+    Flushed { open_handles: usize },
+    Busy { open_handles: usize },
+    Ok,
+    Error { message: String },
+}
+
+/// State of a running daemon as reported by `Request::Status`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DaemonStatus {
+    pub pid: u32,
+    pub root_dir: PathBuf,
+    pub mount_point: PathBuf,
+    pub uptime_secs: u64,
+    /// Time since the last filesystem request.
+    #[serde(default)]
+    pub idle_secs: u64,
+    pub read_only: bool,
+    pub locked: bool,
+    pub open_handles: usize,
+    pub inodes: usize,
+    pub data_bytes: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rekey: Option<RekeyProgress>,
+    /// Whether the daemon is working through `rekey` right now. A rekey that
+    /// stopped on an error resumes at the next mount.
+    #[serde(default)]
+    pub rekey_running: bool,
+}
+
+impl fmt::Display for DaemonStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let yes_no = |value: bool| if value { "yes" } else { "no" };
+        writeln!(f, "mount point:  {}", self.mount_point.display())?;
+        writeln!(f, "root dir:     {}", self.root_dir.display())?;
+        writeln!(f, "pid:          {}", self.pid)?;
+        writeln!(f, "uptime:       {}", format_duration(self.uptime_secs))?;
+        writeln!(f, "idle:         {}", format_duration(self.idle_secs))?;
+        writeln!(f, "read-only:    {}", yes_no(self.read_only))?;
+        writeln!(f, "locked:       {}", yes_no(self.locked))?;
+        writeln!(f, "open handles: {}", self.open_handles)?;
+        writeln!(f, "inodes:       {}", self.inodes)?;
+        writeln!(f, "data bytes:   {}", self.data_bytes)?;
+        if let Some(rekey) = &self.rekey {
+            writeln!(f, "rekey:        {}", rekey)?;
+        }
+        Ok(())
+    }
+}
+
+/// Formats a number of seconds as `1d 2h 3m 4s`, leaving out leading zero
+/// units.
+pub fn format_duration(secs: u64) -> String {
+    let units = [
+        (secs / 86400, "d"),
+        (secs / 3600 % 24, "h"),
+        (secs / 60 % 60, "m"),
+    ];
+    let mut parts: Vec<String> = units
+        .iter()
+        .skip_while(|(value, _)| *value == 0)
+        .map(|(value, unit)| format!("{value}{unit}"))
+        .collect();
+    parts.push(format!("{}s", secs % 60));
+    parts.join(" ")
+}
+
+/// Static facts about a daemon that are included in its status.
+#[derive(Debug)]
+pub struct DaemonInfo {
+    pub root_dir: PathBuf,
+    pub mount_point: PathBuf,
+    pub started: Instant,
+}
+
+/// Returns the path of the control socket for the given mount point.
+pub fn socket_path(mount_point: &Path) -> io::Result<PathBuf> {
+    Ok(runtime_dir()?.join(format!("{}.sock", mount_id(mount_point)?)))
+}
+
+/// Sends a request to the daemon serving `mount_point` and waits up to
+/// `timeout` for its response.
+pub fn send(mount_point: &Path, request: &Request, timeout: Duration) -> io::Result<Response> {
+    let mut stream = UnixStream::connect(socket_path(mount_point)?)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    write_message(&mut stream, request)?;
+    read_message(&mut BufReader::new(stream)).map_err(|err| match err.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("daemon did not respond within {}s", timeout.as_secs()),
+        ),
+        _ => err,
+    })
+}
+
+/// Returns whether a `send` error means that no daemon is listening.
+pub fn is_unreachable(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+    )
+}
+
+/// Converts a response that does not answer the request into an error.
+pub fn unexpected(response: Response) -> io::Error {
+    match response {
+        Response::Error { message } => io::Error::other(format!("daemon error: {message}")),
+        response => io::Error::other(format!("unexpected response from daemon: {response:?}")),
+    }
+}
+
+/// Serves control requests for a mounted filesystem, removing the socket when
+/// dropped.
+#[derive(Debug)]
+pub struct ControlServer {
+    path: PathBuf,
+    in_flight: Arc<Mutex<()>>,
+}
+
+/// Detaches the mount point, provided by the FUSE session.
+type Unmounter = Box<dyn FnMut() -> io::Result<()> + Send>;
+
+/// Everything a control request may act on.
+struct Daemon {
+    fs: SharedFs,
+    info: DaemonInfo,
+    unmounter: Mutex<Unmounter>,
+    rekey: BackgroundRekey,
+}
+
+impl ControlServer {
+    /// Binds the control socket for `info.mount_point` and handles requests on
+    /// a background thread.
+    pub fn start<F>(
+        fs: SharedFs,
+        info: DaemonInfo,
+        unmounter: F,
+        rekey: BackgroundRekey,
+    ) -> io::Result<Self>
+    where
+        F: FnMut() -> io::Result<()> + Send + 'static,
+    {
+        let path = socket_path(&info.mount_point)?;
+        if UnixStream::connect(&path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("another daemon is listening on '{}'", path.display()),
+            ));
+        }
+        match fs::remove_file(&path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+
+        let listener = UnixListener::bind(&path)?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+
+        let daemon = Daemon {
+            fs,
+            info,
+            unmounter: Mutex::new(Box::new(unmounter)),
+            rekey,
+        };
+        let in_flight = Arc::new(Mutex::new(()));
+        let server_in_flight = Arc::clone(&in_flight);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let _in_flight = server_in_flight.lock();
+                let result = stream.and_then(|stream| handle_connection(stream, &daemon));
+                if let Err(err) = result {
+                    error!("Failed to handle control request: {}", err);
+                }
+            }
+        });
+
+        Ok(Self { path, in_flight })
+    }
+}
+
+impl Drop for ControlServer {
+    /// Waits for a request in progress, such as an unmount, to send its
+    /// response before removing the socket.
+    fn drop(&mut self) {
+        let _in_flight = self.in_flight.lock();
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn handle_connection(stream: UnixStream, daemon: &Daemon) -> io::Result<()> {
+    let uid = peer_uid(&stream)?;
+    let owner = unsafe { geteuid() };
+    if uid != owner && uid != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("rejected control connection from uid {uid}"),
+        ));
+    }
+
+    let mut writer = stream.try_clone()?;
+    let request: Request = read_message(&mut BufReader::new(stream))?;
+    info!("Handling control request {:?}", request);
+    let response = handle_request(request, daemon).unwrap_or_else(|err| Response::Error {
+        message: err.to_string(),
+    });
+    write_message(&mut writer, &response)
+}
+
+fn handle_request(request: Request, daemon: &Daemon) -> io::Result<Response> {
+    match request {
+        Request::Status => Ok(Response::Status(status(daemon))),
+        Request::Flush => {
+            let mut fs = daemon.fs.lock();
+            fs.flush_all()?;
+            Ok(Response::Flushed {
+                open_handles: fs.open_handles(),
+            })
+        }
+        Request::Lock => {
+            if daemon.rekey.is_running() {
+                return Err(io::Error::new(
+                    io::ErrorKind::ResourceBusy,
+                    "a rekey is in progress, lock the vault once it has finished",
+                ));
+            }
+            daemon.fs.lock().lock_vault()?;
+            Ok(Response::Ok)
+        }
+        Request::Unlock { keyring } => {
+            daemon.fs.lock().unlock_vault(keyring)?;
+            daemon.fs.touch();
+            Ok(Response::Ok)
+        }
+        Request::Reload { log_level } => {
+            if let Some(log_level) = log_level {
+                set_level(&log_level)?;
+            }
+            Ok(Response::Ok)
+        }
+        Request::Unmount => {
+            // The filesystem mutex is released before unmounting, as the kernel
+            // may still send requests to the daemon while detaching.
+            {
+                let mut fs = daemon.fs.lock();
+                fs.flush_all()?;
+                let open_handles = fs.open_handles();
+                if open_handles > 0 {
+                    return Ok(Response::Busy { open_handles });
+                }
+            }
+            let mut unmount = daemon
+                .unmounter
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            unmount()?;
+            Ok(Response::Ok)
+        }
+        Request::Rekey { generation, key } => {
+            {
+                let mut fs = daemon.fs.lock();
+                if fs.is_read_only() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::ReadOnlyFilesystem,
+                        "cannot rekey a read-only mount",
+                    ));
+                }
+                if fs.is_locked() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        "cannot rekey a locked mount, unlock it first",
+                    ));
+                }
+                fs.add_key(generation, key);
+            }
+            daemon.rekey.start()?;
+            Ok(Response::Ok)
+        }
+    }
+}
+
+fn status(daemon: &Daemon) -> DaemonStatus {
+    let fs = daemon.fs.lock();
+    DaemonStatus {
+        pid: process::id(),
+        root_dir: daemon.info.root_dir.clone(),
+        mount_point: daemon.info.mount_point.clone(),
+        uptime_secs: daemon.info.started.elapsed().as_secs(),
+        idle_secs: daemon.fs.idle_time().as_secs(),
+        read_only: fs.is_read_only(),
+        locked: fs.is_locked(),
+        open_handles: fs.open_handles(),
+        inodes: fs.inode_count(),
+        data_bytes: fs.data_bytes(),
+        rekey: RekeyProgress::read(&daemon.info.root_dir).unwrap_or_default(),
+        rekey_running: daemon.rekey.is_running(),
+    }
+}
+
+/// Returns the uid of the process on the other end of a Unix socket.
+fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    let mut cred: libc::ucred = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            (&mut cred as *mut libc::ucred).cast(),
+            &mut len,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(cred.uid)
+}
+
+/// Messages are JSON objects terminated by a newline.
+fn write_message<W: Write, T: Serialize>(writer: &mut W, message: &T) -> io::Result<()> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    writer.write_all(&line)?;
+    writer.flush()
+}
+
+fn read_message<R: BufRead, T: for<'de> Deserialize<'de>>(reader: &mut R) -> io::Result<T> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "control connection closed without a message",
+        ));
+    }
+    Ok(serde_json::from_str(&line)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn test_message_round_trip() -> io::Result<()> {
+        let mut buffer = Vec::new();
+        write_message(&mut buffer, &Request::Flush)?;
+        write_message(&mut buffer, &Response::Flushed { open_handles: 2 })?;
+        assert_eq!(
+            String::from_utf8(buffer.clone()).unwrap(),
+            "{\"command\":\"flush\"}\n{\"status\":\"flushed\",\"open_handles\":2}\n"
+        );
+
+        let mut reader = Cursor::new(buffer);
+        let request: Request = read_message(&mut reader)?;
+        let response: Response = read_message(&mut reader)?;
+        assert_eq!(request, Request::Flush);
+        assert_eq!(response, Response::Flushed { open_handles: 2 });
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_message_eof() {
+        let result: io::Result<Request> = read_message(&mut Cursor::new(Vec::new()));
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_status_round_trip() -> io::Result<()> {
+        let status = DaemonStatus {
+            pid: 42,
+            root_dir: PathBuf::from("/vaults/personal"),
+            mount_point: PathBuf::from("/mnt/personal"),
+            uptime_secs: 3600,
+            idle_secs: 120,
+            read_only: false,
+            locked: true,
+            open_handles: 1,
+            inodes: 3,
+            data_bytes: 4096,
+            rekey: Some(RekeyProgress::new(2)),
+            rekey_running: true,
+        };
+
+        let mut buffer = Vec::new();
+        write_message(&mut buffer, &Response::Status(status.clone()))?;
+        let response: Response = read_message(&mut Cursor::new(buffer))?;
+        assert_eq!(response, Response::Status(status));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_request_encoding() -> io::Result<()> {
+        let mut buffer = Vec::new();
+        write_message(
+            &mut buffer,
+            &Request::Reload {
+                log_level: Some("debug".to_string()),
+            },
+        )?;
+        write_message(&mut buffer, &Request::Unmount)?;
+        write_message(&mut buffer, &Request::Reload { log_level: None })?;
+        assert_eq!(
+            String::from_utf8(buffer.clone()).unwrap(),
+            "{\"command\":\"reload\",\"log_level\":\"debug\"}\n{\"command\":\"unmount\"}\n{\"\
+             command\":\"reload\"}\n"
+        );
+        let mut reader = Cursor::new(buffer);
+        for _ in 0..2 {
+            read_message::<_, Request>(&mut reader)?;
+        }
+        let request: Request = read_message(&mut reader)?;
+        assert_eq!(request, Request::Reload { log_level: None });
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rekey_request_hides_key() -> io::Result<()> {
+        let key = MasterKey::from_bytes([0xab; 32]);
+        let request = Request::Rekey {
+            generation: 1,
+            key: key.clone(),
+        };
+        assert!(!format!("{request:?}").contains("abab"));
+
+        let mut buffer = Vec::new();
+        write_message(&mut buffer, &request)?;
+        let parsed: Request = read_message(&mut Cursor::new(buffer))?;
+        assert_eq!(parsed, Request::Rekey { generation: 1, key });
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unlock_request_round_trip() -> io::Result<()> {
+        let mut keyring = Keyring::new(0, MasterKey::from_bytes([0xab; 32]));
+        keyring.insert(1, MasterKey::from_bytes([0xcd; 32]));
+        let request = Request::Unlock {
+            keyring: keyring.clone(),
+        };
+        assert!(!format!("{request:?}").contains("abab"));
+
+        let mut buffer = Vec::new();
+        write_message(&mut buffer, &request)?;
+        let parsed: Request = read_message(&mut Cursor::new(buffer))?;
+        assert_eq!(parsed, Request::Unlock { keyring });
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(0), "0s");
+        assert_eq!(format_duration(59), "59s");
+        assert_eq!(format_duration(3600), "1h 0m 0s");
+        assert_eq!(format_duration(90061), "1d 1h 1m 1s");
+    }
+
+    fn test_daemon() -> Daemon {
+        let fs = SharedFs::new(Default::default());
+        let root_dir = PathBuf::from("/vaults/personal");
+        Daemon {
+            rekey: BackgroundRekey::new(fs.clone(), &root_dir),
+            fs,
+            info: DaemonInfo {
+                root_dir,
+                mount_point: PathBuf::from("/mnt/personal"),
+                started: Instant::now(),
+            },
+            unmounter: Mutex::new(Box::new(|| Ok(()))),
+        }
+    }
+
+    #[test]
+    fn test_handle_request_lock_and_status() -> io::Result<()> {
+        let daemon = test_daemon();
+
+        assert_eq!(handle_request(Request::Lock, &daemon)?, Response::Ok);
+        let Response::Status(status) = handle_request(Request::Status, &daemon)? else {
+            panic!("Expected a status response");
+        };
+        assert!(status.locked);
+        assert_eq!(status.pid, process::id());
+        assert_eq!(status.mount_point, PathBuf::from("/mnt/personal"));
+
+        let keyring = Keyring::new(0, MasterKey::generate()?);
+        let response = handle_request(Request::Unlock { keyring }, &daemon)?;
+        assert_eq!(response, Response::Ok);
+        assert!(!daemon.fs.lock().is_locked());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_handle_request_flush_and_unmount() -> io::Result<()> {
+        let daemon = test_daemon();
+
+        let response = handle_request(Request::Flush, &daemon)?;
+        assert_eq!(response, Response::Flushed { open_handles: 0 });
+        assert_eq!(handle_request(Request::Unmount, &daemon)?, Response::Ok);
+
+        Ok(())
+    }
+}
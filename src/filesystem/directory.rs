@@ -1,18 +1,20 @@
 use std::io;
 use std::path::Path;
 
+use crate::log::Redacted;
+
 /// Ensures the given path exists and is a directory.
 pub fn validate_dir(path: &Path) -> io::Result<()> {
     if !path.exists() {
         return Err(io::Error::new(
             io::ErrorKind::NotFound,
-            format!("'{}' does not exist", path.display()),
+            format!("'{}' does not exist", Redacted::path(path)),
         ));
     }
     if !path.is_dir() {
         return Err(io::Error::new(
             io::ErrorKind::InvalidInput,
-            format!("'{}' is not a directory", path.display()),
+            format!("'{}' is not a directory", Redacted::path(path)),
         ));
     }
     Ok(())
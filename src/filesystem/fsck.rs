@@ -0,0 +1,718 @@
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::iter;
+use std::time::SystemTime;
+
+use fuser::FUSE_ROOT_ID;
+use fuser::FileAttr;
+use fuser::FileType;
+use libc::getegid;
+use libc::geteuid;
+
+use crate::filesystem::Entries;
+use crate::filesystem::VylFs;
+use crate::filesystem::blocks::BLOCK_SIZE;
+use crate::filesystem::blocks::BlockKey;
+use crate::filesystem::metadata::InodeRecord;
+use crate::filesystem::metadata::ObjectName;
+use crate::filesystem::metadata::block_object;
+use crate::filesystem::metadata::file_blocks;
+use crate::filesystem::metadata::inode_object;
+use crate::filesystem::tree::TREE_OBJECT;
+use crate::filesystem::tree::TreeRoot;
+use crate::vault::store::Store;
+
+/// Directory in the root of the vault that orphans are moved to.
+pub const LOST_AND_FOUND: &str = "lost+found";
+
+/// Something wrong with a vault, as found by `check`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// An object that does not authenticate or whose contents do not parse.
+    Corrupt { name: String, reason: String },
+    /// An object that is older than the version its parent recorded.
+    RolledBack {
+        name: String,
+        expected: u64,
+        found: u64,
+    },
+    /// The tree root or the root directory.
+    Missing { name: String },
+    /// A directory entry whose inode is missing or corrupt.
+    DanglingEntry { parent: u64, name: String, ino: u64 },
+    /// A directory entry for an inode that another entry already links.
+    ExtraLink { parent: u64, name: String, ino: u64 },
+    /// An inode object that no directory refers to.
+    OrphanedInode(u64),
+    /// Block objects of an inode that does not exist.
+    OrphanedBlocks { ino: u64, count: usize },
+    /// A link count other than the one the filesystem maintains.
+    WrongNlink { ino: u64, found: u32, expected: u32 },
+    /// A file whose block map does not cover its size.
+    BlockMapMismatch { ino: u64, size: u64, mapped: usize },
+    /// A block stored past the end of its file.
+    BlockPastEnd { ino: u64, index: u64 },
+    /// A block that the file's block map records, but that is missing.
+    MissingBlock { ino: u64, index: u64 },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Corrupt { name, reason } => write!(f, "object '{name}' is corrupt: {reason}"),
+            Self::RolledBack {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "object '{name}' was rolled back from version {expected} to {found}"
+            ),
+            Self::Missing { name } => write!(f, "object '{name}' is missing"),
+            Self::DanglingEntry { parent, name, ino } => write!(
+                f,
+                "entry '{name}' of directory {parent} refers to missing inode {ino}"
+            ),
+            Self::ExtraLink { parent, name, ino } => write!(
+                f,
+                "entry '{name}' of directory {parent} links inode {ino} a second time"
+            ),
+            Self::OrphanedInode(ino) => write!(f, "inode {ino} is not in any directory"),
+            Self::OrphanedBlocks { ino, count } => {
+                write!(f, "{count} block(s) belong to missing inode {ino}")
+            }
+            Self::WrongNlink {
+                ino,
+                found,
+                expected,
+            } => write!(
+                f,
+                "inode {ino} has link count {found} instead of {expected}"
+            ),
+            Self::BlockMapMismatch { ino, size, mapped } => {
+                write!(f, "inode {ino} has size {size} but maps {mapped} block(s)")
+            }
+            Self::BlockPastEnd { ino, index } => {
+                write!(
+                    f,
+                    "block {index} of inode {ino} lies past the end of the file"
+                )
+            }
+            Self::MissingBlock { ino, index } => {
+                write!(f, "block {index} of inode {ino} is missing")
+            }
+        }
+    }
+}
+
+/// What `check` found and did.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub objects: usize,
+    pub inodes: usize,
+    pub problems: Vec<Problem>,
+    /// The vault's epoch after the check, see `TreeRoot`.
+    pub epoch: u64,
+    pub repaired: bool,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for problem in &self.problems {
+            writeln!(f, "{problem}")?;
+        }
+        write!(
+            f,
+            "checked {} objects and {} inodes, {} problem(s) found",
+            self.objects,
+            self.inodes,
+            self.problems.len()
+        )?;
+        if self.repaired && !self.problems.is_empty() {
+            write!(f, " and repaired")?;
+        }
+        Ok(())
+    }
+}
+
+/// Verifies every object of the vault behind `store` and cross-checks the
+/// directory tree against the stored inodes and blocks.
+///
+/// With `repair`, corrupt objects are set aside, dangling entries and blocks
+/// past the end of files are dropped, link counts and block maps are fixed,
+/// and orphaned inodes and blocks are moved to `lost+found`. The whole tree is
+/// then written again, so that it authenticates as a whole.
+pub fn check(store: Store, repair: bool) -> io::Result<Report> {
+    let scan = Scan::read(&store)?;
+    let mut checker = Checker {
+        problems: scan.corrupt.clone(),
+        inodes: HashMap::new(),
+        entries: HashMap::new(),
+        versions: HashMap::new(),
+        stale_blocks: BTreeSet::new(),
+        scan,
+    };
+    checker.check_tree();
+    let Checker {
+        scan,
+        problems,
+        inodes,
+        entries,
+        versions,
+        stale_blocks,
+    } = checker;
+
+    let mut report = Report {
+        objects: scan.objects,
+        inodes: inodes.len(),
+        epoch: scan.counter,
+        repaired: repair,
+        problems,
+    };
+    if !repair || report.problems.is_empty() {
+        return Ok(report);
+    }
+
+    for problem in &report.problems {
+        if let Problem::Corrupt { name, .. } = problem {
+            store.quarantine(name)?;
+        }
+    }
+    let key_epoch = TreeRoot::key_epoch(
+        scan.tree.as_ref(),
+        store.current_generation(),
+        scan.counter,
+        scan.objects as u64,
+    );
+    let mut fs = VylFs {
+        store: Some(store),
+        inode_counter: inodes.keys().max().map_or(FUSE_ROOT_ID, |ino| ino + 1),
+        dirty_inodes: inodes.keys().copied().collect(),
+        inodes,
+        entries,
+        versions,
+        version_counter: scan.counter,
+        key_epoch,
+        stale_blocks,
+        ..Default::default()
+    };
+    fs.flush_all()?;
+    report.epoch = fs.epoch();
+    Ok(report)
+}
+
+/// The readable contents of a vault.
+#[derive(Debug, Default)]
+struct Scan {
+    objects: usize,
+    tree: Option<TreeRoot>,
+    /// Inode records with the versions of their objects.
+    records: BTreeMap<u64, (u64, InodeRecord)>,
+    /// The version and length of every block, by inode and index.
+    blocks: BTreeMap<u64, BTreeMap<u64, (u64, usize)>>,
+    corrupt: Vec<Problem>,
+    /// The highest version of any object.
+    counter: u64,
+}
+
+impl Scan {
+    fn read(store: &Store) -> io::Result<Self> {
+        let mut scan = Self::default();
+        for name in store.names()? {
+            let object = match ObjectName::parse(&name) {
+                Some(_) => store.read(&name),
+                None if name == TREE_OBJECT => store.read(&name),
+                None => continue,
+            };
+            scan.objects += 1;
+            let object = match object {
+                Ok(Some(object)) => object,
+                Ok(None) => continue,
+                Err(err) => {
+                    scan.corrupt(name, err.to_string());
+                    continue;
+                }
+            };
+            scan.counter = scan.counter.max(object.version);
+            match ObjectName::parse(&name) {
+                Some(ObjectName::Inode(ino)) => match serde_json::from_slice(&object.data) {
+                    Ok(record) => {
+                        scan.records.insert(ino, (object.version, record));
+                    }
+                    Err(err) => scan.corrupt(name, err.to_string()),
+                },
+                Some(ObjectName::Block(ino, index)) => {
+                    scan.blocks
+                        .entry(ino)
+                        .or_default()
+                        .insert(index, (object.version, object.data.len()));
+                }
+                None => match serde_json::from_slice::<TreeRoot>(&object.data) {
+                    Ok(root) => {
+                        scan.counter = scan.counter.max(root.counter);
+                        scan.tree = Some(root);
+                    }
+                    Err(err) => scan.corrupt(name, err.to_string()),
+                },
+            }
+        }
+        Ok(scan)
+    }
+
+    fn corrupt(&mut self, name: String, reason: String) {
+        self.corrupt.push(Problem::Corrupt { name, reason });
+    }
+}
+
+/// Walks the scanned tree, collecting problems and the state a repair
+/// writes.
+struct Checker {
+    scan: Scan,
+    problems: Vec<Problem>,
+    inodes: HashMap<u64, FileAttr>,
+    entries: Entries,
+    versions: HashMap<ObjectName, u64>,
+    stale_blocks: BTreeSet<BlockKey>,
+}
+
+/// An inode to visit, the version its parent expects and the entry that
+/// links it.
+type Pending = (u64, u64, Option<(u64, String)>);
+
+impl Checker {
+    fn check_tree(&mut self) {
+        let root_version = self
+            .scan
+            .records
+            .get(&FUSE_ROOT_ID)
+            .map(|(version, _)| *version);
+        let empty = self.scan.records.is_empty() && self.scan.blocks.is_empty();
+        match (self.scan.tree, root_version) {
+            (None, Some(version)) if version > 0 => self.problems.push(Problem::Missing {
+                name: TREE_OBJECT.to_string(),
+            }),
+            // A vault that was never mounted has no objects at all.
+            (None, None) if empty => {}
+            (_, None) => self.problems.push(Problem::Missing {
+                name: inode_object(FUSE_ROOT_ID),
+            }),
+            _ => {}
+        }
+        if root_version.is_some() {
+            let expected = self.scan.tree.map_or(0, |tree| tree.root);
+            self.walk(vec![(FUSE_ROOT_ID, expected, None)]);
+        } else {
+            self.inodes
+                .insert(FUSE_ROOT_ID, new_attr(FUSE_ROOT_ID, FileType::Directory));
+        }
+
+        let orphans: BTreeSet<u64> = self
+            .scan
+            .records
+            .keys()
+            .filter(|ino| !self.inodes.contains_key(ino))
+            .copied()
+            .collect();
+        let linked: BTreeSet<u64> = orphans
+            .iter()
+            .flat_map(|ino| self.scan.records[ino].1.entries.values().copied())
+            .collect();
+        let mut pending = Vec::new();
+        for &ino in orphans.difference(&linked) {
+            self.problems.push(Problem::OrphanedInode(ino));
+            pending.push((ino, 0, Some(self.lost(ino))));
+        }
+        self.walk(pending);
+        // What is left are orphans that only link each other in a cycle.
+        while let Some(ino) = orphans
+            .iter()
+            .find(|ino| !self.inodes.contains_key(ino))
+            .copied()
+        {
+            self.problems.push(Problem::OrphanedInode(ino));
+            let link = self.lost(ino);
+            self.walk(vec![(ino, 0, Some(link))]);
+        }
+
+        let orphaned_blocks: Vec<u64> = self.scan.blocks.keys().copied().collect();
+        for ino in orphaned_blocks {
+            let link = self.lost(ino);
+            let blocks = self.scan.blocks.remove(&ino).expect("inode has blocks");
+            self.problems.push(Problem::OrphanedBlocks {
+                ino,
+                count: blocks.len(),
+            });
+            let (&last, &(_, len)) = blocks.last_key_value().expect("blocks are not empty");
+            let mut attr = new_attr(ino, FileType::RegularFile);
+            attr.size = last * BLOCK_SIZE + len as u64;
+            attr.blocks = file_blocks(attr.size);
+            for (index, (version, _)) in blocks {
+                self.versions.insert(ObjectName::Block(ino, index), version);
+            }
+            self.entries.insert(link, ino);
+            self.inodes.insert(ino, attr);
+        }
+
+        self.check_nlink();
+    }
+
+    /// Visits the inodes in `pending` and everything below them.
+    fn walk(&mut self, mut pending: Vec<Pending>) {
+        while let Some((ino, expected, link)) = pending.pop() {
+            let name = inode_object(ino);
+            if self.inodes.contains_key(&ino) {
+                if let Some((parent, name)) = link {
+                    self.problems.push(Problem::ExtraLink { parent, name, ino });
+                }
+                continue;
+            }
+            let Some((version, record)) = self.scan.records.get(&ino) else {
+                if let Some((parent, name)) = link {
+                    self.problems
+                        .push(Problem::DanglingEntry { parent, name, ino });
+                }
+                continue;
+            };
+            if *version < expected {
+                self.problems.push(Problem::RolledBack {
+                    name,
+                    expected,
+                    found: *version,
+                });
+            }
+            self.versions.insert(ObjectName::Inode(ino), *version);
+            if let Some(link) = link {
+                self.entries.insert(link, ino);
+            }
+            for (child_name, child) in &record.entries {
+                let expected = record.child_versions.get(child).copied().unwrap_or(0);
+                pending.push((*child, expected, Some((ino, child_name.clone()))));
+            }
+
+            let attr = record.attr();
+            if attr.kind == FileType::RegularFile {
+                let record = record.clone();
+                self.check_blocks(ino, &record);
+            }
+            self.inodes.insert(ino, attr);
+        }
+    }
+
+    fn check_blocks(&mut self, ino: u64, record: &InodeRecord) {
+        let count = record.size.div_ceil(BLOCK_SIZE);
+        let stored = self.scan.blocks.remove(&ino).unwrap_or_default();
+        let mapped = record.block_versions.len() as u64 == count;
+        if !mapped && !record.block_versions.is_empty() {
+            self.problems.push(Problem::BlockMapMismatch {
+                ino,
+                size: record.size,
+                mapped: record.block_versions.len(),
+            });
+        }
+
+        for (&index, &(version, _)) in &stored {
+            if index >= count {
+                self.problems.push(Problem::BlockPastEnd { ino, index });
+                self.stale_blocks.insert((ino, index));
+                continue;
+            }
+            let expected = record.block_versions.get(index as usize).copied().flatten();
+            match expected {
+                Some(expected) if version < expected => self.problems.push(Problem::RolledBack {
+                    name: block_object(ino, index),
+                    expected,
+                    found: version,
+                }),
+                // Written after the last flush, so not part of the file.
+                None if mapped => continue,
+                _ => {}
+            }
+            self.versions.insert(ObjectName::Block(ino, index), version);
+        }
+        for (index, expected) in record.block_versions.iter().enumerate() {
+            let index = index as u64;
+            if expected.is_some() && index < count && !stored.contains_key(&index) {
+                self.problems.push(Problem::MissingBlock { ino, index });
+            }
+        }
+    }
+
+    /// Compares link counts with the filesystem's: directories always have
+    /// two links and files, which cannot be hard linked, one.
+    fn check_nlink(&mut self) {
+        let mut inos: Vec<u64> = self.inodes.keys().copied().collect();
+        inos.sort_unstable();
+        for ino in inos {
+            let attr = self.inodes.get_mut(&ino).expect("inode exists");
+            let links = match attr.kind {
+                FileType::Directory => 2,
+                _ => 1,
+            };
+            if attr.nlink != links {
+                self.problems.push(Problem::WrongNlink {
+                    ino,
+                    found: attr.nlink,
+                    expected: links,
+                });
+                attr.nlink = links;
+            }
+        }
+    }
+
+    /// Returns the entry in `lost+found` that orphaned inode `ino` is linked
+    /// by.
+    fn lost(&mut self, ino: u64) -> (u64, String) {
+        (self.lost_and_found(), format!("#{ino}"))
+    }
+
+    /// Returns the inode of `lost+found`, adding the directory if needed. If
+    /// the root has an entry by that name that is not a directory, the first
+    /// free or directory one of `lost+found.1`, `lost+found.2`, ... is used.
+    fn lost_and_found(&mut self) -> u64 {
+        let names = iter::once(LOST_AND_FOUND.to_string())
+            .chain((1..).map(|suffix| format!("{LOST_AND_FOUND}.{suffix}")));
+        let mut key = (FUSE_ROOT_ID, String::new());
+        for name in names {
+            key.1 = name;
+            match self.entries.get(&key) {
+                Some(ino) if self.inodes[ino].kind == FileType::Directory => return *ino,
+                Some(_) => {}
+                None => break,
+            }
+        }
+        let ino = self
+            .inodes
+            .keys()
+            .chain(self.scan.records.keys())
+            .chain(self.scan.blocks.keys())
+            .max()
+            .map_or(FUSE_ROOT_ID, |ino| ino + 1);
+        let mut attr = new_attr(ino, FileType::Directory);
+        attr.perm = 0o700;
+        self.inodes.insert(ino, attr);
+        self.entries.insert(key, ino);
+        ino
+    }
+}
+
+/// Attributes of an inode that `check` adds.
+fn new_attr(ino: u64, kind: FileType) -> FileAttr {
+    let now = SystemTime::now();
+    let (size, blocks, nlink, perm) = match kind {
+        FileType::Directory => (4096, 8, 2, 0o755),
+        _ => (0, 0, 1, 0o600),
+    };
+    FileAttr {
+        ino,
+        size,
+        blocks,
+        atime: now,
+        mtime: now,
+        ctime: now,
+        crtime: now,
+        kind,
+        perm,
+        nlink,
+        uid: unsafe { geteuid() },
+        gid: unsafe { getegid() },
+        rdev: 0,
+        blksize: 4096,
+        flags: 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::vault::Keyring;
+    use crate::vault::MasterKey;
+    use crate::vault::store::OBJECTS_DIR_NAME;
+
+    fn new_node(fs: &mut VylFs, parent: u64, name: &str, kind: FileType) -> io::Result<u64> {
+        fs.new_node(parent, name, kind, 0o700)
+            .map(|attr| attr.ino)
+            .map_err(io::Error::from_raw_os_error)
+    }
+
+    #[test]
+    fn test_clean_vault_has_no_problems() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let keyring = Keyring::new(0, MasterKey::generate()?);
+
+        let report = check(Store::open(temp_dir.path(), keyring.clone())?, false)?;
+        assert!(report.problems.is_empty(), "{report}");
+
+        let mut fs = VylFs::load(Store::open(temp_dir.path(), keyring.clone())?, false)?;
+        let dir = new_node(&mut fs, FUSE_ROOT_ID, "docs", FileType::Directory)?;
+        let file = new_node(&mut fs, dir, "notes", FileType::RegularFile)?;
+        fs.write_data(file, BLOCK_SIZE, b"data")
+            .map_err(io::Error::from_raw_os_error)?;
+        fs.flush_all()?;
+        drop(fs);
+
+        let report = check(Store::open(temp_dir.path(), keyring)?, true)?;
+        assert!(report.problems.is_empty(), "{report}");
+        assert_eq!(report.inodes, 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_lost_and_found_skips_files() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let keyring = Keyring::new(0, MasterKey::generate()?);
+        let open = || Store::open(temp_dir.path(), keyring.clone());
+
+        let mut fs = VylFs::load(open()?, false)?;
+        new_node(&mut fs, FUSE_ROOT_ID, LOST_AND_FOUND, FileType::RegularFile)?;
+        let orphan = new_node(&mut fs, FUSE_ROOT_ID, "orphan", FileType::RegularFile)?;
+        fs.flush_all()?;
+        fs.entries.remove(&(FUSE_ROOT_ID, "orphan".to_string()));
+        fs.dirty_inodes.insert(FUSE_ROOT_ID);
+        fs.flush_all()?;
+        drop(fs);
+
+        let report = check(open()?, true)?;
+        assert_eq!(report.problems, [Problem::OrphanedInode(orphan)]);
+
+        let fs = VylFs::load(open()?, false)?;
+        let lost_and_found = fs.entries[&(FUSE_ROOT_ID, format!("{LOST_AND_FOUND}.1"))];
+        assert_eq!(fs.inodes[&lost_and_found].kind, FileType::Directory);
+        assert_eq!(fs.entries[&(lost_and_found, format!("#{orphan}"))], orphan);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_repair_of_corrupted_vault() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let keyring = Keyring::new(0, MasterKey::generate()?);
+        let objects = temp_dir.path().join(OBJECTS_DIR_NAME);
+        let open = || Store::open(temp_dir.path(), keyring.clone());
+
+        let mut fs = VylFs::load(open()?, false)?;
+        let dir = new_node(&mut fs, FUSE_ROOT_ID, "docs", FileType::Directory)?;
+        let orphan = new_node(&mut fs, dir, "orphan", FileType::RegularFile)?;
+        let deleted = new_node(&mut fs, dir, "deleted", FileType::RegularFile)?;
+        let short = new_node(&mut fs, FUSE_ROOT_ID, "short", FileType::RegularFile)?;
+        let corrupt = new_node(&mut fs, FUSE_ROOT_ID, "corrupt", FileType::RegularFile)?;
+        let cycle = new_node(&mut fs, FUSE_ROOT_ID, "cycle", FileType::Directory)?;
+        let inner = new_node(&mut fs, cycle, "inner", FileType::Directory)?;
+        for ino in [orphan, deleted, short, corrupt] {
+            fs.write_data(ino, 0, &vec![1; BLOCK_SIZE as usize + 1])
+                .map_err(io::Error::from_raw_os_error)?;
+        }
+        fs.flush_all()?;
+
+        // Drop an entry without removing its inode, and shrink a file without
+        // discarding its blocks.
+        fs.entries.remove(&(dir, "orphan".to_string()));
+        fs.entries.remove(&(FUSE_ROOT_ID, "cycle".to_string()));
+        fs.inodes.get_mut(&short).expect("file exists").size = 10;
+        fs.inodes.get_mut(&dir).expect("directory exists").nlink = 5;
+        fs.dirty_inodes.extend([FUSE_ROOT_ID, dir, short]);
+        fs.flush_all()?;
+        drop(fs);
+        // Link the detached directory from its own subdirectory.
+        let store = open()?;
+        let object = store.read(&inode_object(inner))?.expect("inode exists");
+        let mut record: InodeRecord = serde_json::from_slice(&object.data)?;
+        record.entries.insert("cycle".to_string(), cycle);
+        store.write(
+            &inode_object(inner),
+            object.version,
+            &serde_json::to_vec(&record)?,
+        )?;
+        fs::remove_file(objects.join(inode_object(deleted)))?;
+        let block = objects.join(block_object(corrupt, 1));
+        let mut contents = fs::read(&block)?;
+        *contents.last_mut().expect("block is not empty") ^= 1;
+        fs::write(&block, contents)?;
+
+        let report = check(open()?, false)?;
+        let expected = [
+            Problem::Corrupt {
+                name: block_object(corrupt, 1),
+                reason: String::new(),
+            },
+            Problem::DanglingEntry {
+                parent: dir,
+                name: "deleted".to_string(),
+                ino: deleted,
+            },
+            Problem::OrphanedInode(orphan),
+            Problem::OrphanedInode(cycle),
+            Problem::ExtraLink {
+                parent: inner,
+                name: "cycle".to_string(),
+                ino: cycle,
+            },
+            Problem::OrphanedBlocks {
+                ino: deleted,
+                count: 2,
+            },
+            Problem::WrongNlink {
+                ino: dir,
+                found: 5,
+                expected: 2,
+            },
+            Problem::BlockPastEnd {
+                ino: short,
+                index: 1,
+            },
+            Problem::MissingBlock {
+                ino: corrupt,
+                index: 1,
+            },
+        ];
+        for problem in &expected {
+            let found = report.problems.iter().any(|found| match (found, problem) {
+                (Problem::Corrupt { name, .. }, Problem::Corrupt { name: expected, .. }) => {
+                    name == expected
+                }
+                _ => found == problem,
+            });
+            assert!(found, "Expected {problem} in {report}");
+        }
+        assert_eq!(report.problems.len(), expected.len(), "{report}");
+        assert!(fs::exists(&block)?);
+
+        let report = check(open()?, true)?;
+        assert_eq!(report.problems.len(), expected.len(), "{report}");
+        assert!(!fs::exists(&block)?);
+        assert!(!fs::exists(objects.join(block_object(short, 1)))?);
+        let report = check(open()?, false)?;
+        assert!(report.problems.is_empty(), "{report}");
+
+        let mut fs = VylFs::load(open()?, false)?;
+        let lost_and_found = fs.entries[&(FUSE_ROOT_ID, LOST_AND_FOUND.to_string())];
+        assert_eq!(fs.entries[&(lost_and_found, format!("#{orphan}"))], orphan);
+        assert_eq!(
+            fs.entries[&(lost_and_found, format!("#{deleted}"))],
+            deleted
+        );
+        assert!(!fs.entries.contains_key(&(dir, "deleted".to_string())));
+        assert_eq!(fs.entries[&(lost_and_found, format!("#{cycle}"))], cycle);
+        assert_eq!(fs.entries[&(cycle, "inner".to_string())], inner);
+        assert!(!fs.entries.contains_key(&(inner, "cycle".to_string())));
+        assert_eq!(fs.inodes[&dir].nlink, 2);
+        assert_eq!(fs.inodes[&deleted].size, BLOCK_SIZE + 1);
+        let data = fs
+            .read_data(deleted, BLOCK_SIZE - 1, 2)
+            .map_err(io::Error::from_raw_os_error)?;
+        assert_eq!(*data, [1, 1]);
+        let data = fs
+            .read_data(corrupt, BLOCK_SIZE, 1)
+            .map_err(io::Error::from_raw_os_error)?;
+        assert_eq!(*data, [0]);
+
+        Ok(())
+    }
+}
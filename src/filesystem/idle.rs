@@ -0,0 +1,148 @@
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+use tracing::debug;
+use tracing::error;
+use tracing::info;
+
+use crate::filesystem::control::format_duration;
+use crate::filesystem::rekey::BackgroundRekey;
+use crate::filesystem::shared::SharedFs;
+
+/// How long the idle check sleeps at most, so that it notices requests that
+/// were served while it slept.
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Locks the vault on a background thread once no FUSE request has arrived for
+/// `timeout`. A rekey in progress postpones the lock until it has finished.
+pub fn start_idle_lock(fs: SharedFs, rekey: BackgroundRekey, timeout: Duration) -> io::Result<()> {
+    watch("idle-lock", fs, timeout, move |fs, idle| {
+        if rekey.is_running() {
+            return false;
+        }
+        let mut fs = fs.lock();
+        if !fs.is_locked() {
+            info!(
+                "Locking after {} without requests",
+                format_duration(idle.as_secs())
+            );
+            if let Err(err) = fs.lock_vault() {
+                error!("Failed to lock idle filesystem: {}", err);
+            }
+        }
+        false
+    })
+}
+
+/// Flushes and unmounts the filesystem on a background thread once no FUSE
+/// request has arrived for `timeout` and no handles are open, after which the
+/// daemon exits. A rekey in progress postpones the unmount until it has
+/// finished.
+pub fn start_idle_unmount<F>(
+    fs: SharedFs,
+    rekey: BackgroundRekey,
+    timeout: Duration,
+    mut unmounter: F,
+) -> io::Result<()>
+where
+    F: FnMut() -> io::Result<()> + Send + 'static,
+{
+    watch("idle-unmount", fs, timeout, move |fs, idle| {
+        if rekey.is_running() {
+            return false;
+        }
+        // The filesystem mutex is released before unmounting, as the kernel
+        // may still send requests to the daemon while detaching.
+        {
+            let mut fs = fs.lock();
+            let open_handles = fs.open_handles();
+            if open_handles > 0 {
+                debug!(
+                    "Idle but {} handle(s) are open, staying mounted",
+                    open_handles
+                );
+                return false;
+            }
+            if let Err(err) = fs.flush_all() {
+                error!("Failed to flush idle filesystem: {}", err);
+                return false;
+            }
+        }
+        info!(
+            "Unmounting after {} without requests",
+            format_duration(idle.as_secs())
+        );
+        match unmounter() {
+            Ok(()) => true,
+            Err(err) => {
+                error!("Failed to unmount idle filesystem: {}", err);
+                false
+            }
+        }
+    })
+}
+
+/// Calls `on_idle` on a background thread whenever no FUSE request has arrived
+/// for `timeout`, until it returns `true`.
+fn watch<F>(name: &str, fs: SharedFs, timeout: Duration, mut on_idle: F) -> io::Result<()>
+where
+    F: FnMut(&SharedFs, Duration) -> bool + Send + 'static,
+{
+    thread::Builder::new()
+        .name(name.to_string())
+        .spawn(move || {
+            loop {
+                let idle = fs.idle_time();
+                if idle >= timeout && on_idle(&fs, idle) {
+                    return;
+                }
+                let wait = match timeout.checked_sub(idle) {
+                    Some(wait) if !wait.is_zero() => wait.min(CHECK_INTERVAL),
+                    _ => CHECK_INTERVAL,
+                };
+                thread::sleep(wait);
+            }
+        })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::sync::mpsc;
+
+    use fuser::FUSE_ROOT_ID;
+
+    use super::*;
+
+    fn start_unmount_watch(fs: &SharedFs) -> io::Result<mpsc::Receiver<()>> {
+        let (sender, receiver) = mpsc::channel();
+        let rekey = BackgroundRekey::new(fs.clone(), Path::new("/vaults/personal"));
+        start_idle_unmount(fs.clone(), rekey, Duration::ZERO, move || {
+            sender.send(()).map_err(io::Error::other)
+        })?;
+        Ok(receiver)
+    }
+
+    #[test]
+    fn test_idle_unmount() -> io::Result<()> {
+        let fs = SharedFs::new(Default::default());
+        let unmounted = start_unmount_watch(&fs)?;
+        let result = unmounted.recv_timeout(Duration::from_secs(5));
+        assert!(result.is_ok(), "Expected an unmount, but got {:?}", result);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_idle_unmount_waits_for_open_handles() -> io::Result<()> {
+        let fs = SharedFs::new(Default::default());
+        fs.lock().allocate_handle(FUSE_ROOT_ID);
+        let unmounted = start_unmount_watch(&fs)?;
+        let result = unmounted.recv_timeout(Duration::from_millis(200));
+        assert!(result.is_err(), "Expected no unmount, but got {:?}", result);
+
+        Ok(())
+    }
+}
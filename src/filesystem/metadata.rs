@@ -0,0 +1,186 @@
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+
+use fuser::FileAttr;
+use fuser::FileType;
+use serde::Deserialize;
+use serde::Serialize;
+
+/// Name of the object holding the metadata of inode `ino`.
+pub fn inode_object(ino: u64) -> String {
+    format!("{ino:016x}")
+}
+
+/// Name of the object holding block `index` of the file with inode `ino`.
+pub fn block_object(ino: u64, index: u64) -> String {
+    format!("{ino:016x}.{index:08x}")
+}
+
+/// What an object in the store holds, as told by its name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ObjectName {
+    Inode(u64),
+    Block(u64, u64),
+}
+
+impl ObjectName {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.split_once('.') {
+            None => u64::from_str_radix(name, 16).ok().map(ObjectName::Inode),
+            Some((ino, index)) => Some(ObjectName::Block(
+                u64::from_str_radix(ino, 16).ok()?,
+                u64::from_str_radix(index, 16).ok()?,
+            )),
+        }
+    }
+}
+
+/// The kinds of inodes the filesystem supports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InodeKind {
+    File,
+    Directory,
+}
+
+/// The stored metadata of an inode. Directories also hold their entries.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InodeRecord {
+    pub ino: u64,
+    pub kind: InodeKind,
+    pub size: u64,
+    pub perm: u16,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub flags: u32,
+    pub atime: SystemTime,
+    pub mtime: SystemTime,
+    pub ctime: SystemTime,
+    pub crtime: SystemTime,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub entries: BTreeMap<String, u64>,
+    /// The version of each child's inode object, by inode. Children missing
+    /// here were written before versions were recorded.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub child_versions: BTreeMap<u64, u64>,
+    /// The version of each block object of a file, `None` for blocks that
+    /// were never written. Empty in records written before versions were.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub block_versions: Vec<Option<u64>>,
+}
+
+impl InodeRecord {
+    pub fn new(attr: &FileAttr, entries: BTreeMap<String, u64>) -> Self {
+        Self {
+            ino: attr.ino,
+            kind: match attr.kind {
+                FileType::Directory => InodeKind::Directory,
+                _ => InodeKind::File,
+            },
+            size: attr.size,
+            perm: attr.perm,
+            nlink: attr.nlink,
+            uid: attr.uid,
+            gid: attr.gid,
+            flags: attr.flags,
+            atime: attr.atime,
+            mtime: attr.mtime,
+            ctime: attr.ctime,
+            crtime: attr.crtime,
+            entries,
+            child_versions: BTreeMap::new(),
+            block_versions: Vec::new(),
+        }
+    }
+
+    pub fn attr(&self) -> FileAttr {
+        let (kind, blocks) = match self.kind {
+            InodeKind::Directory => (FileType::Directory, 8),
+            InodeKind::File => (FileType::RegularFile, file_blocks(self.size)),
+        };
+        FileAttr {
+            ino: self.ino,
+            size: self.size,
+            blocks,
+            atime: self.atime,
+            mtime: self.mtime,
+            ctime: self.ctime,
+            crtime: self.crtime,
+            kind,
+            perm: self.perm,
+            nlink: self.nlink,
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            blksize: 4096,
+            flags: self.flags,
+        }
+    }
+}
+
+/// The number of 512-byte blocks reported for a file of `size` bytes.
+pub fn file_blocks(size: u64) -> u64 {
+    if size == 0 {
+        0
+    } else {
+        std::cmp::max(8, size.div_ceil(512))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+
+    #[test]
+    fn test_object_names_round_trip() {
+        assert_eq!(
+            ObjectName::parse(&inode_object(1)),
+            Some(ObjectName::Inode(1))
+        );
+        assert_eq!(
+            ObjectName::parse(&block_object(0x2a, 3)),
+            Some(ObjectName::Block(0x2a, 3))
+        );
+        assert_eq!(ObjectName::parse("vylfs.header"), None);
+        // Blocks sort right after the inode they belong to.
+        assert!(inode_object(2) < block_object(2, 0));
+        assert!(block_object(2, 0xffff) < inode_object(3));
+    }
+
+    #[test]
+    fn test_record_round_trip() -> io::Result<()> {
+        let record = InodeRecord {
+            ino: 5,
+            kind: InodeKind::Directory,
+            size: 4096,
+            perm: 0o750,
+            nlink: 2,
+            uid: 1000,
+            gid: 1000,
+            flags: 0,
+            atime: SystemTime::UNIX_EPOCH,
+            mtime: SystemTime::now(),
+            ctime: SystemTime::now(),
+            crtime: SystemTime::UNIX_EPOCH,
+            entries: BTreeMap::from([("notes".to_string(), 6)]),
+            child_versions: BTreeMap::from([(6, 42)]),
+            block_versions: Vec::new(),
+        };
+
+        let json = serde_json::to_vec(&record)?;
+        let parsed: InodeRecord = serde_json::from_slice(&json)?;
+        assert_eq!(parsed, record);
+        assert_eq!(
+            InodeRecord {
+                child_versions: record.child_versions.clone(),
+                ..InodeRecord::new(&parsed.attr(), record.entries.clone())
+            },
+            record
+        );
+
+        Ok(())
+    }
+}
@@ -1,9 +1,25 @@
-mod directory;
+pub mod blocks;
+pub mod control;
+pub mod directory;
+pub mod fsck;
+mod idle;
+pub mod metadata;
 pub mod mount;
+pub mod mounts;
+pub mod options;
+pub mod rekey;
+mod shared;
+mod tree;
 pub mod unmount;
+pub mod upgrade;
+pub mod vault_lock;
 
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::ffi::OsStr;
+use std::io;
+use std::path::PathBuf;
 use std::time::Duration;
 use std::time::SystemTime;
 
@@ -18,32 +34,674 @@ use fuser::ReplyData;
 use fuser::ReplyDirectory;
 use fuser::ReplyEmpty;
 use fuser::ReplyEntry;
+use fuser::ReplyOpen;
 use fuser::ReplyWrite;
 use fuser::Request;
 use libc::getegid;
 use libc::geteuid;
+use tracing::debug;
+use tracing::error;
 use tracing::info;
+use tracing::warn;
+use zeroize::Zeroize;
+use zeroize::Zeroizing;
+
+use crate::filesystem::blocks::BLOCK_SIZE;
+use crate::filesystem::blocks::BlockCache;
+use crate::filesystem::blocks::BlockKey;
+use crate::filesystem::blocks::DEFAULT_CACHE_BLOCKS;
+use crate::filesystem::metadata::InodeRecord;
+use crate::filesystem::metadata::ObjectName;
+use crate::filesystem::metadata::block_object;
+use crate::filesystem::metadata::file_blocks;
+use crate::filesystem::metadata::inode_object;
+use crate::filesystem::tree::TREE_OBJECT;
+use crate::filesystem::tree::Tree;
+use crate::filesystem::tree::TreeRoot;
+use crate::filesystem::tree::check_version;
+use crate::filesystem::tree::read_tree;
+use crate::log::Redacted;
+use crate::vault::Keyring;
+use crate::vault::MasterKey;
+use crate::vault::epoch::SeenEpoch;
+use crate::vault::store::Store;
+
+/// Directory entries, the inode of each child by its parent and name.
+type Entries = HashMap<(u64, String), u64>;
 
 #[derive(Debug)]
 pub struct VylFs {
     ttl: Duration,
+    read_only: bool,
+    locked: bool,
     inode_counter: u64,
     inodes: HashMap<u64, FileAttr>,
-    entries: HashMap<(u64, String), u64>,
-    file_data: HashMap<u64, Vec<u8>>,
+    entries: Entries,
+    /// Where inodes and blocks are persisted. Without a store the filesystem
+    /// only lives in memory.
+    store: Option<Store>,
+    /// Root directory of the vault while it is locked and its store, with the
+    /// keys, has been dropped.
+    locked_root: Option<PathBuf>,
+    blocks: BlockCache,
+    /// Inodes whose stored metadata is out of date.
+    dirty_inodes: BTreeSet<u64>,
+    /// Inodes whose objects are removed on the next flush.
+    removed_inodes: BTreeSet<u64>,
+    /// Blocks beyond the end of a truncated or removed file, whose objects are
+    /// removed on the next flush.
+    stale_blocks: BTreeSet<BlockKey>,
+    /// The version of every stored object, as recorded in the tree.
+    versions: HashMap<ObjectName, u64>,
+    /// The highest object version handed out.
+    version_counter: u64,
+    /// Where the epoch is recorded each time the tree is written, so that a
+    /// crash does not leave an older one behind.
+    seen_epoch: Option<SeenEpoch>,
+    /// Whether `unlock_vault` accepts a vault older than the one locked.
+    accept_rollback: bool,
+    /// The epoch before the current key generation wrote its first object,
+    /// see `check_write_limit`.
+    key_epoch: u64,
+    /// Whether the approaching write limit of the key was warned about.
+    warned_write_limit: bool,
+    next_fh: u64,
+    open_handles: HashMap<u64, u64>,
 }
 
 impl VylFs {
+    /// Loads the filesystem stored in `store`, creating its root directory if
+    /// the store is empty.
+    pub fn load(store: Store, read_only: bool) -> io::Result<Self> {
+        let tree = read_tree(&store)?;
+        let mut fs = Self {
+            read_only,
+            store: Some(store),
+            blocks: BlockCache::bounded(DEFAULT_CACHE_BLOCKS),
+            ..Default::default()
+        };
+        if tree.inodes.is_empty() {
+            info!("Creating root directory of new filesystem");
+            fs.dirty_inodes.insert(FUSE_ROOT_ID);
+            if !read_only {
+                fs.flush_all()?;
+            }
+        } else {
+            let has_root = tree.root.is_some();
+            fs.set_tree(tree);
+            if !has_root && !read_only {
+                info!("Adding an authenticated tree to the vault");
+                fs.write_tree_root()?;
+            }
+        }
+        info!("Loaded {} inodes", fs.inodes.len());
+        Ok(fs)
+    }
+
+    fn set_tree(&mut self, tree: Tree) {
+        let next = tree.inodes.keys().max().map_or(FUSE_ROOT_ID, |ino| ino + 1);
+        self.inode_counter = self.inode_counter.max(next);
+        self.inodes = tree.inodes;
+        self.entries = tree.entries;
+        self.version_counter = self.version_counter.max(tree.counter);
+        let generation = self.store.as_ref().map_or(0, Store::current_generation);
+        self.key_epoch = TreeRoot::key_epoch(
+            tree.root.as_ref(),
+            generation,
+            self.version_counter,
+            tree.versions.len() as u64 + 1,
+        );
+        self.versions = tree.versions;
+    }
+
+    /// Records the version of the root directory as the root of the tree.
+    fn write_tree_root(&mut self) -> io::Result<()> {
+        let Some(store) = &self.store else {
+            return Ok(());
+        };
+        self.version_counter += 1;
+        let root = TreeRoot {
+            counter: self.version_counter,
+            root: self
+                .versions
+                .get(&ObjectName::Inode(FUSE_ROOT_ID))
+                .copied()
+                .unwrap_or_default(),
+            generation: store.current_generation(),
+            key_epoch: self.key_epoch,
+        };
+        store.write(
+            TREE_OBJECT,
+            self.version_counter,
+            &serde_json::to_vec(&root)?,
+        )?;
+        if let Some(seen_epoch) = &self.seen_epoch
+            && let Err(err) = seen_epoch.record(self.version_counter)
+        {
+            warn!("Failed to record the vault's epoch: {}", err);
+        }
+        Ok(())
+    }
+
     pub fn add_entry(&mut self, parent: u64, name: &str, attr: FileAttr) {
         let ino = attr.ino;
         self.inodes.insert(ino, attr);
         self.entries.insert((parent, name.to_string()), ino);
+        self.dirty_inodes.extend([parent, ino]);
     }
 
     pub fn remove_entry(&mut self, ino: &u64, key: &(u64, String)) {
-        self.inodes.remove(ino);
         self.entries.remove(key);
+        self.dirty_inodes.insert(key.0);
+        self.versions.remove(&ObjectName::Inode(*ino));
+        if let Some(attr) = self.inodes.remove(ino) {
+            if attr.kind == FileType::RegularFile {
+                self.discard_blocks(*ino, 0, attr.size.div_ceil(BLOCK_SIZE));
+            }
+            self.dirty_inodes.remove(ino);
+            self.removed_inodes.insert(*ino);
+        }
+    }
+
+    /// Writes all dirty data and metadata to storage.
+    ///
+    /// Blocks are written before the inodes that refer to them, and every
+    /// inode before its parent directory, so an interrupted flush never leaves
+    /// a file longer than its stored data. The tree root is written once all
+    /// of them are durable, and objects are only removed after it.
+    pub fn flush_all(&mut self) -> io::Result<()> {
+        let Some(store) = &self.store else {
+            return Ok(());
+        };
+
+        let dirty_blocks = self.blocks.dirty();
+        let changed = !dirty_blocks.is_empty() || !self.dirty_inodes.is_empty();
+        for key in dirty_blocks {
+            let data = self.blocks.peek(&key).expect("dirty block is cached");
+            self.version_counter += 1;
+            store.write(&block_object(key.0, key.1), self.version_counter, data)?;
+            self.versions
+                .insert(ObjectName::Block(key.0, key.1), self.version_counter);
+            self.blocks.mark_clean(&key);
+            self.dirty_inodes.insert(key.0);
+        }
+        for ino in self.dirty_tree() {
+            if let Some(attr) = self.inodes.get(&ino) {
+                let record = self.record(ino, attr);
+                self.version_counter += 1;
+                store.write(
+                    &inode_object(ino),
+                    self.version_counter,
+                    &serde_json::to_vec(&record)?,
+                )?;
+                self.versions
+                    .insert(ObjectName::Inode(ino), self.version_counter);
+            }
+        }
+        self.dirty_inodes.clear();
+        if changed {
+            store.sync()?;
+            self.write_tree_root()?;
+        }
+
+        let Some(store) = &self.store else {
+            return Ok(());
+        };
+        if !self.removed_inodes.is_empty() || !self.stale_blocks.is_empty() {
+            store.sync()?;
+        }
+        while let Some(&ino) = self.removed_inodes.first() {
+            store.remove(&inode_object(ino))?;
+            self.removed_inodes.remove(&ino);
+        }
+        while let Some(&(ino, index)) = self.stale_blocks.first() {
+            store.remove(&block_object(ino, index))?;
+            self.stale_blocks.remove(&(ino, index));
+        }
+        store.sync()?;
+
+        debug!("Flushed filesystem state");
+        Ok(())
+    }
+
+    /// Returns the dirty inodes together with all directories above them,
+    /// deepest first, so that every record is written after the children it
+    /// records the versions of.
+    fn dirty_tree(&self) -> Vec<u64> {
+        let parents: HashMap<u64, u64> = self
+            .entries
+            .iter()
+            .map(|((parent, _), child)| (*child, *parent))
+            .collect();
+        let mut depths = HashMap::new();
+        for &ino in &self.dirty_inodes {
+            let mut path = vec![ino];
+            while let Some(parent) = parents.get(path.last().expect("path is not empty")) {
+                path.push(*parent);
+            }
+            for (height, ino) in path.iter().enumerate() {
+                depths.insert(*ino, path.len() - height);
+            }
+        }
+        let mut order: Vec<(usize, u64)> = depths
+            .into_iter()
+            .map(|(ino, depth)| (depth, ino))
+            .collect();
+        order.sort_unstable_by(|a, b| b.cmp(a));
+        order.into_iter().map(|(_, ino)| ino).collect()
+    }
+
+    /// Builds the stored record of inode `ino`, with the versions of the
+    /// objects it refers to.
+    fn record(&self, ino: u64, attr: &FileAttr) -> InodeRecord {
+        let mut record = InodeRecord::new(attr, self.children(ino));
+        record.child_versions = record
+            .entries
+            .values()
+            .filter_map(|child| Some((*child, *self.versions.get(&ObjectName::Inode(*child))?)))
+            .collect();
+        if attr.kind == FileType::RegularFile {
+            record.block_versions = (0..attr.size.div_ceil(BLOCK_SIZE))
+                .map(|index| self.versions.get(&ObjectName::Block(ino, index)).copied())
+                .collect();
+        }
+        record
+    }
+
+    /// Adds a master key generation to the store, see `Store::add_key`.
+    pub fn add_key(&mut self, generation: u32, key: MasterKey) {
+        if let Some(store) = &mut self.store {
+            store.add_key(generation, key);
+            if store.current_generation() == generation {
+                // The rekey that follows re-encrypts every object once.
+                self.key_epoch = self
+                    .version_counter
+                    .saturating_sub(self.versions.len() as u64 + 1);
+                self.warned_write_limit = false;
+            }
+        }
+    }
+
+    /// Drops the retired key generations from the store once a rekey has
+    /// re-encrypted every object, see `Store::retire_keys`.
+    pub fn retire_keys(&mut self) {
+        if let Some(store) = &mut self.store {
+            store.retire_keys();
+        }
+    }
+
+    /// Lists the objects in the store.
+    pub fn object_names(&self) -> io::Result<Vec<String>> {
+        self.check_unlocked()?;
+        match &self.store {
+            Some(store) => store.names(),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Re-encrypts one object under the newest key generation.
+    pub fn reencrypt(&self, name: &str) -> io::Result<()> {
+        self.check_unlocked()?;
+        if let Some(store) = &self.store {
+            store.reencrypt(name)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the number of files and directories currently held open.
+    pub fn open_handles(&self) -> usize {
+        self.open_handles.len()
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Returns whether filesystem requests are currently denied.
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    /// Fails while locked, for work such as a rekey that must not silently
+    /// skip the objects it cannot read.
+    fn check_unlocked(&self) -> io::Result<()> {
+        if self.locked {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "the vault is locked",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Writes all changes, then drops the master keys and every decrypted
+    /// inode, name and block, so that requests are denied until
+    /// `unlock_vault`. A filesystem without a store only denies requests, as
+    /// dropping its contents would lose them.
+    pub fn lock_vault(&mut self) -> io::Result<()> {
+        if self.locked {
+            return Ok(());
+        }
+        self.flush_all()?;
+        if let Some(store) = self.store.take() {
+            self.locked_root = Some(store.root_dir().to_path_buf());
+            self.inodes.clear();
+            for ((_, mut name), _) in self.entries.drain() {
+                name.zeroize();
+            }
+            self.blocks.clear();
+        }
+        self.locked = true;
+        info!("Filesystem locked");
+        Ok(())
+    }
+
+    /// Records the epoch in `seen_epoch` from now on, and accepts a vault
+    /// that was rolled back while locked if `accept_rollback` is set.
+    pub fn track_epoch(&mut self, seen_epoch: SeenEpoch, accept_rollback: bool) {
+        self.seen_epoch = Some(seen_epoch);
+        self.accept_rollback = accept_rollback;
+    }
+
+    /// Reopens the store with `keyring` after `lock_vault` and reloads the
+    /// inodes, staying locked if the keys do not decrypt them or the vault is
+    /// older than when it was locked.
+    pub fn unlock_vault(&mut self, keyring: Keyring) -> io::Result<()> {
+        if !self.locked {
+            return Ok(());
+        }
+        if let Some(root_dir) = &self.locked_root {
+            let store = Store::open(root_dir, keyring)?;
+            let tree = read_tree(&store)?;
+            if tree.inodes.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "the root directory of the vault is missing",
+                ));
+            }
+            if tree.counter < self.version_counter {
+                let message = format!(
+                    "the vault was rolled back to epoch {} while locked at epoch {}",
+                    tree.counter, self.version_counter
+                );
+                if !self.accept_rollback {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{message}; mount with --accept-rollback to accept it"),
+                    ));
+                }
+                warn!("Accepting rollback: {}", message);
+            }
+            self.store = Some(store);
+            self.set_tree(tree);
+            self.locked_root = None;
+        }
+        self.locked = false;
+        info!("Filesystem unlocked, {} inodes loaded", self.inodes.len());
+        Ok(())
+    }
+
+    /// Warns once the current key has encrypted three quarters of the objects
+    /// its cipher allows, see `Cipher::write_limit`, and refuses new files and
+    /// data past fifteen sixteenths. What is already cached can still be
+    /// flushed, and the rest leaves room for the rekey that replaces the key.
+    fn check_write_limit(&mut self) -> io::Result<()> {
+        let Some(store) = &self.store else {
+            return Ok(());
+        };
+        let Some(limit) = store.cipher().write_limit() else {
+            return Ok(());
+        };
+        let writes = self.version_counter.saturating_sub(self.key_epoch);
+        if writes >= limit - limit / 16 {
+            return Err(io::Error::new(
+                io::ErrorKind::QuotaExceeded,
+                format!(
+                    "the key has encrypted {writes} objects, close to the {limit} that {} allows, \
+                     run 'vylfs rekey' to replace it",
+                    store.cipher()
+                ),
+            ));
+        }
+        if writes >= limit - limit / 4 && !self.warned_write_limit {
+            warn!(
+                "The key has encrypted {} of the {} objects that {} allows, run 'vylfs rekey' to \
+                 replace it before writes are refused",
+                writes,
+                limit,
+                store.cipher()
+            );
+            self.warned_write_limit = true;
+        }
+        Ok(())
+    }
+
+    /// Returns the vault's epoch, the highest object version written so far.
+    pub fn epoch(&self) -> u64 {
+        self.version_counter
     }
+
+    /// Returns the number of files and directories.
+    pub fn inode_count(&self) -> usize {
+        self.inodes.len()
+    }
+
+    /// Returns the total size of all file contents.
+    pub fn data_bytes(&self) -> u64 {
+        self.inodes
+            .values()
+            .filter(|attr| attr.kind == FileType::RegularFile)
+            .map(|attr| attr.size)
+            .sum()
+    }
+
+    fn allocate_handle(&mut self, ino: u64) -> u64 {
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        self.open_handles.insert(fh, ino);
+        fh
+    }
+
+    /// Forgets handle `fh`, which the kernel no longer uses once it has been
+    /// released, whether the release succeeded or not.
+    pub fn close_handle(&mut self, fh: u64) {
+        self.open_handles.remove(&fh);
+    }
+
+    /// Returns the entries of directory `ino`.
+    fn children(&self, ino: u64) -> BTreeMap<String, u64> {
+        self.entries
+            .iter()
+            .filter(|((parent, _), _)| *parent == ino)
+            .map(|((_, name), child)| (name.clone(), *child))
+            .collect()
+    }
+
+    /// Adds a file or directory named `name` to directory `parent`.
+    fn new_node(
+        &mut self,
+        parent: u64,
+        name: &str,
+        kind: FileType,
+        mode: u32,
+    ) -> Result<FileAttr, i32> {
+        if self.read_only {
+            return Err(libc::EROFS);
+        }
+        self.check_write_limit().map_err(storage_error)?;
+        if self.entries.contains_key(&(parent, name.to_string())) {
+            return Err(libc::EEXIST);
+        }
+
+        let ino = self.inode_counter;
+        self.inode_counter += 1;
+
+        let (size, blocks, nlink) = match kind {
+            FileType::Directory => (4096, 8, 2),
+            _ => (0, 0, 1),
+        };
+        let uid = unsafe { geteuid() };
+        let gid = unsafe { getegid() };
+        let attr = FileAttr {
+            ino,
+            size,
+            blocks,
+            atime: SystemTime::now(),
+            mtime: SystemTime::now(),
+            ctime: SystemTime::now(),
+            crtime: SystemTime::now(),
+            kind,
+            perm: (mode & 0o7777) as u16,
+            nlink,
+            uid,
+            gid,
+            rdev: 0,
+            blksize: 4096,
+            flags: 0,
+        };
+
+        self.add_entry(parent, name, attr);
+        self.flush_all().map_err(storage_error)?;
+        Ok(attr)
+    }
+
+    /// Makes sure block `index` of file `ino` is cached. Blocks that were never
+    /// written read as empty.
+    fn load_block(&mut self, ino: u64, index: u64) -> io::Result<()> {
+        let key = (ino, index);
+        if self.blocks.contains(&key) {
+            return Ok(());
+        }
+
+        let expected = self.versions.get(&ObjectName::Block(ino, index));
+        let data = match (&self.store, expected) {
+            (Some(store), Some(&expected)) if !self.stale_blocks.contains(&key) => {
+                let name = block_object(ino, index);
+                check_version(&name, expected, store.read(&name)?)?
+            }
+            _ => Vec::new(),
+        };
+        let evicted = self.blocks.insert(key, data);
+        if let Some(store) = &self.store {
+            for ((ino, index), data) in evicted {
+                self.version_counter += 1;
+                store.write(&block_object(ino, index), self.version_counter, &data)?;
+                self.versions
+                    .insert(ObjectName::Block(ino, index), self.version_counter);
+                self.dirty_inodes.insert(ino);
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops blocks `from..to` of file `ino`, removing their objects on the
+    /// next flush.
+    fn discard_blocks(&mut self, ino: u64, from: u64, to: u64) {
+        for index in from..to {
+            self.blocks.remove(&(ino, index));
+            self.versions.remove(&ObjectName::Block(ino, index));
+            self.stale_blocks.insert((ino, index));
+        }
+    }
+
+    fn read_data(&mut self, ino: u64, offset: u64, size: u32) -> Result<Zeroizing<Vec<u8>>, i32> {
+        let Some(attr) = self.inodes.get(&ino) else {
+            return Err(libc::ENOENT);
+        };
+        if attr.kind == FileType::Directory {
+            return Err(libc::EISDIR);
+        }
+
+        let end = offset.saturating_add(size as u64).min(attr.size);
+        let mut data = Zeroizing::new(Vec::with_capacity(end.saturating_sub(offset) as usize));
+        let mut position = offset;
+        while position < end {
+            let index = position / BLOCK_SIZE;
+            let start = (position % BLOCK_SIZE) as usize;
+            let len = (((index + 1) * BLOCK_SIZE).min(end) - position) as usize;
+
+            self.load_block(ino, index).map_err(storage_error)?;
+            let block = self.blocks.get(&(ino, index)).expect("block was loaded");
+            let stored = block.get(start..).unwrap_or_default();
+            let stored = &stored[..len.min(stored.len())];
+            data.extend_from_slice(stored);
+            let filled = data.len() + len - stored.len();
+            data.resize(filled, 0);
+            position += len as u64;
+        }
+        Ok(data)
+    }
+
+    fn write_data(&mut self, ino: u64, offset: u64, data: &[u8]) -> Result<(), i32> {
+        if self.read_only {
+            return Err(libc::EROFS);
+        }
+        self.check_write_limit().map_err(storage_error)?;
+        match self.inodes.get(&ino) {
+            Some(attr) if attr.kind == FileType::Directory => return Err(libc::EISDIR),
+            Some(_) => {}
+            None => return Err(libc::ENOENT),
+        }
+
+        let mut written = 0;
+        while written < data.len() {
+            let position = offset + written as u64;
+            let index = position / BLOCK_SIZE;
+            let start = (position % BLOCK_SIZE) as usize;
+            let len = (BLOCK_SIZE as usize - start).min(data.len() - written);
+
+            self.load_block(ino, index).map_err(storage_error)?;
+            let block = self
+                .blocks
+                .get_mut(&(ino, index))
+                .expect("block was loaded");
+            if block.len() < start + len {
+                block.resize(start + len, 0);
+            }
+            block[start..start + len].copy_from_slice(&data[written..written + len]);
+            self.stale_blocks.remove(&(ino, index));
+            written += len;
+        }
+
+        let attr = self.inodes.get_mut(&ino).expect("inode was checked");
+        attr.size = attr.size.max(offset + data.len() as u64);
+        attr.blocks = file_blocks(attr.size);
+        attr.mtime = SystemTime::now();
+        self.dirty_inodes.insert(ino);
+        Ok(())
+    }
+
+    /// Changes the size of file `ino`, dropping the blocks past its new end.
+    fn truncate(&mut self, ino: u64, size: u64) -> io::Result<()> {
+        let Some(attr) = self.inodes.get_mut(&ino) else {
+            return Ok(());
+        };
+        let old_size = attr.size;
+        attr.size = size;
+        attr.blocks = file_blocks(size);
+        self.dirty_inodes.insert(ino);
+        if attr.kind == FileType::RegularFile && size < old_size {
+            self.discard_blocks(
+                ino,
+                size.div_ceil(BLOCK_SIZE),
+                old_size.div_ceil(BLOCK_SIZE),
+            );
+            if !size.is_multiple_of(BLOCK_SIZE) {
+                let index = size / BLOCK_SIZE;
+                self.load_block(ino, index)?;
+                if let Some(block) = self.blocks.get_mut(&(ino, index)) {
+                    block.truncate((size % BLOCK_SIZE) as usize);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Logs a storage error and returns the errno to fail a FUSE request with.
+fn storage_error(err: io::Error) -> i32 {
+    error!("Storage error: {}", err);
+    err.raw_os_error().unwrap_or(libc::EIO)
 }
 
 impl Default for VylFs {
@@ -71,10 +729,25 @@ impl Default for VylFs {
 
         Self {
             ttl,
+            read_only: false,
+            locked: false,
             inode_counter: FUSE_ROOT_ID + 1,
             inodes: HashMap::from([(FUSE_ROOT_ID, root_attr)]),
             entries: HashMap::new(),
-            file_data: HashMap::new(),
+            store: None,
+            locked_root: None,
+            blocks: BlockCache::default(),
+            dirty_inodes: BTreeSet::new(),
+            removed_inodes: BTreeSet::new(),
+            stale_blocks: BTreeSet::new(),
+            versions: HashMap::new(),
+            version_counter: 0,
+            seen_epoch: None,
+            accept_rollback: false,
+            key_epoch: 0,
+            warned_write_limit: false,
+            next_fh: 1,
+            open_handles: HashMap::new(),
         }
     }
 }
@@ -86,6 +759,10 @@ impl Filesystem for VylFs {
     }
 
     fn destroy(&mut self) {
+        if let Err(err) = self.flush_all() {
+            error!("Failed to flush filesystem state: {}", err);
+        }
+        self.blocks.clear();
         info!("Filesystem destroyed");
     }
 
@@ -152,10 +829,10 @@ impl Filesystem for VylFs {
         ];
 
         for ((parent, name), &child_ino) in &self.entries {
-            if *parent == ino {
-                if let Some(attr) = self.inodes.get(&child_ino) {
-                    entries.push((attr.ino, attr.kind, name.to_string()));
-                }
+            if *parent == ino
+                && let Some(attr) = self.inodes.get(&child_ino)
+            {
+                entries.push((attr.ino, attr.kind, name.to_string()));
             }
         }
 
@@ -181,6 +858,11 @@ impl Filesystem for VylFs {
         _flags: i32,
         reply: ReplyCreate,
     ) {
+        if self.read_only {
+            reply.error(libc::EROFS);
+            return;
+        }
+
         let name_str = match name.to_str() {
             Some(s) => s,
             None => {
@@ -189,38 +871,102 @@ impl Filesystem for VylFs {
             }
         };
 
-        if self.entries.contains_key(&(parent, name_str.to_string())) {
-            reply.error(libc::EEXIST);
+        match self.new_node(parent, name_str, FileType::RegularFile, mode) {
+            Ok(attr) => {
+                let fh = self.allocate_handle(attr.ino);
+                debug!(parent, ino = attr.ino, name = %Redacted(name), "Created file");
+                reply.created(&self.ttl, &attr, 0, fh, 0);
+            }
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn open(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        let Some(attr) = self.inodes.get(&ino) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+
+        if attr.kind == FileType::Directory {
+            reply.error(libc::EISDIR);
             return;
         }
 
-        let ino = self.inode_counter;
-        self.inode_counter += 1;
+        if self.read_only && flags & libc::O_ACCMODE != libc::O_RDONLY {
+            reply.error(libc::EROFS);
+            return;
+        }
 
-        let uid = unsafe { geteuid() };
-        let gid = unsafe { getegid() };
-        let attr = FileAttr {
-            ino,
-            size: 0,
-            blocks: 0,
-            atime: SystemTime::now(),
-            mtime: SystemTime::now(),
-            ctime: SystemTime::now(),
-            crtime: SystemTime::now(),
-            kind: FileType::RegularFile,
-            perm: (mode & 0o7777) as u16,
-            nlink: 1,
-            uid,
-            gid,
-            rdev: 0,
-            blksize: 4096,
-            flags: 0,
-        };
+        let fh = self.allocate_handle(ino);
+        reply.opened(fh, 0);
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.close_handle(fh);
+        match self.flush_all() {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(storage_error(err)),
+        }
+    }
 
-        self.add_entry(parent, name_str, attr);
-        self.file_data.insert(ino, Vec::new());
+    fn flush(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        _fh: u64,
+        _lock_owner: u64,
+        reply: ReplyEmpty,
+    ) {
+        match self.flush_all() {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(storage_error(err)),
+        }
+    }
+
+    fn fsync(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        _fh: u64,
+        _datasync: bool,
+        reply: ReplyEmpty,
+    ) {
+        match self.flush_all() {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(storage_error(err)),
+        }
+    }
+
+    fn opendir(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
+        match self.inodes.get(&ino) {
+            Some(attr) if attr.kind == FileType::Directory => {
+                let fh = self.allocate_handle(ino);
+                reply.opened(fh, 0);
+            }
+            Some(_) => reply.error(libc::ENOTDIR),
+            None => reply.error(libc::ENOENT),
+        }
+    }
 
-        reply.created(&self.ttl, &attr, 0, 0, 0);
+    fn releasedir(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        reply: ReplyEmpty,
+    ) {
+        self.close_handle(fh);
+        reply.ok();
     }
 
     fn setattr(
@@ -241,6 +987,18 @@ impl Filesystem for VylFs {
         flags: Option<u32>,
         reply: ReplyAttr,
     ) {
+        if self.read_only {
+            reply.error(libc::EROFS);
+            return;
+        }
+
+        if let Some(new_size) = size
+            && let Err(err) = self.truncate(ino, new_size)
+        {
+            reply.error(storage_error(err));
+            return;
+        }
+
         if let Some(attr) = self.inodes.get_mut(&ino) {
             if let Some(new_mode) = mode {
                 attr.perm = new_mode as u16;
@@ -254,11 +1012,6 @@ impl Filesystem for VylFs {
                 attr.gid = new_gid;
             }
 
-            if let Some(new_size) = size {
-                attr.size = new_size;
-                attr.blocks = new_size.div_ceil(attr.blksize as u64);
-            }
-
             if let Some(a) = atime {
                 attr.atime = match a {
                     fuser::TimeOrNow::SpecificTime(t) => t,
@@ -285,13 +1038,23 @@ impl Filesystem for VylFs {
                 attr.flags = f;
             }
 
-            reply.attr(&self.ttl, attr);
+            let attr = *attr;
+            self.dirty_inodes.insert(ino);
+            match self.flush_all() {
+                Ok(()) => reply.attr(&self.ttl, &attr),
+                Err(err) => reply.error(storage_error(err)),
+            }
         } else {
             reply.error(libc::ENOENT);
         }
     }
 
     fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        if self.read_only {
+            reply.error(libc::EROFS);
+            return;
+        }
+
         let name_str = match name.to_str() {
             Some(s) => s,
             None => {
@@ -301,10 +1064,14 @@ impl Filesystem for VylFs {
         };
 
         let key = (parent, name_str.to_string());
-        match self.entries.remove(&key) {
-            Some(ino) => {
-                self.inodes.remove(&ino);
-                reply.ok();
+        match self.entries.get(&key) {
+            Some(&ino) => {
+                self.remove_entry(&ino, &key);
+                debug!(parent, ino, name = %Redacted(name), "Unlinked file");
+                match self.flush_all() {
+                    Ok(()) => reply.ok(),
+                    Err(err) => reply.error(storage_error(err)),
+                }
             }
             None => {
                 reply.error(libc::ENOENT);
@@ -323,17 +1090,9 @@ impl Filesystem for VylFs {
         _lock_owner: Option<u64>,
         reply: ReplyData,
     ) {
-        if let Some(content) = self.file_data.get(&ino) {
-            let start = offset as usize;
-            let end = (offset as usize + size as usize).min(content.len());
-
-            if start > content.len() {
-                reply.data(&[]);
-            } else {
-                reply.data(&content[start..end]);
-            }
-        } else {
-            reply.error(libc::ENOENT);
+        match self.read_data(ino, offset as u64, size) {
+            Ok(data) => reply.data(&data),
+            Err(errno) => reply.error(errno),
         }
     }
 
@@ -349,29 +1108,14 @@ impl Filesystem for VylFs {
         _lock_owner: Option<u64>,
         reply: ReplyWrite,
     ) {
-        if let Some(buffer) = self.file_data.get_mut(&ino) {
-            let offset = offset as usize;
-            let end = offset + data.len();
-
-            if buffer.len() < end {
-                buffer.resize(end, 0);
-            }
-
-            buffer[offset..end].copy_from_slice(data);
-
-            if let Some(attr) = self.inodes.get_mut(&ino) {
-                attr.size = buffer.len() as u64;
-                attr.blocks = if attr.size == 0 {
-                    0
-                } else {
-                    std::cmp::max(8, attr.size.div_ceil(512))
-                };
-                attr.mtime = SystemTime::now();
-            }
+        if self.read_only {
+            reply.error(libc::EROFS);
+            return;
+        }
 
-            reply.written(data.len() as u32);
-        } else {
-            reply.error(libc::ENOENT);
+        match self.write_data(ino, offset as u64, data) {
+            Ok(()) => reply.written(data.len() as u32),
+            Err(errno) => reply.error(errno),
         }
     }
 
@@ -384,6 +1128,11 @@ impl Filesystem for VylFs {
         _umask: u32,
         reply: ReplyEntry,
     ) {
+        if self.read_only {
+            reply.error(libc::EROFS);
+            return;
+        }
+
         let name_str = match name.to_str() {
             Some(s) => s,
             None => {
@@ -392,39 +1141,21 @@ impl Filesystem for VylFs {
             }
         };
 
-        if self.entries.contains_key(&(parent, name_str.to_string())) {
-            reply.error(libc::EEXIST);
-            return;
+        match self.new_node(parent, name_str, FileType::Directory, mode) {
+            Ok(attr) => {
+                debug!(parent, ino = attr.ino, name = %Redacted(name), "Created directory");
+                reply.entry(&self.ttl, &attr, 0);
+            }
+            Err(errno) => reply.error(errno),
         }
-
-        let ino = self.inode_counter;
-        self.inode_counter += 1;
-
-        let uid = unsafe { geteuid() };
-        let gid = unsafe { getegid() };
-        let attr = FileAttr {
-            ino,
-            size: 4096,
-            blocks: 8,
-            atime: SystemTime::now(),
-            mtime: SystemTime::now(),
-            ctime: SystemTime::now(),
-            crtime: SystemTime::now(),
-            kind: FileType::Directory,
-            perm: (mode & 0o7777) as u16,
-            nlink: 2,
-            uid,
-            gid,
-            rdev: 0,
-            blksize: 4096,
-            flags: 0,
-        };
-
-        self.add_entry(parent, name_str, attr);
-        reply.entry(&self.ttl, &attr, 0);
     }
 
     fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        if self.read_only {
+            reply.error(libc::EROFS);
+            return;
+        }
+
         let name_str = match name.to_str() {
             Some(s) => s,
             None => {
@@ -455,6 +1186,371 @@ impl Filesystem for VylFs {
         }
 
         self.remove_entry(&ino, &key);
-        reply.ok();
+        debug!(parent, ino, name = %Redacted(name), "Removed directory");
+        match self.flush_all() {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(storage_error(err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::vault::Credential;
+    use crate::vault::Vault;
+    use crate::vault::crypto::Cipher;
+    use crate::vault::rekey;
+    use crate::vault::store::OBJECTS_DIR_NAME;
+    use crate::vault::tests::TEST_PARAMS;
+
+    fn test_keyring() -> io::Result<Keyring> {
+        Ok(Keyring::new(0, MasterKey::generate()?))
+    }
+
+    #[test]
+    fn test_load_persists_files_and_directories() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let keyring = test_keyring()?;
+        let data: Vec<u8> = (0..BLOCK_SIZE * 2 + 100).map(|i| i as u8).collect();
+
+        let mut fs = VylFs::load(Store::open(temp_dir.path(), keyring.clone())?, false)?;
+        let dir = fs
+            .new_node(FUSE_ROOT_ID, "docs", FileType::Directory, 0o700)
+            .map_err(io::Error::from_raw_os_error)?;
+        let file = fs
+            .new_node(dir.ino, "notes", FileType::RegularFile, 0o600)
+            .map_err(io::Error::from_raw_os_error)?;
+        fs.write_data(file.ino, 0, &data)
+            .map_err(io::Error::from_raw_os_error)?;
+        fs.flush_all()?;
+        drop(fs);
+
+        let mut fs = VylFs::load(Store::open(temp_dir.path(), keyring)?, false)?;
+        assert_eq!(
+            fs.entries.get(&(FUSE_ROOT_ID, "docs".to_string())),
+            Some(&dir.ino)
+        );
+        assert_eq!(fs.inodes[&file.ino].size, data.len() as u64);
+        assert_eq!(fs.inodes[&file.ino].perm, 0o600);
+        assert_eq!(
+            *fs.read_data(file.ino, 0, data.len() as u32)
+                .map_err(io::Error::from_raw_os_error)?,
+            data
+        );
+        assert_eq!(
+            *fs.read_data(file.ino, BLOCK_SIZE - 2, 4)
+                .map_err(io::Error::from_raw_os_error)?,
+            &data[BLOCK_SIZE as usize - 2..BLOCK_SIZE as usize + 2]
+        );
+        assert_eq!(fs.inode_counter, file.ino + 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_truncate_and_unlink_remove_objects() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let store = Store::open(temp_dir.path(), test_keyring()?)?;
+        let mut fs = VylFs::load(store, false)?;
+        let file = fs
+            .new_node(FUSE_ROOT_ID, "big", FileType::RegularFile, 0o600)
+            .map_err(io::Error::from_raw_os_error)?;
+        fs.write_data(file.ino, 0, &vec![7; BLOCK_SIZE as usize * 3])
+            .map_err(io::Error::from_raw_os_error)?;
+        fs.flush_all()?;
+        assert_eq!(fs.object_names()?.len(), 6);
+
+        fs.truncate(file.ino, 10)?;
+        fs.flush_all()?;
+        assert_eq!(fs.object_names()?.len(), 4);
+
+        // Growing the file again reads zeros past the truncated data.
+        fs.write_data(file.ino, 20, b"x")
+            .map_err(io::Error::from_raw_os_error)?;
+        assert_eq!(
+            *fs.read_data(file.ino, 8, 13)
+                .map_err(io::Error::from_raw_os_error)?,
+            b"\x07\x07\0\0\0\0\0\0\0\0\0\0x"
+        );
+
+        let key = (FUSE_ROOT_ID, "big".to_string());
+        fs.remove_entry(&file.ino, &key);
+        fs.flush_all()?;
+        assert_eq!(
+            fs.object_names()?,
+            [inode_object(FUSE_ROOT_ID), TREE_OBJECT.to_string()]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cache_eviction_writes_back_blocks() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let store = Store::open(temp_dir.path(), test_keyring()?)?;
+        let mut fs = VylFs::load(store, false)?;
+        fs.blocks = BlockCache::bounded(1);
+        let file = fs
+            .new_node(FUSE_ROOT_ID, "file", FileType::RegularFile, 0o600)
+            .map_err(io::Error::from_raw_os_error)?;
+
+        fs.write_data(file.ino, 0, b"first")
+            .map_err(io::Error::from_raw_os_error)?;
+        fs.write_data(file.ino, BLOCK_SIZE, b"second")
+            .map_err(io::Error::from_raw_os_error)?;
+        assert_eq!(
+            *fs.read_data(file.ino, 0, 5)
+                .map_err(io::Error::from_raw_os_error)?,
+            b"first"
+        );
+        assert_eq!(
+            *fs.read_data(file.ino, BLOCK_SIZE, 6)
+                .map_err(io::Error::from_raw_os_error)?,
+            b"second"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rekey_keeps_files_readable() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let credential = Credential::Passphrase("passphrase".to_string().into());
+        let mut vault = Vault::init(temp_dir.path(), &credential, "personal", &TEST_PARAMS)?;
+        let store = Store::open(temp_dir.path(), vault.keyring().clone())?;
+        let mut fs = VylFs::load(store, false)?;
+        let file = fs
+            .new_node(FUSE_ROOT_ID, "notes", FileType::RegularFile, 0o600)
+            .map_err(io::Error::from_raw_os_error)?;
+        fs.write_data(file.ino, 0, b"before")
+            .map_err(io::Error::from_raw_os_error)?;
+        fs.flush_all()?;
+
+        vault.begin_rekey(false)?;
+        let generation = vault.keyring().current_generation();
+        fs.add_key(generation, vault.keyring().current().clone());
+        let names = fs.object_names()?;
+        rekey::run(temp_dir.path(), names, |name| fs.reencrypt(name), |_| {})?;
+        vault.finish_rekey()?;
+
+        let vault = Vault::unlock(temp_dir.path(), &credential)?;
+        assert_eq!(vault.keyring().len(), 1);
+        let store = Store::open(temp_dir.path(), vault.keyring().clone())?;
+        for name in store.names()? {
+            assert_eq!(store.generation(&name)?, Some(generation));
+        }
+        let mut fs = VylFs::load(store, false)?;
+        assert_eq!(
+            *fs.read_data(file.ino, 0, 6)
+                .map_err(io::Error::from_raw_os_error)?,
+            b"before"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_lock_drops_keys_and_unlock_reloads() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let keyring = test_keyring()?;
+        let mut fs = VylFs::load(Store::open(temp_dir.path(), keyring.clone())?, false)?;
+        let file = fs
+            .new_node(FUSE_ROOT_ID, "notes", FileType::RegularFile, 0o600)
+            .map_err(io::Error::from_raw_os_error)?;
+        fs.write_data(file.ino, 0, b"secret")
+            .map_err(io::Error::from_raw_os_error)?;
+
+        fs.lock_vault()?;
+        assert!(fs.is_locked());
+        assert!(fs.store.is_none());
+        assert!(fs.inodes.is_empty() && fs.entries.is_empty());
+        assert!(fs.blocks.peek(&(file.ino, 0)).is_none());
+        let result = fs.object_names();
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+
+        let result = fs.unlock_vault(test_keyring()?);
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+        assert!(fs.is_locked());
+
+        fs.unlock_vault(keyring)?;
+        assert!(!fs.is_locked());
+        assert_eq!(
+            fs.entries.get(&(FUSE_ROOT_ID, "notes".to_string())),
+            Some(&file.ino)
+        );
+        assert_eq!(
+            *fs.read_data(file.ino, 0, 6)
+                .map_err(io::Error::from_raw_os_error)?,
+            b"secret"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rollback_while_locked_is_refused() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let vault_dir = temp_dir.path().join("vault");
+        fs::create_dir(&vault_dir)?;
+        let objects = vault_dir.join(OBJECTS_DIR_NAME);
+        let keyring = test_keyring()?;
+        let mut fs = VylFs::load(Store::open(&vault_dir, keyring.clone())?, false)?;
+        let seen_epoch = SeenEpoch::in_dir(temp_dir.path(), "0123abcd");
+        fs.track_epoch(SeenEpoch::in_dir(temp_dir.path(), "0123abcd"), false);
+        let file = fs
+            .new_node(FUSE_ROOT_ID, "notes", FileType::RegularFile, 0o600)
+            .map_err(io::Error::from_raw_os_error)?;
+        fs.flush_all()?;
+        let old_objects = fs::read_dir(&objects)?
+            .map(|entry| {
+                let path = entry?.path();
+                Ok((path.clone(), fs::read(path)?))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        // Every write of the tree records the epoch.
+        fs.write_data(file.ino, 0, b"newer")
+            .map_err(io::Error::from_raw_os_error)?;
+        fs.flush_all()?;
+        assert_eq!(seen_epoch.get()?, Some(fs.epoch()));
+
+        fs.lock_vault()?;
+        for (path, contents) in &old_objects {
+            fs::write(path, contents)?;
+        }
+        fs::remove_file(objects.join(block_object(file.ino, 0)))?;
+        let result = fs.unlock_vault(keyring.clone());
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert!(fs.is_locked());
+
+        fs.accept_rollback = true;
+        fs.unlock_vault(keyring)?;
+        assert!(!fs.is_locked());
+        assert_eq!(fs.inodes[&file.ino].size, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_aes_256_gcm_write_limit() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let mut keyring = test_keyring()?;
+        let open = |keyring: &Keyring| {
+            Store::with_cipher(temp_dir.path(), keyring.clone(), Cipher::Aes256Gcm)
+        };
+        let mut fs = VylFs::load(open(&keyring)?, false)?;
+        let file = fs
+            .new_node(FUSE_ROOT_ID, "notes", FileType::RegularFile, 0o600)
+            .map_err(io::Error::from_raw_os_error)?;
+        fs.flush_all()?;
+        let limit = Cipher::Aes256Gcm
+            .write_limit()
+            .expect("AES-256-GCM has a write limit");
+
+        // Close to the limit, new data is refused until a rekey.
+        fs.version_counter = limit - limit / 16;
+        assert_eq!(fs.write_data(file.ino, 0, b"data"), Err(libc::EIO));
+        let result = fs.new_node(FUSE_ROOT_ID, "other", FileType::RegularFile, 0o600);
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+
+        let key = MasterKey::generate()?;
+        keyring.insert(1, key.clone());
+        fs.add_key(1, key);
+        fs.write_data(file.ino, 0, b"data")
+            .map_err(io::Error::from_raw_os_error)?;
+        fs.flush_all()?;
+        let key_epoch = fs.key_epoch;
+        assert!(fs.version_counter - key_epoch < limit / 16);
+        drop(fs);
+
+        // The tree root records since when the key writes.
+        let fs = VylFs::load(open(&keyring)?, false)?;
+        assert_eq!(fs.key_epoch, key_epoch);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tampering_is_detected() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let keyring = test_keyring()?;
+        let objects = temp_dir.path().join(OBJECTS_DIR_NAME);
+        let load = || VylFs::load(Store::open(temp_dir.path(), keyring.clone())?, false);
+
+        let mut fs = load()?;
+        let mut files = Vec::new();
+        for name in ["a", "b"] {
+            let file = fs
+                .new_node(FUSE_ROOT_ID, name, FileType::RegularFile, 0o600)
+                .map_err(io::Error::from_raw_os_error)?;
+            fs.write_data(file.ino, 0, name.as_bytes())
+                .map_err(io::Error::from_raw_os_error)?;
+            files.push(file.ino);
+        }
+        fs.flush_all()?;
+        let old_inode = fs::read(objects.join(inode_object(files[0])))?;
+        let old_block = fs::read(objects.join(block_object(files[0], 0)))?;
+        fs.write_data(files[0], 0, b"A")
+            .map_err(io::Error::from_raw_os_error)?;
+        fs.flush_all()?;
+        drop(fs);
+        let current = |name: &str| fs::read(objects.join(name));
+        let restore = |name: &str, contents: &[u8]| fs::write(objects.join(name), contents);
+
+        // A block rolled back to its old contents fails when it is read.
+        let block = current(&block_object(files[0], 0))?;
+        restore(&block_object(files[0], 0), &old_block)?;
+        let mut fs = load()?;
+        let result = fs.read_data(files[0], 0, 1);
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+        drop(fs);
+        restore(&block_object(files[0], 0), &block)?;
+
+        // Rolled back, swapped and deleted metadata fails at load.
+        let inode = current(&inode_object(files[0]))?;
+        let tree = current(TREE_OBJECT)?;
+        restore(&inode_object(files[0]), &old_inode)?;
+        let result = load();
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+        restore(&inode_object(files[0]), &current(&inode_object(files[1]))?)?;
+        let result = load();
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+        fs::remove_file(objects.join(inode_object(files[0])))?;
+        let result = load();
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+        restore(&inode_object(files[0]), &inode)?;
+        fs::remove_file(objects.join(TREE_OBJECT))?;
+        let result = load();
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+        restore(TREE_OBJECT, &tree)?;
+
+        let mut fs = load()?;
+        assert_eq!(
+            *fs.read_data(files[0], 0, 1)
+                .map_err(io::Error::from_raw_os_error)?,
+            b"A"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_only_load_of_empty_store() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let store = Store::open(temp_dir.path(), test_keyring()?)?;
+
+        let mut fs = VylFs::load(store, true)?;
+        assert_eq!(fs.inode_count(), 1);
+        assert!(fs.object_names()?.is_empty());
+        let result = fs.new_node(FUSE_ROOT_ID, "file", FileType::RegularFile, 0o600);
+        assert_eq!(result, Err(libc::EROFS));
+
+        Ok(())
     }
 }
@@ -1,39 +1,356 @@
 use std::error::Error;
-use std::fs::File;
+use std::fs;
+use std::io;
+use std::io::PipeReader;
+use std::io::PipeWriter;
+use std::io::Read;
+use std::io::Write;
 use std::path::Path;
+use std::process;
+use std::time::Duration;
+use std::time::Instant;
 
 use daemonize::Daemonize;
+use daemonize::Outcome;
 use fuser::MountOption;
-use fuser::mount2;
+use fuser::Session;
+use tracing::error;
 use tracing::info;
+use tracing::warn;
 
 use crate::filesystem::VylFs;
+use crate::filesystem::control::ControlServer;
+use crate::filesystem::control::DaemonInfo;
 use crate::filesystem::directory::validate_dir;
+use crate::filesystem::idle::start_idle_lock;
+use crate::filesystem::idle::start_idle_unmount;
+use crate::filesystem::mounts::DaemonRecord;
+use crate::filesystem::mounts::unix_time;
+use crate::filesystem::options::is_read_only;
+use crate::filesystem::options::with_defaults;
+use crate::filesystem::rekey::BackgroundRekey;
+use crate::filesystem::shared::SharedFs;
+use crate::filesystem::vault_lock::VaultLock;
+use crate::log::Redacted;
+use crate::log::create_log_file;
+use crate::vault::Vault;
+use crate::vault::epoch::SeenEpoch;
+use crate::vault::passphrase;
+use crate::vault::passphrase::CredentialSource;
+use crate::vault::rekey::RekeyProgress;
+use crate::vault::secret;
+use crate::vault::store::Store;
 
-/// Mounts the encrypted filesystem in a background daemon process.
-pub fn mount(root_dir: &Path, mount_point: &Path) -> Result<(), Box<dyn Error>> {
+/// Status byte sent by the daemon once the filesystem is mounted.
+const MOUNT_READY: u8 = 0;
+
+/// How a vault is mounted.
+#[derive(Debug, Default)]
+pub struct MountConfig {
+    /// Options passed to FUSE, before `with_defaults` is applied.
+    pub options: Vec<MountOption>,
+    /// Serve the filesystem from the calling process instead of a daemon.
+    pub foreground: bool,
+    /// Take over the vault lock even if its holder cannot be verified as gone.
+    pub break_lock: bool,
+    /// Mount the vault even if it is older than a copy that was seen before.
+    pub accept_rollback: bool,
+    /// Where the secret that unlocks the vault comes from.
+    pub credential: CredentialSource,
+    /// Lock the vault after this long without filesystem requests.
+    pub idle_lock: Option<Duration>,
+    /// Unmount and exit after this long without filesystem requests, once no
+    /// handles are open.
+    pub idle_timeout: Option<Duration>,
+}
+
+/// Mounts the encrypted filesystem, either in the foreground or in a background
+/// daemon process.
+///
+/// In daemon mode the calling process only returns once the daemon has reported
+/// whether the mount succeeded.
+pub fn mount(
+    root_dir: &Path,
+    mount_point: &Path,
+    config: &MountConfig,
+) -> Result<(), Box<dyn Error>> {
     validate_dir(root_dir)?;
     validate_dir(mount_point)?;
 
-    let stdout = File::create("/tmp/vylfs.out")?;
-    let stderr = File::create("/tmp/vylfs.err")?;
+    let options = with_defaults(&config.options);
+    let unlocked = unlock(root_dir, config, is_read_only(&options))?;
+
+    if config.foreground {
+        return serve(unlocked, mount_point, &options, config, None, |_| {});
+    }
 
-    Daemonize::new()
+    let (stdout, log_path) = create_log_file(mount_point)?;
+    let stderr = stdout.try_clone()?;
+    let (reader, writer) = io::pipe()?;
+
+    let outcome = Daemonize::new()
         .stdout(stdout)
         .stderr(stderr)
         .working_directory(".")
-        .start()
-        .map_err(|e| format!("failed to daemonize: {e}"))?;
+        .execute();
+
+    match outcome {
+        Outcome::Parent(Ok(_)) => {
+            drop(writer);
+            wait_for_daemon(reader, &log_path)
+        }
+        Outcome::Parent(Err(err)) => Err(format!("failed to daemonize: {err}").into()),
+        Outcome::Child(Ok(_)) => {
+            drop(reader);
+            serve(
+                unlocked,
+                mount_point,
+                &options,
+                config,
+                Some(&log_path),
+                |status| report_status(writer, status),
+            )
+        }
+        Outcome::Child(Err(err)) => {
+            report_status(writer, Err(&format!("failed to daemonize: {err}")));
+            Err(format!("failed to daemonize: {err}").into())
+        }
+    }
+}
+
+/// What serving a vault needs of it once it is unlocked. The keys are only
+/// kept by the store, so that locking the filesystem drops the last copy.
+struct Unlocked {
+    store: Store,
+    vault_id: String,
+    generation: u32,
+}
+
+/// Unlocks the vault at `root_dir` with the credential from `config` and
+/// finishes a rekey that the last mount completed, unless `read_only`. The
+/// vault and the credential are dropped before this returns.
+fn unlock(root_dir: &Path, config: &MountConfig, read_only: bool) -> io::Result<Unlocked> {
+    let credential = passphrase::credential(root_dir, &config.credential)?;
+    let mut vault = Vault::unlock(root_dir, &credential)?;
+    vault.check_format()?;
+    if !read_only && vault.finish_completed_rekey()? {
+        info!("Finished the rekey the last mount completed");
+    }
+    Ok(Unlocked {
+        store: Store::open(root_dir, vault.keyring().clone())?,
+        vault_id: vault.id(),
+        generation: vault.keyring().current_generation(),
+    })
+}
 
-    let options = vec![
-        MountOption::FSName("vylfs".to_string()),
-        MountOption::AutoUnmount,
-        MountOption::AllowRoot,
-    ];
+/// Mounts the filesystem, passes the result to `report` and serves requests
+/// until the filesystem is unmounted. A rekey that was interrupted is resumed
+/// in the background.
+fn serve<F: FnOnce(Result<(), &str>)>(
+    unlocked: Unlocked,
+    mount_point: &Path,
+    options: &[MountOption],
+    config: &MountConfig,
+    log_path: Option<&Path>,
+    report: F,
+) -> Result<(), Box<dyn Error>> {
+    if let Err(err) = secret::disable_core_dumps() {
+        warn!("Failed to disable core dumps: {}", err);
+    }
+    let info = DaemonInfo {
+        root_dir: fs::canonicalize(unlocked.store.root_dir())?,
+        mount_point: fs::canonicalize(mount_point)?,
+        started: Instant::now(),
+    };
+    let record = DaemonRecord {
+        pid: process::id(),
+        root_dir: info.root_dir.clone(),
+        mounted_at: unix_time(),
+        log_path: log_path.map(Path::to_path_buf),
+    };
+    let read_only = is_read_only(options);
+    let mounted = VaultLock::acquire(&info.root_dir, Some(&info.mount_point), config.break_lock)
+        .and_then(|vault_lock| {
+            let mut fs = VylFs::load(unlocked.store, read_only)?;
+            let seen_epoch = check_epoch(&unlocked.vault_id, fs.epoch(), config.accept_rollback)?;
+            fs.track_epoch(seen_epoch, config.accept_rollback);
+            let fs = SharedFs::new(fs);
+            let rekey = BackgroundRekey::new(fs.clone(), &info.root_dir);
+            let mut session = Session::new(fs.clone(), mount_point, options)?;
+            let mut unmounter = session.unmount_callable();
+            let registration = record.register(&info.mount_point)?;
+            resume_rekey(&info.root_dir, unlocked.generation, &rekey, read_only)?;
+            if let Some(timeout) = config.idle_lock {
+                start_idle_lock(fs.clone(), rekey.clone(), timeout)?;
+            }
+            if let Some(timeout) = config.idle_timeout {
+                let mut unmounter = session.unmount_callable();
+                start_idle_unmount(fs.clone(), rekey.clone(), timeout, move || {
+                    unmounter.unmount()
+                })?;
+            }
+            let control =
+                ControlServer::start(fs.clone(), info, move || unmounter.unmount(), rekey)?;
+            Ok((session, vault_lock, registration, control))
+        });
+    let (mut session, _vault_lock, _registration, _control) = match mounted {
+        Ok(mounted) => {
+            report(Ok(()));
+            mounted
+        }
+        Err(err) => {
+            report(Err(&err.to_string()));
+            return Err(err.into());
+        }
+    };
 
-    let fs = VylFs::default();
-    mount2(fs, mount_point, &options)?;
-    info!("Unmounted '{}' and exiting daemon", mount_point.display());
+    info!("Mounted '{}'", Redacted::path(mount_point));
+    session.run()?;
+    info!("Unmounted '{}'", Redacted::path(mount_point));
 
     Ok(())
 }
+
+/// Refuses a vault that is older than one seen before, unless
+/// `accept_rollback` is set, and records `epoch` as the newest one seen.
+/// The filesystem records each newer epoch after that.
+fn check_epoch(vault_id: &str, epoch: u64, accept_rollback: bool) -> io::Result<SeenEpoch> {
+    let seen_epoch = SeenEpoch::open(vault_id)?;
+    if let Err(err) = seen_epoch.check(epoch) {
+        if !accept_rollback {
+            return Err(err);
+        }
+        warn!("Accepting rollback: {}", err);
+    }
+    seen_epoch.record(epoch)?;
+    Ok(seen_epoch)
+}
+
+/// Restarts a rekey to key `generation` that was interrupted while the vault
+/// at `root_dir` was mounted.
+fn resume_rekey(
+    root_dir: &Path,
+    generation: u32,
+    rekey: &BackgroundRekey,
+    read_only: bool,
+) -> io::Result<()> {
+    let Some(progress) = RekeyProgress::read(root_dir)? else {
+        return Ok(());
+    };
+    if read_only || progress.generation != generation {
+        warn!("A rekey is in progress, run 'vylfs rekey' to finish it");
+        return Ok(());
+    }
+    info!("Resuming rekey {}", progress);
+    rekey.start()
+}
+
+/// Sends the mount result to the parent process, closing the pipe afterwards.
+fn report_status(mut writer: PipeWriter, status: Result<(), &str>) {
+    let result = match status {
+        Ok(()) => writer.write_all(&[MOUNT_READY]),
+        Err(message) => writer.write_all(message.as_bytes()),
+    };
+
+    if let Err(err) = result {
+        error!("Failed to report mount status to parent process: {}", err);
+    }
+}
+
+/// Blocks until the daemon reports the mount result or exits without reporting
+/// one.
+fn wait_for_daemon(mut reader: PipeReader, log_path: &Path) -> Result<(), Box<dyn Error>> {
+    let mut status = Vec::new();
+    reader.read_to_end(&mut status)?;
+
+    match status.as_slice() {
+        [MOUNT_READY] => Ok(()),
+        [] => Err(format!(
+            "daemon exited before reporting mount status, see '{}'",
+            log_path.display()
+        )
+        .into()),
+        message => Err(String::from_utf8_lossy(message).into_owned().into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::vault::Credential;
+    use crate::vault::tests::TEST_PARAMS;
+
+    #[test]
+    fn test_failed_mount_logs_no_raw_path() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let mount_point = temp_dir.path().join("mnt");
+        fs::create_dir(&mount_point)?;
+        let keyfile = temp_dir.path().join("keyfile");
+        fs::write(&keyfile, b"keyfile contents")?;
+        let config = MountConfig {
+            credential: CredentialSource::Keyfile(keyfile),
+            ..MountConfig::default()
+        };
+
+        // The error is what `main` logs, for a missing directory and for one
+        // that holds no vault.
+        let root_dir = temp_dir.path().join("vault");
+        for create in [false, true] {
+            if create {
+                fs::create_dir(&root_dir)?;
+            }
+            let result = mount(&root_dir, &mount_point, &config);
+            assert!(result.is_err(), "Expected an error, but got {:?}", result);
+            let message = result.unwrap_err().to_string();
+            assert!(!message.contains("vault'"), "{message}");
+            assert!(
+                !message.contains(&*temp_dir.path().to_string_lossy()),
+                "{message}"
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_locking_drops_the_only_keys() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let root_dir = temp_dir.path().join("vault");
+        fs::create_dir(&root_dir)?;
+        let keyfile = temp_dir.path().join("keyfile");
+        fs::write(&keyfile, b"keyfile contents")?;
+        let mut vault = Vault::init(
+            &root_dir,
+            &Credential::Passphrase("passphrase".to_string().into()),
+            "personal",
+            &TEST_PARAMS,
+        )?;
+        vault.add_slot(
+            "keyfile",
+            &Credential::from_keyfile(&keyfile)?,
+            &TEST_PARAMS,
+        )?;
+        let config = MountConfig {
+            credential: CredentialSource::Keyfile(keyfile),
+            ..MountConfig::default()
+        };
+
+        // Nothing but the store holds keys once the vault is unlocked.
+        let Unlocked {
+            store,
+            vault_id,
+            generation,
+        } = unlock(&root_dir, &config, false)?;
+        assert_eq!(vault_id, vault.id());
+        assert_eq!(generation, 0);
+
+        let mut fs = VylFs::load(store, false)?;
+        fs.lock_vault()?;
+        assert!(fs.store.is_none());
+        assert!(fs.inodes.is_empty() && fs.entries.is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,280 @@
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::filesystem::control::format_duration;
+use crate::paths::escape_path;
+use crate::paths::mount_id;
+use crate::paths::runtime_dir;
+
+/// Where the kernel lists the mounts visible to this process.
+const MOUNTINFO_PATH: &str = "/proc/self/mountinfo";
+
+/// Default `fsname` of our mounts, see `options::with_defaults`.
+const FS_NAME: &str = "vylfs";
+
+/// Metadata a daemon records about its mount while it is running.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DaemonRecord {
+    pub pid: u32,
+    pub root_dir: PathBuf,
+    /// Seconds since the Unix epoch at which the filesystem was mounted.
+    pub mounted_at: u64,
+    /// The daemon's log file, absent when running in the foreground.
+    pub log_path: Option<PathBuf>,
+}
+
+impl DaemonRecord {
+    /// Writes the record for `mount_point`, returning a guard that removes it
+    /// when dropped.
+    pub fn register(&self, mount_point: &Path) -> io::Result<Registration> {
+        let path = record_path(&mount_id(mount_point)?)?;
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(&path)?;
+        serde_json::to_writer(&mut file, self)?;
+        file.write_all(b"\n")?;
+        Ok(Registration { path })
+    }
+}
+
+/// Keeps a `DaemonRecord` on disk for as long as the mount is served.
+#[derive(Debug)]
+pub struct Registration {
+    path: PathBuf,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// A vylfs mount listed by the kernel, with the metadata of its daemon if it
+/// recorded any.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ActiveMount {
+    pub mount_point: PathBuf,
+    pub fs_name: String,
+    /// Whether the kernel mounted the filesystem read-only.
+    pub read_only: bool,
+    pub daemon: Option<DaemonRecord>,
+}
+
+impl fmt::Display for ActiveMount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let yes_no = |value: bool| if value { "yes" } else { "no" };
+        writeln!(f, "{}", self.mount_point.display())?;
+        writeln!(f, "  read-only:  {}", yes_no(self.read_only))?;
+        let Some(daemon) = &self.daemon else {
+            return writeln!(f, "  daemon:     unknown");
+        };
+        writeln!(f, "  root dir:   {}", daemon.root_dir.display())?;
+        writeln!(f, "  pid:        {}", daemon.pid)?;
+        let mounted_for = unix_time().saturating_sub(daemon.mounted_at);
+        writeln!(f, "  mounted:    {} ago", format_duration(mounted_for))?;
+        match &daemon.log_path {
+            Some(log_path) => writeln!(f, "  log:        {}", log_path.display()),
+            None => writeln!(f, "  log:        none (foreground)"),
+        }
+    }
+}
+
+/// A FUSE entry of `/proc/self/mountinfo`.
+#[derive(Debug, PartialEq)]
+struct MountInfoEntry {
+    mount_point: PathBuf,
+    fs_type: String,
+    source: String,
+    read_only: bool,
+}
+
+/// Lists the vylfs filesystems that are currently mounted.
+///
+/// A FUSE mount is included if its source is `vylfs` or if a daemon recorded
+/// metadata for it, which covers mounts with a custom `fsname`.
+pub fn active_mounts() -> io::Result<Vec<ActiveMount>> {
+    let mountinfo = fs::read_to_string(MOUNTINFO_PATH)?;
+    let mut mounts = Vec::new();
+
+    for entry in parse_mountinfo(&mountinfo) {
+        // The kernel lists canonical paths, so the mount point does not need
+        // to be resolved, which could block on an unresponsive daemon.
+        let daemon = read_record(&escape_path(&entry.mount_point))?;
+        if entry.source != FS_NAME && daemon.is_none() {
+            continue;
+        }
+        mounts.push(ActiveMount {
+            mount_point: entry.mount_point,
+            fs_name: entry.source,
+            read_only: entry.read_only,
+            daemon,
+        });
+    }
+
+    Ok(mounts)
+}
+
+/// Returns the current time in seconds since the Unix epoch.
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+fn record_path(mount_id: &str) -> io::Result<PathBuf> {
+    Ok(runtime_dir()?.join(format!("{mount_id}.json")))
+}
+
+fn read_record(mount_id: &str) -> io::Result<Option<DaemonRecord>> {
+    let contents = match fs::read(record_path(mount_id)?) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    // A record that cannot be parsed was left behind by an incompatible
+    // version and says nothing reliable about the mount.
+    Ok(serde_json::from_slice(&contents).ok())
+}
+
+/// Parses the FUSE mounts out of the contents of a `mountinfo` file.
+///
+/// Each line has the form
+/// `id parent major:minor root mount_point options [optional...] - type source
+/// super_options`.
+fn parse_mountinfo(mountinfo: &str) -> Vec<MountInfoEntry> {
+    mountinfo
+        .lines()
+        .filter_map(|line| {
+            let (mount_fields, fs_fields) = line.split_once(" - ")?;
+            let mount_fields: Vec<&str> = mount_fields.split(' ').collect();
+            let mut fs_fields = fs_fields.split(' ');
+            let fs_type = fs_fields.next()?;
+            let source = fs_fields.next()?;
+
+            if fs_type != "fuse" && !fs_type.starts_with("fuse.") {
+                return None;
+            }
+
+            Some(MountInfoEntry {
+                mount_point: PathBuf::from(unescape_octal(mount_fields.get(4)?)),
+                fs_type: fs_type.to_string(),
+                source: unescape_octal(source).to_string_lossy().into_owned(),
+                read_only: mount_fields.get(5)?.split(',').any(|option| option == "ro"),
+            })
+        })
+        .collect()
+}
+
+/// Decodes the `\ooo` escapes the kernel uses for spaces, tabs, newlines and
+/// backslashes in `mountinfo`.
+fn unescape_octal(field: &str) -> OsString {
+    let bytes = field.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escape = bytes.get(i + 1..i + 4).filter(|digits| {
+            bytes[i] == b'\\' && digits.iter().all(|digit| (b'0'..=b'7').contains(digit))
+        });
+        match escape {
+            Some(digits) => {
+                let value = digits
+                    .iter()
+                    .fold(0u32, |value, digit| value * 8 + u32::from(digit - b'0'));
+                decoded.push(value as u8);
+                i += 4;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    OsString::from_vec(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mountinfo() {
+        let mountinfo = "\
+22 1 0:21 / /proc rw,nosuid,nodev,noexec,relatime shared:12 - proc proc rw
+61 22 0:52 / /mnt/vault rw,nosuid,nodev,relatime shared:33 - fuse vylfs rw,user_id=1000
+62 22 0:53 / /mnt/my\\040vault ro,nosuid,nodev,relatime - fuse.vylfs backup rw,user_id=1000
+63 22 0:54 / /mnt/other rw,relatime - fuse.sshfs host:/ rw,user_id=1000
+";
+
+        let entries = parse_mountinfo(mountinfo);
+        assert_eq!(
+            entries,
+            [
+                MountInfoEntry {
+                    mount_point: PathBuf::from("/mnt/vault"),
+                    fs_type: "fuse".to_string(),
+                    source: "vylfs".to_string(),
+                    read_only: false,
+                },
+                MountInfoEntry {
+                    mount_point: PathBuf::from("/mnt/my vault"),
+                    fs_type: "fuse.vylfs".to_string(),
+                    source: "backup".to_string(),
+                    read_only: true,
+                },
+                MountInfoEntry {
+                    mount_point: PathBuf::from("/mnt/other"),
+                    fs_type: "fuse.sshfs".to_string(),
+                    source: "host:/".to_string(),
+                    read_only: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unescape_octal() {
+        assert_eq!(unescape_octal("/mnt/a\\040b"), "/mnt/a b");
+        assert_eq!(unescape_octal("/mnt/a\\134b\\011"), "/mnt/a\\b\t");
+        assert_eq!(unescape_octal("/mnt/a\\09"), "/mnt/a\\09");
+        assert_eq!(unescape_octal("trailing\\"), "trailing\\");
+    }
+
+    #[test]
+    fn test_active_mount_json() -> io::Result<()> {
+        let mount = ActiveMount {
+            mount_point: PathBuf::from("/mnt/vault"),
+            fs_name: "vylfs".to_string(),
+            read_only: false,
+            daemon: Some(DaemonRecord {
+                pid: 42,
+                root_dir: PathBuf::from("/vaults/personal"),
+                mounted_at: 1_700_000_000,
+                log_path: None,
+            }),
+        };
+
+        let json = serde_json::to_value(&mount)?;
+        assert_eq!(json["mount_point"], "/mnt/vault");
+        assert_eq!(json["daemon"]["pid"], 42);
+        assert_eq!(json["daemon"]["log_path"], serde_json::Value::Null);
+
+        Ok(())
+    }
+}
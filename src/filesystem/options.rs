@@ -0,0 +1,131 @@
+use fuser::MountOption;
+
+/// Parses a single `-o` mount option into a `fuser::MountOption`.
+pub fn parse_mount_option(option: &str) -> Result<MountOption, String> {
+    match option.split_once('=') {
+        Some(("fsname", name)) if !name.is_empty() => Ok(MountOption::FSName(name.to_string())),
+        Some(("subtype", subtype)) if !subtype.is_empty() => {
+            Ok(MountOption::Subtype(subtype.to_string()))
+        }
+        Some((key, _)) => Err(format!("invalid value for mount option '{key}'")),
+        None => match option {
+            "ro" => Ok(MountOption::RO),
+            "allow_other" => Ok(MountOption::AllowOther),
+            "default_permissions" => Ok(MountOption::DefaultPermissions),
+            "noexec" => Ok(MountOption::NoExec),
+            "nosuid" => Ok(MountOption::NoSuid),
+            "nodev" => Ok(MountOption::NoDev),
+            "noatime" => Ok(MountOption::NoAtime),
+            _ => Err(format!("unsupported mount option '{option}'")),
+        },
+    }
+}
+
+/// Merges user supplied mount options with the defaults used by `vylfs`.
+pub fn with_defaults(options: &[MountOption]) -> Vec<MountOption> {
+    let mut merged = Vec::with_capacity(options.len() + 3);
+
+    if !options.iter().any(|o| matches!(o, MountOption::FSName(_))) {
+        merged.push(MountOption::FSName("vylfs".to_string()));
+    }
+    merged.push(MountOption::AutoUnmount);
+    if !options.contains(&MountOption::AllowOther) {
+        merged.push(MountOption::AllowRoot);
+    }
+
+    for option in options {
+        if !merged.contains(option) {
+            merged.push(option.clone());
+        }
+    }
+
+    merged
+}
+
+/// Returns whether the filesystem should be mounted read-only.
+pub fn is_read_only(options: &[MountOption]) -> bool {
+    options.contains(&MountOption::RO)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mount_option_flags() {
+        let cases = [
+            ("ro", MountOption::RO),
+            ("allow_other", MountOption::AllowOther),
+            ("default_permissions", MountOption::DefaultPermissions),
+            ("noexec", MountOption::NoExec),
+            ("nosuid", MountOption::NoSuid),
+            ("nodev", MountOption::NoDev),
+            ("noatime", MountOption::NoAtime),
+        ];
+
+        for (input, expected) in cases {
+            assert_eq!(
+                parse_mount_option(input),
+                Ok(expected),
+                "failed to parse '{input}'"
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_mount_option_with_value() {
+        assert_eq!(
+            parse_mount_option("fsname=secrets"),
+            Ok(MountOption::FSName("secrets".to_string()))
+        );
+        assert_eq!(
+            parse_mount_option("subtype=vylfs"),
+            Ok(MountOption::Subtype("vylfs".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_parse_mount_option_invalid() {
+        for input in ["", "rw", "fsname=", "subtype=", "ro=1", "allow_root"] {
+            let result = parse_mount_option(input);
+            assert!(
+                result.is_err(),
+                "Expected an error for '{input}', but got {:?}",
+                result
+            );
+        }
+    }
+
+    #[test]
+    fn test_with_defaults_keeps_defaults() {
+        let options = with_defaults(&[MountOption::NoExec]);
+        assert_eq!(
+            options,
+            vec![
+                MountOption::FSName("vylfs".to_string()),
+                MountOption::AutoUnmount,
+                MountOption::AllowRoot,
+                MountOption::NoExec,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_with_defaults_overrides() {
+        let options = with_defaults(&[
+            MountOption::FSName("secrets".to_string()),
+            MountOption::AllowOther,
+            MountOption::RO,
+        ]);
+        assert_eq!(
+            options,
+            vec![
+                MountOption::AutoUnmount,
+                MountOption::FSName("secrets".to_string()),
+                MountOption::AllowOther,
+                MountOption::RO,
+            ]
+        );
+        assert!(is_read_only(&options));
+    }
+}
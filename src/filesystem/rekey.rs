@@ -0,0 +1,74 @@
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::thread;
+
+use tracing::error;
+use tracing::info;
+
+use crate::filesystem::shared::SharedFs;
+use crate::vault::rekey;
+
+/// Re-encrypts the objects of a mounted vault on a background thread.
+///
+/// The filesystem is locked for one object at a time, so files stay readable
+/// and writable through the mount while the rekey runs.
+#[derive(Debug, Clone)]
+pub struct BackgroundRekey {
+    fs: SharedFs,
+    root_dir: PathBuf,
+    running: Arc<AtomicBool>,
+}
+
+impl BackgroundRekey {
+    pub fn new(fs: SharedFs, root_dir: &Path) -> Self {
+        Self {
+            fs,
+            root_dir: root_dir.to_path_buf(),
+            running: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Starts working through the vault's rekey progress record, unless that
+    /// is already happening.
+    pub fn start(&self) -> io::Result<()> {
+        if self.running.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        let rekey = self.clone();
+        let spawned = thread::Builder::new()
+            .name("rekey".to_string())
+            .spawn(move || {
+                if let Err(err) = rekey.run() {
+                    error!("Rekey stopped, it resumes at the next mount: {}", err);
+                }
+                rekey.running.store(false, Ordering::SeqCst);
+            });
+        if let Err(err) = spawned {
+            self.running.store(false, Ordering::SeqCst);
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    fn run(&self) -> io::Result<()> {
+        let names = self.fs.lock().object_names()?;
+        rekey::run(
+            &self.root_dir,
+            names,
+            |name| self.fs.lock().reencrypt(name),
+            |progress| info!("Rekey progress: {}", progress),
+        )?;
+        self.fs.lock().retire_keys();
+        info!("Rekey finished, retired keys were dropped");
+        Ok(())
+    }
+}
@@ -0,0 +1,268 @@
+use std::ffi::OsStr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+
+use fuser::Filesystem;
+use fuser::KernelConfig;
+use fuser::ReplyAttr;
+use fuser::ReplyCreate;
+use fuser::ReplyData;
+use fuser::ReplyDirectory;
+use fuser::ReplyEmpty;
+use fuser::ReplyEntry;
+use fuser::ReplyOpen;
+use fuser::ReplyWrite;
+use fuser::Request;
+use fuser::TimeOrNow;
+
+use crate::filesystem::VylFs;
+
+/// Lets the FUSE session share a `VylFs` with the daemon's control socket.
+///
+/// Every request locks the filesystem, so control requests are handled in
+/// between FUSE requests.
+#[derive(Debug, Clone)]
+pub struct SharedFs {
+    fs: Arc<Mutex<VylFs>>,
+    /// When the kernel last sent a request, denied ones included.
+    last_request: Arc<Mutex<Instant>>,
+}
+
+impl SharedFs {
+    pub fn new(fs: VylFs) -> Self {
+        Self {
+            fs: Arc::new(Mutex::new(fs)),
+            last_request: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// Locks the filesystem, recovering it if a previous request panicked.
+    pub fn lock(&self) -> MutexGuard<'_, VylFs> {
+        self.fs
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Returns how long ago the last FUSE request arrived.
+    pub fn idle_time(&self) -> Duration {
+        self.last_request
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .elapsed()
+    }
+
+    /// Counts as activity, so that an idle timeout starts over.
+    pub fn touch(&self) {
+        *self
+            .last_request
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Instant::now();
+    }
+
+    /// Locks the filesystem for a FUSE request, which counts as activity.
+    fn serve(&self) -> MutexGuard<'_, VylFs> {
+        self.touch();
+        self.lock()
+    }
+
+    /// Locks the filesystem for a FUSE request, or returns `None` if the vault
+    /// is locked and the request must be denied.
+    fn request(&self) -> Option<MutexGuard<'_, VylFs>> {
+        let fs = self.serve();
+        if fs.is_locked() { None } else { Some(fs) }
+    }
+
+    /// Locks the filesystem to release handle `fh`, or returns `None` if the
+    /// vault is locked and the release must be denied. The handle is closed
+    /// either way, as the kernel does not use it again.
+    fn release_request(&self, fh: u64) -> Option<MutexGuard<'_, VylFs>> {
+        let mut fs = self.serve();
+        if fs.is_locked() {
+            fs.close_handle(fh);
+            None
+        } else {
+            Some(fs)
+        }
+    }
+}
+
+/// Forwards a FUSE request to the shared filesystem, replying `EACCES` while
+/// the vault is locked.
+macro_rules! forward {
+    ($self:ident, $reply:ident, $method:ident($($arg:expr),* $(,)?)) => {
+        match $self.request() {
+            Some(mut fs) => fs.$method($($arg,)* $reply),
+            None => $reply.error(libc::EACCES),
+        }
+    };
+}
+
+impl Filesystem for SharedFs {
+    fn init(&mut self, req: &Request<'_>, config: &mut KernelConfig) -> Result<(), i32> {
+        self.lock().init(req, config)
+    }
+
+    fn destroy(&mut self) {
+        self.lock().destroy();
+    }
+
+    fn lookup(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        forward!(self, reply, lookup(req, parent, name));
+    }
+
+    fn getattr(&mut self, req: &Request<'_>, ino: u64, fh: Option<u64>, reply: ReplyAttr) {
+        forward!(self, reply, getattr(req, ino, fh));
+    }
+
+    fn readdir(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        reply: ReplyDirectory,
+    ) {
+        forward!(self, reply, readdir(req, ino, fh, offset));
+    }
+
+    fn create(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        flags: i32,
+        reply: ReplyCreate,
+    ) {
+        forward!(self, reply, create(req, parent, name, mode, umask, flags));
+    }
+
+    fn open(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        forward!(self, reply, open(req, ino, flags));
+    }
+
+    fn release(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        flags: i32,
+        lock_owner: Option<u64>,
+        flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        match self.release_request(fh) {
+            Some(mut fs) => fs.release(req, ino, fh, flags, lock_owner, flush, reply),
+            None => reply.error(libc::EACCES),
+        }
+    }
+
+    fn flush(&mut self, req: &Request<'_>, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
+        forward!(self, reply, flush(req, ino, fh, lock_owner));
+    }
+
+    fn fsync(&mut self, req: &Request<'_>, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        forward!(self, reply, fsync(req, ino, fh, datasync));
+    }
+
+    fn opendir(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        forward!(self, reply, opendir(req, ino, flags));
+    }
+
+    fn releasedir(&mut self, req: &Request<'_>, ino: u64, fh: u64, flags: i32, reply: ReplyEmpty) {
+        match self.release_request(fh) {
+            Some(mut fs) => fs.releasedir(req, ino, fh, flags, reply),
+            None => reply.error(libc::EACCES),
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        ctime: Option<SystemTime>,
+        fh: Option<u64>,
+        crtime: Option<SystemTime>,
+        chgtime: Option<SystemTime>,
+        bkuptime: Option<SystemTime>,
+        flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        forward!(
+            self,
+            reply,
+            setattr(
+                req, ino, mode, uid, gid, size, atime, mtime, ctime, fh, crtime, chgtime, bkuptime,
+                flags
+            )
+        );
+    }
+
+    fn unlink(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        forward!(self, reply, unlink(req, parent, name));
+    }
+
+    fn read(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        flags: i32,
+        lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        forward!(
+            self,
+            reply,
+            read(req, ino, fh, offset, size, flags, lock_owner)
+        );
+    }
+
+    fn write(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        write_flags: u32,
+        flags: i32,
+        lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        forward!(
+            self,
+            reply,
+            write(req, ino, fh, offset, data, write_flags, flags, lock_owner)
+        );
+    }
+
+    fn mkdir(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        reply: ReplyEntry,
+    ) {
+        forward!(self, reply, mkdir(req, parent, name, mode, umask));
+    }
+
+    fn rmdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        forward!(self, reply, rmdir(req, parent, name));
+    }
+}
@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+use std::io;
+
+use fuser::FUSE_ROOT_ID;
+use fuser::FileAttr;
+use fuser::FileType;
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::debug;
+
+use crate::filesystem::Entries;
+use crate::filesystem::blocks::BLOCK_SIZE;
+use crate::filesystem::metadata::InodeRecord;
+use crate::filesystem::metadata::ObjectName;
+use crate::filesystem::metadata::block_object;
+use crate::filesystem::metadata::inode_object;
+use crate::vault::store::Store;
+use crate::vault::store::StoredObject;
+
+/// Name of the object holding the `TreeRoot`.
+pub const TREE_OBJECT: &str = "tree";
+
+/// The root of the vault's authenticated tree, written after everything else
+/// by every flush.
+///
+/// Every object carries an authenticated version that is never reused, and
+/// every inode record lists the versions of its children and blocks. With the
+/// version of the root directory recorded here, an object that is swapped for
+/// another, deleted or rolled back to an older copy no longer matches the
+/// version its parent expects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TreeRoot {
+    /// The highest version handed out so far. As it only grows, it is also
+    /// the vault's epoch, see `SeenEpoch`.
+    pub counter: u64,
+    /// The version of the root directory's inode object.
+    pub root: u64,
+    /// The key generation the root was written under. Roots written before
+    /// this was recorded have 0.
+    #[serde(default)]
+    pub generation: u32,
+    /// The epoch before that key generation wrote its first object, from
+    /// which the objects it encrypted are counted.
+    #[serde(default)]
+    pub key_epoch: u64,
+}
+
+impl TreeRoot {
+    /// Returns the epoch from which the objects encrypted under key
+    /// `generation` are counted, given the last `root` of a tree whose epoch
+    /// is `counter`. A generation the root was not written under just
+    /// replaced the previous one, and the rekey re-encrypted up to `objects`
+    /// objects with it.
+    pub fn key_epoch(root: Option<&Self>, generation: u32, counter: u64, objects: u64) -> u64 {
+        match root {
+            Some(root) if root.generation == generation => root.key_epoch,
+            _ => counter.saturating_sub(objects),
+        }
+    }
+}
+
+/// The filesystem as found by walking the tree from its root.
+#[derive(Debug, Default)]
+pub struct Tree {
+    pub inodes: HashMap<u64, FileAttr>,
+    pub entries: Entries,
+    /// The version expected of every object reachable from the root.
+    pub versions: HashMap<ObjectName, u64>,
+    /// The highest version seen, which new versions continue from.
+    pub counter: u64,
+    /// The vault's `TreeRoot`, which vaults written before it was added lack.
+    pub root: Option<TreeRoot>,
+}
+
+impl Tree {
+    fn insert_version(&mut self, name: ObjectName, version: u64) {
+        self.versions.insert(name, version);
+        self.counter = self.counter.max(version);
+    }
+}
+
+/// Reads the inodes reachable from the root directory of `store`, checking
+/// every object against the version its parent recorded. Inode objects that
+/// are not reachable are left out.
+///
+/// Returns an empty tree for a store without a root directory.
+pub fn read_tree(store: &Store) -> io::Result<Tree> {
+    let mut tree = Tree::default();
+    let root = match store.read(TREE_OBJECT)? {
+        Some(object) => {
+            let root: TreeRoot = serde_json::from_slice(&object.data)?;
+            tree.counter = root.counter.max(object.version);
+            tree.root = Some(root);
+            Some(root)
+        }
+        None => None,
+    };
+    match (root, store.header(&inode_object(FUSE_ROOT_ID))?) {
+        (None, None) => {
+            let has_inodes = store
+                .names()?
+                .iter()
+                .any(|name| matches!(ObjectName::parse(name), Some(ObjectName::Inode(_))));
+            if has_inodes {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "the root directory of the vault is missing",
+                ));
+            }
+            return Ok(tree);
+        }
+        // Only vaults that never had a tree have an unversioned root.
+        (None, Some(header)) if header.version > 0 => {
+            return Err(tampered(TREE_OBJECT, "is missing"));
+        }
+        _ => {}
+    }
+
+    let mut pending = vec![(FUSE_ROOT_ID, root.map_or(0, |root| root.root))];
+    while let Some((ino, expected)) = pending.pop() {
+        let name = inode_object(ino);
+        if tree.inodes.contains_key(&ino) {
+            return Err(tampered(&name, "is linked more than once"));
+        }
+        let object = store.read(&name)?;
+        let version = object.as_ref().map_or(0, |object| object.version);
+        let record: InodeRecord = serde_json::from_slice(&check_version(&name, expected, object)?)?;
+        tree.insert_version(ObjectName::Inode(ino), version);
+
+        for (child_name, child) in &record.entries {
+            tree.entries.insert((ino, child_name.clone()), *child);
+            let expected = record.child_versions.get(child).copied().unwrap_or(0);
+            pending.push((*child, expected));
+        }
+        let attr = record.attr();
+        if attr.kind == FileType::RegularFile {
+            let count = attr.size.div_ceil(BLOCK_SIZE);
+            if record.block_versions.len() as u64 == count {
+                for (index, version) in record.block_versions.iter().enumerate() {
+                    if let Some(version) = version {
+                        tree.insert_version(ObjectName::Block(ino, index as u64), *version);
+                    }
+                }
+            } else {
+                // Without recorded versions, every stored block belongs to the
+                // file.
+                for index in 0..count {
+                    if let Some(header) = store.header(&block_object(ino, index))? {
+                        tree.insert_version(ObjectName::Block(ino, index), header.version);
+                    }
+                }
+            }
+        }
+        tree.inodes.insert(ino, attr);
+    }
+    Ok(tree)
+}
+
+/// Returns the data of `object` if it is at least as new as the version its
+/// parent expects.
+///
+/// A newer object was written after the last complete flush, such as before a
+/// crash, and is the most recent data there is. An older one was put back from
+/// a copy.
+pub fn check_version(
+    name: &str,
+    expected: u64,
+    object: Option<StoredObject>,
+) -> io::Result<Vec<u8>> {
+    match object {
+        None => Err(tampered(name, "is missing")),
+        Some(object) if object.version < expected => Err(tampered(
+            name,
+            &format!(
+                "was rolled back from version {expected} to {}",
+                object.version
+            ),
+        )),
+        Some(object) => {
+            if object.version > expected {
+                debug!(
+                    "Object '{}' is newer than recorded, it was written after the last flush",
+                    name
+                );
+            }
+            Ok(object.data)
+        }
+    }
+}
+
+fn tampered(name: &str, reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("the vault was tampered with, object '{name}' {reason}"),
+    )
+}
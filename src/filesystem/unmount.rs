@@ -1,30 +1,302 @@
 use std::ffi::CString;
+use std::ffi::OsString;
+use std::fs;
 use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path;
 use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::Duration;
 
+use libc::MNT_DETACH;
 use libc::MNT_FORCE;
 use libc::geteuid;
 use libc::umount2;
+use tracing::info;
+use tracing::warn;
 
-use crate::filesystem::directory::validate_dir;
+use crate::filesystem::control;
+use crate::filesystem::control::Request;
+use crate::filesystem::control::Response;
+use crate::filesystem::mounts::ActiveMount;
+use crate::filesystem::mounts::active_mounts;
+use crate::log::Redacted;
 
-/// Unmounts the given mount point using `umount2`, requires root privileges.
-pub fn unmount(mount_point: &Path) -> io::Result<()> {
-    validate_dir(mount_point)?;
+/// Setuid helpers shipped with libfuse that let users unmount their own FUSE
+/// mounts, in order of preference.
+const FUSERMOUNT_PROGRAMS: [&str; 2] = ["fusermount3", "fusermount"];
+
+/// How the mount point is detached.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum UnmountMode {
+    /// Fail if the filesystem is busy.
+    #[default]
+    Normal,
+    /// Detach the mount point now and clean up once it is no longer busy.
+    Lazy,
+    /// Abort pending requests and unmount even if busy, requires root.
+    Force,
+}
+
+/// Unmounts the given mount point after asking its daemon to flush.
+///
+/// In the default mode the daemon flushes and detaches itself, refusing if
+/// handles are still open. Lazy and forced unmounts flush first and then
+/// detach the mount point directly. Root uses `umount2`, other users go through
+/// `fusermount3 -u` like libfuse does. The daemon has `timeout` to answer, and
+/// only a forced unmount continues when it does not.
+pub fn unmount(mount_point: &Path, mode: UnmountMode, timeout: Duration) -> io::Result<()> {
+    let mount_point = find_mount(mount_point, &active_mounts()?)?;
+    unmount_at(&mount_point, mode, timeout)
+}
+
+/// Looks `mount_point` up among `mounts` and returns the path the kernel
+/// lists for it.
+///
+/// The mount point itself is never resolved, as a locked vault denies access
+/// to its root and an unresponsive daemon would block. Only its parent is.
+fn find_mount(mount_point: &Path, mounts: &[ActiveMount]) -> io::Result<PathBuf> {
+    let absolute = path::absolute(mount_point)?;
+    let resolved = match (absolute.parent(), absolute.file_name()) {
+        (Some(parent), Some(name)) => fs::canonicalize(parent).map(|parent| parent.join(name)),
+        _ => fs::canonicalize(&absolute),
+    };
+    let resolved = resolved.unwrap_or(absolute);
+    mounts
+        .iter()
+        .find(|mount| mount.mount_point == resolved)
+        .map(|mount| mount.mount_point.clone())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("'{}' is not a vylfs mount", Redacted::path(mount_point)),
+            )
+        })
+}
+
+fn unmount_at(mount_point: &Path, mode: UnmountMode, timeout: Duration) -> io::Result<()> {
+    if mode == UnmountMode::Normal {
+        match control::send(mount_point, &Request::Unmount, timeout) {
+            Ok(Response::Ok) => {
+                info!("Daemon flushed all data and unmounted");
+                return Ok(());
+            }
+            Ok(Response::Busy { open_handles }) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::ResourceBusy,
+                    format!(
+                        "mount is busy with {open_handles} open handle(s), close them or use \
+                         --lazy or --force"
+                    ),
+                ));
+            }
+            Ok(response) => return Err(control::unexpected(response)),
+            Err(err) if control::is_unreachable(&err) => warn!(
+                "No daemon is serving '{}', unmounting directly",
+                Redacted::path(mount_point)
+            ),
+            Err(err) => return Err(err),
+        }
+    } else {
+        match flush_daemon(mount_point, timeout) {
+            Err(err) if mode == UnmountMode::Force => {
+                warn!("Forcing unmount despite failed flush: {}", err);
+            }
+            result => result?,
+        }
+    }
 
     let is_root = unsafe { geteuid() == 0 };
-    if !is_root {
-        return Err(io::Error::new(
-            io::ErrorKind::PermissionDenied,
-            "must be root to unmount with umount2",
-        ));
+    if is_root {
+        unmount_root(mount_point, mode)
+    } else {
+        unmount_fusermount(mount_point, mode)
+    }
+}
+
+/// Asks the daemon serving `mount_point` to write back all dirty state before
+/// it is detached.
+fn flush_daemon(mount_point: &Path, timeout: Duration) -> io::Result<()> {
+    match control::send(mount_point, &Request::Flush, timeout) {
+        Ok(Response::Flushed { open_handles: 0 }) => {
+            info!("Daemon flushed all data");
+            Ok(())
+        }
+        Ok(Response::Flushed { open_handles }) => {
+            warn!(
+                "Daemon flushed all data, detaching with {} open handle(s)",
+                open_handles
+            );
+            Ok(())
+        }
+        Ok(response) => Err(control::unexpected(response)),
+        Err(err) if control::is_unreachable(&err) => {
+            warn!(
+                "No daemon is serving '{}', skipping flush",
+                Redacted::path(mount_point)
+            );
+            Ok(())
+        }
+        Err(err) => Err(err),
     }
+}
+
+fn unmount_root(mount_point: &Path, mode: UnmountMode) -> io::Result<()> {
+    let flags = match mode {
+        UnmountMode::Normal => 0,
+        UnmountMode::Lazy => MNT_DETACH,
+        UnmountMode::Force => MNT_FORCE,
+    };
 
-    let c_path = CString::new(mount_point.to_str().unwrap()).unwrap();
-    let res = unsafe { umount2(c_path.as_ptr(), MNT_FORCE) };
+    let c_path = CString::new(mount_point.as_os_str().as_bytes())?;
+    let res = unsafe { umount2(c_path.as_ptr(), flags) };
     if res != 0 {
         Err(io::Error::last_os_error())
     } else {
         Ok(())
     }
 }
+
+fn unmount_fusermount(mount_point: &Path, mode: UnmountMode) -> io::Result<()> {
+    let args = fusermount_args(mount_point, mode)?;
+
+    for program in FUSERMOUNT_PROGRAMS {
+        let output = match Command::new(program).args(&args).output() {
+            Ok(output) => output,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
+        };
+
+        if output.status.success() {
+            return Ok(());
+        }
+
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(io::Error::other(format!(
+            "{program} failed: {}",
+            stderr.trim()
+        )));
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        "fusermount3 was not found, install fuse3 or unmount as root",
+    ))
+}
+
+fn fusermount_args(mount_point: &Path, mode: UnmountMode) -> io::Result<Vec<OsString>> {
+    let mut args = vec![OsString::from("-u")];
+    match mode {
+        UnmountMode::Normal => {}
+        UnmountMode::Lazy => args.push(OsString::from("-z")),
+        UnmountMode::Force => {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "must be root to force an unmount",
+            ));
+        }
+    }
+    args.push(OsString::from("--"));
+    args.push(mount_point.as_os_str().to_os_string());
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::time::Instant;
+
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::filesystem::control::ControlServer;
+    use crate::filesystem::control::DaemonInfo;
+    use crate::filesystem::rekey::BackgroundRekey;
+    use crate::filesystem::shared::SharedFs;
+
+    fn active_mount(mount_point: &Path) -> ActiveMount {
+        ActiveMount {
+            mount_point: mount_point.to_path_buf(),
+            fs_name: "vylfs".to_string(),
+            read_only: false,
+            daemon: None,
+        }
+    }
+
+    #[test]
+    fn test_find_mount() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let parent = fs::canonicalize(temp_dir.path())?;
+        let mount_point = parent.join("vault");
+        let mounts = [active_mount(&mount_point)];
+
+        // The mount point is found without being accessed, as it does not even
+        // exist here.
+        assert_eq!(find_mount(&mount_point, &mounts)?, mount_point);
+        fs::create_dir(temp_dir.path().join("sub"))?;
+        assert_eq!(
+            find_mount(&temp_dir.path().join("sub/../vault"), &mounts)?,
+            mount_point
+        );
+
+        let result = find_mount(&parent.join("other"), &mounts);
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::NotFound);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unmount_locked_shared_fs() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        // The root of a locked vault cannot be accessed, like this mount point
+        // that does not exist.
+        let mount_point = fs::canonicalize(temp_dir.path())?.join("vault");
+        let fs = SharedFs::new(Default::default());
+        fs.lock().lock_vault()?;
+        assert!(fs.lock().is_locked());
+
+        let root_dir = temp_dir.path().join("root");
+        let (sender, receiver) = mpsc::channel();
+        let _server = ControlServer::start(
+            fs.clone(),
+            DaemonInfo {
+                root_dir: root_dir.clone(),
+                mount_point: mount_point.clone(),
+                started: Instant::now(),
+            },
+            move || {
+                let _ = sender.send(());
+                Ok(())
+            },
+            BackgroundRekey::new(fs, &root_dir),
+        )?;
+
+        let mount_point = find_mount(&mount_point, &[active_mount(&mount_point)])?;
+        unmount_at(&mount_point, UnmountMode::Normal, Duration::from_secs(5))?;
+        assert!(receiver.try_recv().is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fusermount_args() -> io::Result<()> {
+        let mount_point = Path::new("/mnt/-vault");
+
+        let args = fusermount_args(mount_point, UnmountMode::Normal)?;
+        assert_eq!(args, ["-u", "--", "/mnt/-vault"]);
+
+        let args = fusermount_args(mount_point, UnmountMode::Lazy)?;
+        assert_eq!(args, ["-u", "-z", "--", "/mnt/-vault"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fusermount_args_force_requires_root() {
+        let result = fusermount_args(Path::new("/mnt/vault"), UnmountMode::Force);
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+    }
+}
@@ -0,0 +1,282 @@
+use std::fmt;
+use std::fs;
+use std::fs::DirBuilder;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::Path;
+use std::path::PathBuf;
+
+use tracing::info;
+
+use crate::filesystem::VylFs;
+use crate::filesystem::metadata::ObjectName;
+use crate::filesystem::tree::TREE_OBJECT;
+use crate::vault::header::FORMAT_VERSION;
+use crate::vault::header::HEADER_FILE_NAME;
+use crate::vault::store::OBJECTS_DIR_NAME;
+use crate::vault::store::Store;
+
+/// Directory in the vault's root directory that `backup` copies metadata to.
+pub const UPGRADE_BACKUP_DIR: &str = "upgrade-backup";
+
+/// What upgrading a vault to the format of this build changes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Plan {
+    pub from: u32,
+    /// Objects written before objects were versioned, which are rewritten.
+    pub legacy_objects: usize,
+    /// Whether the vault lacks an authenticated tree, which is added.
+    pub missing_tree: bool,
+}
+
+impl Plan {
+    /// Inspects the objects in `store` of a vault in format version `from`.
+    pub fn new(store: &Store, from: u32) -> io::Result<Self> {
+        let mut legacy_objects = 0;
+        for name in store.names()? {
+            if ObjectName::parse(&name).is_none() && name != TREE_OBJECT {
+                continue;
+            }
+            if store
+                .header(&name)?
+                .is_some_and(|header| header.version == 0)
+            {
+                legacy_objects += 1;
+            }
+        }
+        Ok(Self {
+            from,
+            legacy_objects,
+            missing_tree: store.header(TREE_OBJECT)?.is_none(),
+        })
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "upgrade from format version {} to {FORMAT_VERSION}",
+            self.from
+        )?;
+        write!(f, "  rewrite {} unversioned object(s)", self.legacy_objects)?;
+        if self.missing_tree {
+            write!(f, "\n  add an authenticated tree")?;
+        }
+        Ok(())
+    }
+}
+
+/// Copies the header and every metadata object of the vault behind `store`
+/// to a new directory in `UPGRADE_BACKUP_DIR`, named after format version
+/// `from`, and returns its path.
+///
+/// The blocks of files are not copied. Upgrading only re-encrypts them under
+/// a version, and the inode objects from before the upgrade still read them.
+///
+/// The copy is only given its name once complete. An existing backup of the
+/// same format is kept as it is, as it was made by an upgrade that was
+/// interrupted, and the vault may already be partly migrated.
+pub fn backup(store: &Store, from: u32) -> io::Result<PathBuf> {
+    let root_dir = store.root_dir();
+    let backup_dir = root_dir
+        .join(UPGRADE_BACKUP_DIR)
+        .join(format!("format-{from}"));
+    if backup_dir.exists() {
+        info!("Resuming upgrade with the existing backup");
+        return Ok(backup_dir);
+    }
+    let partial_dir = backup_dir.with_extension("partial");
+    match fs::remove_dir_all(&partial_dir) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+    let objects_dir = partial_dir.join(OBJECTS_DIR_NAME);
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&objects_dir)?;
+
+    copy_synced(
+        &root_dir.join(HEADER_FILE_NAME),
+        &partial_dir.join(HEADER_FILE_NAME),
+    )?;
+    for name in store.names()? {
+        if matches!(ObjectName::parse(&name), Some(ObjectName::Inode(_))) || name == TREE_OBJECT {
+            copy_synced(
+                &root_dir.join(OBJECTS_DIR_NAME).join(&name),
+                &objects_dir.join(&name),
+            )?;
+        }
+    }
+    File::open(&objects_dir)?.sync_all()?;
+    File::open(&partial_dir)?.sync_all()?;
+    fs::rename(&partial_dir, &backup_dir)?;
+    File::open(root_dir.join(UPGRADE_BACKUP_DIR))?.sync_all()?;
+    Ok(backup_dir)
+}
+
+/// Returns whether the vault in `root_dir` still has a backup made by
+/// `backup`. Its header holds the key slots from before the upgrade.
+pub fn has_backup(root_dir: &Path) -> bool {
+    root_dir.join(UPGRADE_BACKUP_DIR).exists()
+}
+
+/// Removes the backup made by `backup` once the upgrade is finished, so that
+/// the key slots it copied no longer open the vault.
+pub fn remove_backup(root_dir: &Path) -> io::Result<()> {
+    fs::remove_dir_all(root_dir.join(UPGRADE_BACKUP_DIR))
+}
+
+/// Rewrites every object in `store` that has no version, then all inodes and
+/// the tree, and returns the vault's new epoch.
+pub fn migrate(store: Store) -> io::Result<u64> {
+    let mut fs = VylFs::load(store, false)?;
+    let mut legacy_blocks: Vec<(u64, u64)> = fs
+        .versions
+        .iter()
+        .filter_map(|(name, version)| match name {
+            ObjectName::Block(ino, index) if *version == 0 => Some((*ino, *index)),
+            _ => None,
+        })
+        .collect();
+    legacy_blocks.sort_unstable();
+    info!("Rewriting {} unversioned blocks", legacy_blocks.len());
+    for key in legacy_blocks {
+        fs.load_block(key.0, key.1)?;
+        // Marks the block as dirty, so that it is written with a version.
+        fs.blocks.get_mut(&key).expect("block was loaded");
+    }
+    fs.dirty_inodes = fs.inodes.keys().copied().collect();
+    fs.flush_all()?;
+    Ok(fs.epoch())
+}
+
+fn copy_synced(from: &Path, to: &Path) -> io::Result<()> {
+    fs::copy(from, to)?;
+    File::open(to)?.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use fuser::FUSE_ROOT_ID;
+    use fuser::FileType;
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::filesystem::blocks::BLOCK_SIZE;
+    use crate::filesystem::metadata::InodeRecord;
+    use crate::vault::Credential;
+    use crate::vault::Vault;
+    use crate::vault::tests::TEST_PARAMS;
+
+    /// Rewrites every object in `store` as a build before versioned objects
+    /// would have written it.
+    fn make_legacy(store: &Store) -> io::Result<()> {
+        store.remove(TREE_OBJECT)?;
+        for name in store.names()? {
+            let mut data = store.read(&name)?.expect("object exists").data;
+            if let Some(ObjectName::Inode(_)) = ObjectName::parse(&name) {
+                let mut record: InodeRecord = serde_json::from_slice(&data)?;
+                record.child_versions.clear();
+                record.block_versions.clear();
+                data = serde_json::to_vec(&record)?;
+            }
+            store.write_legacy(&name, &data)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_upgrade_rewrites_legacy_objects() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let credential = Credential::Passphrase("passphrase".to_string().into());
+        let vault = Vault::init(temp_dir.path(), &credential, "personal", &TEST_PARAMS)?;
+        let open = || Store::open(temp_dir.path(), vault.keyring().clone());
+        let data: Vec<u8> = (0..BLOCK_SIZE + 100).map(|i| i as u8).collect();
+
+        let mut fs = VylFs::load(open()?, false)?;
+        let dir = fs
+            .new_node(FUSE_ROOT_ID, "docs", FileType::Directory, 0o700)
+            .map_err(io::Error::from_raw_os_error)?;
+        let file = fs
+            .new_node(dir.ino, "notes", FileType::RegularFile, 0o600)
+            .map_err(io::Error::from_raw_os_error)?;
+        fs.write_data(file.ino, 0, &data)
+            .map_err(io::Error::from_raw_os_error)?;
+        fs.flush_all()?;
+        drop(fs);
+        let store = open()?;
+        make_legacy(&store)?;
+
+        let plan = Plan::new(&store, 1)?;
+        assert_eq!(
+            plan,
+            Plan {
+                from: 1,
+                legacy_objects: 5,
+                missing_tree: true,
+            }
+        );
+
+        let backup_dir = backup(&store, 1)?;
+        assert!(backup_dir.join(HEADER_FILE_NAME).exists());
+        let backed_up = fs::read_dir(backup_dir.join(OBJECTS_DIR_NAME))?.count();
+        assert_eq!(backed_up, 3);
+        // An interrupted upgrade resumes with the backup it made.
+        let copied = fs::read_dir(backup_dir.join(OBJECTS_DIR_NAME))?
+            .next()
+            .expect("objects were backed up")?;
+        fs::remove_file(copied.path())?;
+        assert_eq!(backup(&store, 1)?, backup_dir);
+        let backed_up = fs::read_dir(backup_dir.join(OBJECTS_DIR_NAME))?.count();
+        assert_eq!(backed_up, 2);
+
+        let epoch = migrate(store)?;
+        let store = open()?;
+        assert_eq!(
+            Plan::new(&store, FORMAT_VERSION)?,
+            Plan {
+                from: FORMAT_VERSION,
+                legacy_objects: 0,
+                missing_tree: false,
+            }
+        );
+        let mut fs = VylFs::load(store, false)?;
+        assert_eq!(fs.epoch(), epoch);
+        let read = fs
+            .read_data(file.ino, 0, data.len() as u32)
+            .map_err(io::Error::from_raw_os_error)?;
+        assert_eq!(*read, data);
+
+        assert!(has_backup(temp_dir.path()));
+        remove_backup(temp_dir.path())?;
+        assert!(!has_backup(temp_dir.path()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_backup_replaces_partial_copy() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let credential = Credential::Passphrase("passphrase".to_string().into());
+        let vault = Vault::init(temp_dir.path(), &credential, "personal", &TEST_PARAMS)?;
+        let store = Store::open(temp_dir.path(), vault.keyring().clone())?;
+        let partial_dir = temp_dir
+            .path()
+            .join(UPGRADE_BACKUP_DIR)
+            .join("format-1.partial");
+        fs::create_dir_all(&partial_dir)?;
+        fs::write(partial_dir.join(HEADER_FILE_NAME), b"trunc")?;
+
+        let backup_dir = backup(&store, 1)?;
+        assert!(!partial_dir.exists());
+        assert_eq!(
+            fs::read(backup_dir.join(HEADER_FILE_NAME))?,
+            fs::read(temp_dir.path().join(HEADER_FILE_NAME))?
+        );
+
+        Ok(())
+    }
+}
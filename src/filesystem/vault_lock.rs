@@ -0,0 +1,307 @@
+use std::ffi::CStr;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process;
+
+use libc::LOCK_EX;
+use libc::LOCK_NB;
+use libc::flock;
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::warn;
+
+use crate::log::Redacted;
+
+/// Name of the lock file inside the vault's root directory.
+pub const LOCK_FILE_NAME: &str = "vylfs.lock";
+
+/// Who holds a vault's lock, as written to its lock file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct LockHolder {
+    pid: u32,
+    host: String,
+    /// Where the vault is mounted, or `None` for a command like `rekey` that
+    /// works on the vault directly.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mount_point: Option<PathBuf>,
+}
+
+impl LockHolder {
+    fn describe(&self) -> String {
+        match &self.mount_point {
+            Some(mount_point) => {
+                format!("mounted at '{}' by pid {}", mount_point.display(), self.pid)
+            }
+            None => format!("in use by pid {}", self.pid),
+        }
+    }
+}
+
+/// Exclusive lock on a vault's root directory, held by its daemon for as long
+/// as the vault is mounted.
+///
+/// The lock is an advisory `flock` on the lock file, which the kernel releases
+/// when the daemon exits. The file also records the holder, so that a mount
+/// from another host or on a filesystem without `flock` support is detected as
+/// well. Dropping the lock clears the record.
+#[derive(Debug)]
+pub struct VaultLock {
+    file: File,
+}
+
+impl VaultLock {
+    /// Locks the vault at `root_dir` for a mount at `mount_point`, or for a
+    /// command that changes the vault while it is not mounted.
+    ///
+    /// Fails with `ResourceBusy` if another daemon holds the lock. A record
+    /// left behind by a daemon on this host that has since died is replaced
+    /// with a warning, while records that cannot be verified, such as those
+    /// written by another host, are only replaced if `break_lock` is set.
+    pub fn acquire(
+        root_dir: &Path,
+        mount_point: Option<&Path>,
+        break_lock: bool,
+    ) -> io::Result<Self> {
+        let path = root_dir.join(LOCK_FILE_NAME);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(&path)?;
+
+        let kernel_locked = match try_flock(&file) {
+            Ok(()) => true,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                let message = match read_holder(&mut file) {
+                    Some(holder) => format!("vault is already {}", holder.describe()),
+                    None => "vault is already locked by another process".to_string(),
+                };
+                return Err(io::Error::new(io::ErrorKind::ResourceBusy, message));
+            }
+            Err(err) if is_unsupported(&err) => {
+                warn!(
+                    "Filesystem of '{}' does not support locking, relying on the lock file only",
+                    Redacted::path(&path)
+                );
+                false
+            }
+            Err(err) => return Err(err),
+        };
+
+        let host = hostname()?;
+        if let Some(holder) = read_holder(&mut file) {
+            if break_lock {
+                warn!(
+                    "Breaking lock of pid {} on host '{}'",
+                    holder.pid, holder.host
+                );
+            } else if is_stale(&holder, &host, kernel_locked) {
+                warn!("Removing stale lock left by pid {}", holder.pid);
+            } else {
+                return Err(io::Error::new(
+                    io::ErrorKind::ResourceBusy,
+                    format!(
+                        "vault is {} on host '{}', use --break-lock if it is no longer in use",
+                        holder.describe(),
+                        holder.host
+                    ),
+                ));
+            }
+        }
+
+        let holder = LockHolder {
+            pid: process::id(),
+            host,
+            mount_point: mount_point.map(Path::to_path_buf),
+        };
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        serde_json::to_writer(&mut file, &holder)?;
+        file.write_all(b"\n")?;
+        file.sync_all()?;
+
+        Ok(Self { file })
+    }
+}
+
+impl Drop for VaultLock {
+    /// Clears the record before the kernel releases the lock. The file itself
+    /// is kept, as removing it would let two daemons lock different files.
+    fn drop(&mut self) {
+        let _ = self.file.set_len(0);
+    }
+}
+
+fn try_flock(file: &File) -> io::Result<()> {
+    let res = unsafe { flock(file.as_raw_fd(), LOCK_EX | LOCK_NB) };
+    if res != 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+fn is_unsupported(err: &io::Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(libc::ENOLCK | libc::EOPNOTSUPP | libc::ENOSYS)
+    )
+}
+
+/// Reads the holder recorded in the lock file, if any. An unreadable record is
+/// treated like an empty one, as it cannot name a holder to wait for.
+fn read_holder(file: &mut File) -> Option<LockHolder> {
+    let mut contents = String::new();
+    file.seek(SeekFrom::Start(0)).ok()?;
+    file.read_to_string(&mut contents).ok()?;
+    if contents.trim().is_empty() {
+        return None;
+    }
+    serde_json::from_str(&contents).ok()
+}
+
+/// Returns whether a recorded holder can no longer be holding the lock.
+///
+/// A daemon on this host holds the `flock` while it runs, so if we got it the
+/// record is stale. Without `flock` support, the recorded pid must be gone.
+/// Holders on other hosts cannot be checked.
+fn is_stale(holder: &LockHolder, host: &str, kernel_locked: bool) -> bool {
+    holder.host == host && (kernel_locked || !is_process_alive(holder.pid))
+}
+
+fn is_process_alive(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    let res = unsafe { libc::kill(pid, 0) };
+    res == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+fn hostname() -> io::Result<String> {
+    let mut buffer = [0u8; 256];
+    let res = unsafe { libc::gethostname(buffer.as_mut_ptr().cast(), buffer.len()) };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let name = CStr::from_bytes_until_nul(&buffer)
+        .map_err(|_| io::Error::other("hostname is not terminated"))?;
+    Ok(name.to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn write_holder(root_dir: &Path, holder: &LockHolder) -> io::Result<()> {
+        fs::write(root_dir.join(LOCK_FILE_NAME), serde_json::to_vec(holder)?)
+    }
+
+    #[test]
+    fn test_acquire_is_exclusive() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let lock = VaultLock::acquire(temp_dir.path(), Some(Path::new("/mnt/a")), false)?;
+
+        let result = VaultLock::acquire(temp_dir.path(), Some(Path::new("/mnt/b")), false);
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+        let err = result.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ResourceBusy);
+        assert!(err.to_string().contains("/mnt/a"), "got {err}");
+
+        // Commands that work on the vault directly are excluded as well.
+        let result = VaultLock::acquire(temp_dir.path(), None, false);
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+
+        // Breaking the lock does not take it from a running daemon.
+        let result = VaultLock::acquire(temp_dir.path(), Some(Path::new("/mnt/b")), true);
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+
+        drop(lock);
+        VaultLock::acquire(temp_dir.path(), Some(Path::new("/mnt/b")), false)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_drop_clears_holder() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let lock = VaultLock::acquire(temp_dir.path(), Some(Path::new("/mnt/a")), false)?;
+        let contents = fs::read_to_string(temp_dir.path().join(LOCK_FILE_NAME))?;
+        assert!(contents.contains(&process::id().to_string()));
+
+        drop(lock);
+        let contents = fs::read_to_string(temp_dir.path().join(LOCK_FILE_NAME))?;
+        assert!(
+            contents.is_empty(),
+            "Expected an empty lock file, got {contents}"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_acquire_replaces_stale_lock() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        write_holder(
+            temp_dir.path(),
+            &LockHolder {
+                pid: u32::MAX,
+                host: hostname()?,
+                mount_point: Some(PathBuf::from("/mnt/crashed")),
+            },
+        )?;
+
+        VaultLock::acquire(temp_dir.path(), Some(Path::new("/mnt/a")), false)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_acquire_foreign_lock_requires_break() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        write_holder(
+            temp_dir.path(),
+            &LockHolder {
+                pid: 1,
+                host: "other-host".to_string(),
+                mount_point: Some(PathBuf::from("/mnt/remote")),
+            },
+        )?;
+
+        let result = VaultLock::acquire(temp_dir.path(), Some(Path::new("/mnt/a")), false);
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::ResourceBusy);
+
+        VaultLock::acquire(temp_dir.path(), Some(Path::new("/mnt/a")), true)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_stale() {
+        let holder = LockHolder {
+            pid: process::id(),
+            host: "here".to_string(),
+            mount_point: Some(PathBuf::from("/mnt/a")),
+        };
+
+        assert!(is_stale(&holder, "here", true));
+        assert!(!is_stale(&holder, "here", false));
+        assert!(!is_stale(&holder, "elsewhere", true));
+    }
+}
@@ -1,22 +1,363 @@
+use std::ffi::OsStr;
+use std::fmt;
+use std::fmt::Write as _;
 use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::hash::BuildHasher;
+use std::hash::RandomState;
 use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::thread;
+use std::time::Duration;
 
-/// Prints the `vylfs` log file from `/tmp/vylfs.out` if it exists.
-pub fn view() -> io::Result<()> {
-    view_file(&PathBuf::from("/tmp/vylfs.out"), &mut io::stdout())
+use serde_json::Value;
+use tracing::Level;
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::Layer;
+use tracing_subscriber::Registry;
+use tracing_subscriber::fmt as subscriber_fmt;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::reload;
+use tracing_subscriber::util::SubscriberInitExt;
+
+use crate::paths::mount_id;
+use crate::paths::state_dir;
+
+/// How often `--follow` checks the log file for new lines.
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Process wide redaction settings, see `init_redaction`.
+static REDACTOR: OnceLock<Redactor> = OnceLock::new();
+
+/// Handle used to change the log level of a running daemon.
+static FILTER_HANDLE: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+/// Installs the global `tracing` subscriber writing to stdout.
+///
+/// `level` is one of `error`, `warn`, `info`, `debug` or `trace`, and `json`
+/// selects one JSON object per line instead of the compact text format.
+pub fn init_subscriber(level: &str, plaintext_names: bool, json: bool) {
+    init_redaction(plaintext_names);
+
+    let (filter, handle) = reload::Layer::new(log_filter(level));
+    let _ = FILTER_HANDLE.set(handle);
+
+    let format = if json {
+        subscriber_fmt::layer().json().boxed()
+    } else {
+        subscriber_fmt::layer()
+            .event_format(subscriber_fmt::format().without_time().compact())
+            .boxed()
+    };
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(format)
+        .init();
 }
 
-fn view_file<W: io::Write>(file_path: &Path, writer: &mut W) -> io::Result<()> {
-    match fs::read_to_string(file_path) {
-        Ok(content) => write!(writer, "{content}")?,
+/// Changes the level of the subscriber installed by `init_subscriber`.
+pub fn set_level(level: &str) -> io::Result<()> {
+    let level: Level = level
+        .parse()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, format!("{err}")))?;
+    let handle = FILTER_HANDLE
+        .get()
+        .ok_or_else(|| io::Error::other("logging is not initialized"))?;
+    handle
+        .reload(log_filter(&level.to_string().to_lowercase()))
+        .map_err(io::Error::other)
+}
+
+fn log_filter(level: &str) -> EnvFilter {
+    let plaintext = REDACTOR.get().is_some_and(|redactor| redactor.plaintext);
+    // fuser logs mount points and request names without going through
+    // `Redacted`.
+    if plaintext {
+        EnvFilter::new(level)
+    } else {
+        EnvFilter::new(format!("{level},fuser=warn"))
+    }
+}
+
+/// Replaces file names and paths with keyed hashes before they reach the log.
+///
+/// The key is random per process, so the same name always maps to the same
+/// hash within one daemon's log without revealing the name itself.
+#[derive(Debug)]
+struct Redactor {
+    key: RandomState,
+    plaintext: bool,
+}
+
+impl Redactor {
+    fn new(plaintext: bool) -> Self {
+        Self {
+            key: RandomState::new(),
+            plaintext,
+        }
+    }
+
+    fn write(&self, name: &OsStr, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.plaintext {
+            write!(f, "{}", name.to_string_lossy())
+        } else {
+            write!(f, "<{:016x}>", self.key.hash_one(name.as_bytes()))
+        }
+    }
+}
+
+/// Sets whether names and paths are logged in plaintext, must be called before
+/// anything is logged.
+fn init_redaction(plaintext: bool) {
+    let _ = REDACTOR.set(Redactor::new(plaintext));
+}
+
+/// A file name or path that is redacted when formatted into a log event.
+pub struct Redacted<'a>(pub &'a OsStr);
+
+impl<'a> Redacted<'a> {
+    pub fn path(path: &'a Path) -> Self {
+        Self(path.as_os_str())
+    }
+}
+
+impl fmt::Display for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        REDACTOR
+            .get_or_init(|| Redactor::new(false))
+            .write(self.0, f)
+    }
+}
+
+/// Returns the path of the log file for the given mount point.
+pub fn log_path(mount_point: &Path) -> io::Result<PathBuf> {
+    Ok(state_dir()?.join(format!("{}.log", mount_id(mount_point)?)))
+}
+
+/// Creates or truncates the log file for the given mount point, readable only
+/// by its owner.
+pub fn create_log_file(mount_point: &Path) -> io::Result<(File, PathBuf)> {
+    let path = log_path(mount_point)?;
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(&path)?;
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    Ok((file, path))
+}
+
+/// Options controlling how a log file is printed.
+#[derive(Debug, Default)]
+pub struct ViewOptions {
+    /// Only print the last `lines` matching lines before following.
+    pub lines: Option<usize>,
+    /// Only print lines at this level or more severe.
+    pub level: Option<Level>,
+    /// Keep printing new lines as the daemon writes them.
+    pub follow: bool,
+}
+
+/// Prints the log file of the given mount point, or of the most recent mount if
+/// none is given.
+pub fn view(mount_point: Option<&Path>, options: &ViewOptions) -> io::Result<()> {
+    let file_path = match mount_point {
+        Some(mount_point) => Some(log_path(mount_point)?),
+        None => latest_log(&state_dir()?)?,
+    };
+
+    let mut stdout = io::stdout();
+    match file_path {
+        Some(file_path) => {
+            let Some(offset) = view_file(&file_path, options, &mut stdout)? else {
+                return Ok(());
+            };
+            if options.follow {
+                follow_file(&file_path, offset, options, &mut stdout)?;
+            }
+            Ok(())
+        }
+        None => {
+            println!("no logs available");
+            Ok(())
+        }
+    }
+}
+
+/// Finds the most recently modified log file in `dir`.
+fn latest_log(dir: &Path) -> io::Result<Option<PathBuf>> {
+    let mut latest = None;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if path.extension().is_none_or(|ext| ext != "log") {
+            continue;
+        }
+
+        let modified = entry.metadata()?.modified()?;
+        if latest.as_ref().is_none_or(|(time, _)| modified > *time) {
+            latest = Some((modified, path));
+        }
+    }
+    Ok(latest.map(|(_, path)| path))
+}
+
+/// Prints the matching lines of a log file and returns the offset it was read
+/// up to, or `None` if the file does not exist.
+fn view_file<W: io::Write>(
+    file_path: &Path,
+    options: &ViewOptions,
+    writer: &mut W,
+) -> io::Result<Option<u64>> {
+    let content = match fs::read(file_path) {
+        Ok(content) => content,
         Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
             writeln!(writer, "no logs available")?;
+            return Ok(None);
         }
         Err(err) => return Err(err),
+    };
+
+    let offset = content.len() as u64;
+    let content = String::from_utf8_lossy(&content);
+    let lines: Vec<&str> = content
+        .split_inclusive('\n')
+        .filter(|line| matches_level(line, options.level))
+        .collect();
+    let skip = options
+        .lines
+        .map_or(0, |count| lines.len().saturating_sub(count));
+
+    for line in &lines[skip..] {
+        write_line(line, writer)?;
+    }
+
+    Ok(Some(offset))
+}
+
+/// Prints lines appended to a log file after `offset` until interrupted.
+fn follow_file<W: io::Write>(
+    file_path: &Path,
+    mut offset: u64,
+    options: &ViewOptions,
+    writer: &mut W,
+) -> io::Result<()> {
+    let mut file = File::open(file_path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut pending = Vec::new();
+
+    loop {
+        let read = file.read_to_end(&mut pending)?;
+        offset += read as u64;
+
+        if read == 0 {
+            if fs::metadata(file_path)?.len() < offset {
+                file = File::open(file_path)?;
+                offset = 0;
+                pending.clear();
+                continue;
+            }
+            writer.flush()?;
+            thread::sleep(FOLLOW_INTERVAL);
+            continue;
+        }
+
+        while let Some(end) = pending.iter().position(|&byte| byte == b'\n') {
+            let line: Vec<u8> = pending.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            if matches_level(&line, options.level) {
+                write_line(&line, writer)?;
+            }
+        }
+    }
+}
+
+/// Writes a log line, rendering JSON lines in the same layout as the text
+/// format.
+fn write_line<W: io::Write>(line: &str, writer: &mut W) -> io::Result<()> {
+    match render_json(line) {
+        Some(rendered) => writeln!(writer, "{rendered}"),
+        None => write!(writer, "{line}"),
+    }
+}
+
+/// Returns whether a line should be printed for the given level filter.
+///
+/// Lines without a recognizable level, such as panic messages, are always kept.
+fn matches_level(line: &str, filter: Option<Level>) -> bool {
+    match (filter, line_level(line)) {
+        (Some(filter), Some(level)) => level <= filter,
+        _ => true,
+    }
+}
+
+/// Extracts the level of a log line written in either the JSON or text format.
+fn line_level(line: &str) -> Option<Level> {
+    if let Ok(Value::Object(event)) = serde_json::from_str::<Value>(line) {
+        return event.get("level")?.as_str()?.parse().ok();
+    }
+
+    strip_ansi(line).split_whitespace().next()?.parse().ok()
+}
+
+/// Renders a JSON log line as `LEVEL target: message key=value`.
+fn render_json(line: &str) -> Option<String> {
+    let Ok(Value::Object(event)) = serde_json::from_str::<Value>(line) else {
+        return None;
+    };
+
+    let level = event.get("level")?.as_str()?;
+    let target = event.get("target").and_then(Value::as_str).unwrap_or("");
+    let mut rendered = String::new();
+    if let Some(timestamp) = event.get("timestamp").and_then(Value::as_str) {
+        rendered.push_str(timestamp);
+        rendered.push(' ');
     }
-    Ok(())
+    let _ = write!(rendered, "{level:>5} {target}:");
+
+    if let Some(Value::Object(fields)) = event.get("fields") {
+        if let Some(message) = fields.get("message").and_then(Value::as_str) {
+            rendered.push(' ');
+            rendered.push_str(message);
+        }
+        for (key, value) in fields.iter().filter(|(key, _)| *key != "message") {
+            let value = value
+                .as_str()
+                .map_or_else(|| value.to_string(), str::to_string);
+            let _ = write!(rendered, " {key}={value}");
+        }
+    }
+
+    Some(rendered)
+}
+
+/// Removes ANSI color escape sequences written by the text formatter.
+fn strip_ansi(line: &str) -> String {
+    let mut stripped = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c == '\u{1b}' {
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            stripped.push(c);
+        }
+    }
+    stripped
 }
 
 #[cfg(test)]
@@ -24,6 +365,8 @@ mod tests {
     use std::fs;
     use std::io::Cursor;
     use std::io::{self};
+    use std::time::Duration;
+    use std::time::SystemTime;
 
     use tempfile::tempdir;
 
@@ -37,8 +380,8 @@ mod tests {
         fs::write(&log_file_path, expected_contents)?;
 
         let mut buffer = Cursor::new(Vec::new());
-        let result = view_file(&log_file_path, &mut buffer);
-        assert!(result.is_ok(), "Expected Ok(()), but got {:?}", result);
+        let result = view_file(&log_file_path, &ViewOptions::default(), &mut buffer);
+        assert!(result.is_ok(), "Expected Ok(_), but got {:?}", result);
 
         let output = String::from_utf8(buffer.into_inner()).unwrap();
         assert_eq!(output, expected_contents);
@@ -52,8 +395,8 @@ mod tests {
         let non_existent_log_path = temp_dir.path().join("non_existent_log.out");
 
         let mut buffer = Cursor::new(Vec::new());
-        let result = view_file(&non_existent_log_path, &mut buffer);
-        assert!(result.is_ok(), "Expected Ok(()), but got {:?}", result);
+        let result = view_file(&non_existent_log_path, &ViewOptions::default(), &mut buffer);
+        assert!(result.is_ok(), "Expected Ok(_), but got {:?}", result);
 
         let output = String::from_utf8(buffer.into_inner()).unwrap();
         assert_eq!(output.trim(), "no logs available");
@@ -69,7 +412,7 @@ mod tests {
         fs::create_dir(&denied_path)?;
 
         let mut buffer = Cursor::new(Vec::new());
-        let result = view_file(&denied_path, &mut buffer);
+        let result = view_file(&denied_path, &ViewOptions::default(), &mut buffer);
         assert!(result.is_err(), "Expected an error, but got Ok(())");
 
         let err = result.unwrap_err();
@@ -82,4 +425,154 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_latest_log_picks_newest() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let old_log = temp_dir.path().join("mnt-old.log");
+        let new_log = temp_dir.path().join("mnt-new.log");
+        fs::write(&old_log, "old")?;
+        fs::write(&new_log, "new")?;
+        fs::write(temp_dir.path().join("mnt-other.json"), "{}")?;
+
+        let past = SystemTime::now() - Duration::from_secs(60);
+        File::options()
+            .write(true)
+            .open(&old_log)?
+            .set_modified(past)?;
+
+        let latest = latest_log(temp_dir.path())?;
+        assert_eq!(latest, Some(new_log));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_latest_log_empty_dir() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+
+        let latest = latest_log(temp_dir.path())?;
+        assert_eq!(latest, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_view_log_level_and_lines() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let log_file_path = temp_dir.path().join("mnt.log");
+        let contents = concat!(
+            " INFO vylfs: first\n",
+            " WARN vylfs: second\n",
+            "ERROR vylfs: third\n",
+            "DEBUG vylfs: fourth\n",
+            "thread 'main' panicked\n",
+        );
+        fs::write(&log_file_path, contents)?;
+
+        let options = ViewOptions {
+            lines: Some(2),
+            level: Some(Level::WARN),
+            follow: false,
+        };
+        let mut buffer = Cursor::new(Vec::new());
+        let offset = view_file(&log_file_path, &options, &mut buffer)?;
+        assert_eq!(offset, Some(contents.len() as u64));
+
+        let output = String::from_utf8(buffer.into_inner()).unwrap();
+        assert_eq!(output, "ERROR vylfs: third\nthread 'main' panicked\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_view_log_renders_json() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let log_file_path = temp_dir.path().join("mnt.log");
+        let contents = concat!(
+            r#"{"timestamp":"2025-01-01T00:00:00Z","level":"INFO","fields":"#,
+            r#"{"message":"Filesystem initialized"},"target":"vylfs::filesystem"}"#,
+            "\n",
+            r#"{"timestamp":"2025-01-01T00:00:01Z","level":"DEBUG","fields":"#,
+            r#"{"message":"lookup","ino":1},"target":"vylfs::filesystem"}"#,
+            "\n",
+        );
+        fs::write(&log_file_path, contents)?;
+
+        let options = ViewOptions {
+            level: Some(Level::INFO),
+            ..Default::default()
+        };
+        let mut buffer = Cursor::new(Vec::new());
+        view_file(&log_file_path, &options, &mut buffer)?;
+
+        let output = String::from_utf8(buffer.into_inner()).unwrap();
+        assert_eq!(
+            output,
+            "2025-01-01T00:00:00Z  INFO vylfs::filesystem: Filesystem initialized\n"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_line_level() {
+        assert_eq!(
+            line_level("\u{1b}[32m INFO\u{1b}[0m vylfs: hi"),
+            Some(Level::INFO)
+        );
+        assert_eq!(
+            line_level(r#"{"level":"WARN","fields":{}}"#),
+            Some(Level::WARN)
+        );
+        assert_eq!(line_level("no level here"), None);
+        assert_eq!(line_level(""), None);
+    }
+
+    struct Formatted<'a>(&'a Redactor, &'a str);
+
+    impl fmt::Display for Formatted<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            self.0.write(OsStr::new(self.1), f)
+        }
+    }
+
+    #[test]
+    fn test_redactor_hides_names() {
+        let redactor = Redactor::new(false);
+
+        let redacted = Formatted(&redactor, "secret-plans.txt").to_string();
+        assert!(
+            !redacted.contains("secret"),
+            "name leaked into '{redacted}'"
+        );
+        assert!(redacted.starts_with('<') && redacted.ends_with('>'));
+        assert_eq!(redacted.len(), 18);
+    }
+
+    #[test]
+    fn test_redactor_hashes_are_correlatable() {
+        let redactor = Redactor::new(false);
+
+        let first = Formatted(&redactor, "notes.txt").to_string();
+        let second = Formatted(&redactor, "notes.txt").to_string();
+        let other = Formatted(&redactor, "notes.txt.swp").to_string();
+        assert_eq!(first, second);
+        assert_ne!(first, other);
+    }
+
+    #[test]
+    fn test_redactor_keys_differ() {
+        let first = Formatted(&Redactor::new(false), "notes.txt").to_string();
+        let second = Formatted(&Redactor::new(false), "notes.txt").to_string();
+        assert_ne!(first, second, "hashes should be keyed per process");
+    }
+
+    #[test]
+    fn test_redactor_plaintext() {
+        let redactor = Redactor::new(true);
+
+        let formatted = Formatted(&redactor, "/home/me/vault").to_string();
+        assert_eq!(formatted, "/home/me/vault");
+    }
 }
@@ -1,20 +1,72 @@
 mod filesystem;
 mod log;
+mod paths;
+mod vault;
 
 use std::env;
+use std::fs;
+use std::io;
+use std::os::fd::RawFd;
+use std::path::Path;
 use std::path::PathBuf;
 use std::process;
+use std::thread;
+use std::time::Duration;
 
 use clap::Arg;
 use clap::ArgAction;
+use clap::ArgMatches;
 use clap::Command;
+use clap::builder::PossibleValuesParser;
+use clap::builder::TypedValueParser;
 use clap::value_parser;
+use filesystem::control;
+use filesystem::control::Request;
+use filesystem::control::Response;
+use filesystem::directory::validate_dir;
+use filesystem::fsck;
+use filesystem::fsck::Problem;
+use filesystem::mount::MountConfig;
 use filesystem::mount::mount;
+use filesystem::mounts::active_mounts;
+use filesystem::options::parse_mount_option;
+use filesystem::unmount::UnmountMode;
 use filesystem::unmount::unmount;
+use filesystem::upgrade;
+use filesystem::vault_lock::VaultLock;
+use fuser::MountOption;
+use log::Redacted;
+use log::ViewOptions;
+use tracing::Level;
 use tracing::error;
 use tracing::info;
-use tracing_subscriber::EnvFilter;
-use tracing_subscriber::fmt;
+use vault::Credential;
+use vault::RECOVERY_SLOT_LABEL;
+use vault::Vault;
+use vault::crypto::Cipher;
+use vault::crypto::KdfParams;
+use vault::epoch::SeenEpoch;
+use vault::header::FORMAT_VERSION;
+use vault::header::SlotKind;
+use vault::passphrase;
+use vault::passphrase::CredentialSource;
+use vault::passphrase::PassphraseInput;
+use vault::recipient::Identity;
+use vault::recipient::Recipient;
+use vault::recovery::RecoveryKey;
+use vault::recovery::RecoveryShare;
+use vault::rekey;
+use vault::store::Store;
+
+/// How long control subcommands wait for the daemon to respond.
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often `rekey` asks a daemon how far its rekey got.
+const REKEY_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The most minutes an idle period can be given in, so that it converts to
+/// seconds without overflowing.
+const MAX_IDLE_MINUTES: u64 = u64::MAX / 60;
 
 fn main() {
     let mut command = build_command();
@@ -26,29 +78,117 @@ fn main() {
 
     let matches = command.get_matches();
 
-    let verbose = matches.get_flag("verbose");
-    let log_level = if verbose { "info" } else { "warn" };
-    tracing_subscriber::fmt()
-        .with_env_filter(EnvFilter::new(log_level))
-        .event_format(fmt::format().without_time().compact())
-        .init();
+    let log_level = match matches.get_count("verbose") {
+        0 => "warn",
+        1 => "info",
+        _ => "debug",
+    };
+    log::init_subscriber(
+        log_level,
+        matches.get_flag("log_plaintext_names"),
+        matches
+            .get_one::<String>("log_format")
+            .is_some_and(|format| format == "json"),
+    );
 
-    if let Some(("log", _)) = matches.subcommand() {
-        if let Err(err) = log::view() {
+    passphrase::set_input(passphrase_input(&matches));
+
+    if let Some(("log", sub_matches)) = matches.subcommand() {
+        let mount_point = sub_matches.get_one::<PathBuf>("mount_point");
+        let options = ViewOptions {
+            lines: sub_matches.get_one::<usize>("lines").copied(),
+            level: sub_matches.get_one::<Level>("level").copied(),
+            follow: sub_matches.get_flag("follow"),
+        };
+        if let Err(err) = log::view(mount_point.map(PathBuf::as_path), &options) {
             error!("Failed to view log: {}", err);
             process::exit(1);
         }
         return;
     }
 
+    if let Some(("status", sub_matches)) = matches.subcommand() {
+        let mount_point = sub_matches.get_one::<PathBuf>("mount_point");
+        let json = sub_matches.get_flag("json");
+        if let Err(err) = run_status(mount_point.map(PathBuf::as_path), json) {
+            error!("Failed to get status: {}", err);
+            process::exit(1);
+        }
+        return;
+    }
+
+    if let Some((
+        name @ ("init" | "passwd" | "recover" | "rekey" | "fsck" | "upgrade" | "key" | "recipient"),
+        sub_matches,
+    )) = matches.subcommand()
+    {
+        let (action, result) = match (name, sub_matches.subcommand()) {
+            ("init", _) => ("initialize vault", run_init(sub_matches)),
+            ("passwd", _) => ("change passphrase", run_passwd(sub_matches)),
+            ("recover", _) => ("recover vault", run_recover(sub_matches)),
+            ("rekey", _) => ("rekey vault", run_rekey(sub_matches)),
+            ("fsck", _) => ("check vault", run_fsck(sub_matches)),
+            ("upgrade", _) => ("upgrade vault", run_upgrade(sub_matches)),
+            ("recipient", Some(("add", recipient_matches))) => {
+                ("add recipient", run_recipient_add(recipient_matches))
+            }
+            (_, Some(("add", key_matches))) => ("add key slot", run_key_add(key_matches)),
+            (_, Some(("remove", key_matches))) => ("remove key slot", run_key_remove(key_matches)),
+            (_, Some(("list", key_matches))) => ("list key slots", run_key_list(key_matches)),
+            ("recipient", Some(("keygen", keygen_matches))) => {
+                ("generate identity", run_recipient_keygen(keygen_matches))
+            }
+            _ => unreachable!("unknown subcommand '{name}'"),
+        };
+        if let Err(err) = result {
+            error!("Failed to {}: {}", action, err);
+            process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(("unlock", sub_matches)) = matches.subcommand() {
+        let mount_point = sub_matches.get_one::<PathBuf>("mount_point").unwrap();
+        if let Err(err) = run_unlock(mount_point, sub_matches) {
+            error!("Failed to unlock: {}", err);
+            process::exit(1);
+        }
+        return;
+    }
+
+    if let Some((name, sub_matches)) = matches.subcommand() {
+        let mount_point = sub_matches.get_one::<PathBuf>("mount_point").unwrap();
+        let request = match name {
+            "flush" => Request::Flush,
+            "lock" => Request::Lock,
+            "reload" => Request::Reload {
+                log_level: sub_matches.get_one::<String>("level").cloned(),
+            },
+            _ => unreachable!("unknown subcommand '{name}'"),
+        };
+        if let Err(err) = run_control(mount_point, &request) {
+            error!("Failed to {}: {}", name, err);
+            process::exit(1);
+        }
+        return;
+    }
+
     match (
         matches.get_one::<PathBuf>("unmount"),
         matches.get_one::<PathBuf>("root_dir"),
         matches.get_one::<PathBuf>("mount_point"),
     ) {
         (Some(unmount_dir), _, _) => {
-            info!("Unmounting directory '{}'...", unmount_dir.display());
-            if let Err(err) = unmount(unmount_dir) {
+            info!("Unmounting directory '{}'...", Redacted::path(unmount_dir));
+            let mode = if matches.get_flag("force") {
+                UnmountMode::Force
+            } else if matches.get_flag("lazy") {
+                UnmountMode::Lazy
+            } else {
+                UnmountMode::Normal
+            };
+            let timeout = Duration::from_secs(*matches.get_one::<u64>("timeout").unwrap());
+            if let Err(err) = unmount(unmount_dir, mode, timeout) {
                 error!("Failed to unmount: {}", err);
                 process::exit(1);
             }
@@ -56,10 +196,26 @@ fn main() {
         (None, Some(root_dir), Some(mount_point)) => {
             info!(
                 "Mounting encrypted storage from '{}' to '{}'...",
-                root_dir.display(),
-                mount_point.display()
+                Redacted::path(root_dir),
+                Redacted::path(mount_point)
             );
-            if let Err(err) = mount(root_dir, mount_point) {
+            let config = MountConfig {
+                options: matches
+                    .get_many::<MountOption>("options")
+                    .map(|options| options.cloned().collect())
+                    .unwrap_or_default(),
+                foreground: matches.get_flag("foreground"),
+                break_lock: matches.get_flag("break_lock"),
+                accept_rollback: matches.get_flag("accept_rollback"),
+                credential: credential_source(&matches),
+                idle_lock: matches
+                    .get_one::<u64>("idle_lock")
+                    .map(|minutes| Duration::from_secs(minutes * 60)),
+                idle_timeout: matches
+                    .get_one::<u64>("idle_timeout")
+                    .map(|minutes| Duration::from_secs(minutes * 60)),
+            };
+            if let Err(err) = mount(root_dir, mount_point, &config) {
                 error!("Failed to mount: {}", err);
                 process::exit(1);
             }
@@ -71,11 +227,850 @@ fn main() {
     }
 }
 
+/// Creates a new vault with a key slot that opens with a keyfile if one is
+/// given and with a new passphrase otherwise, and by default a recovery key.
+fn run_init(matches: &ArgMatches) -> io::Result<()> {
+    let root_dir = matches.get_one::<PathBuf>("root_dir").unwrap();
+    let label = matches.get_one::<String>("label").unwrap();
+    validate_dir(root_dir)?;
+    let recovery = !matches.get_flag("no_recovery_key");
+    if recovery && label == RECOVERY_SLOT_LABEL {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "the label '{RECOVERY_SLOT_LABEL}' is reserved for the recovery key, choose \
+                 another one or pass --no-recovery-key"
+            ),
+        ));
+    }
+    let credential = new_credential(matches.get_one::<PathBuf>("keyfile"))?;
+    let params = KdfParams::default();
+    let cipher = *matches.get_one::<Cipher>("cipher").unwrap();
+    let mut vault = Vault::init_with_cipher(root_dir, &credential, label, &params, cipher)?;
+    println!(
+        "initialized vault in '{}', encrypted with {}",
+        root_dir.display(),
+        vault.cipher()
+    );
+
+    if recovery {
+        let recovery_key = RecoveryKey::generate()?;
+        let split = matches.get_one::<(u8, u8)>("split");
+        let printed: Vec<String> = match split {
+            Some((threshold, count)) => recovery_key
+                .split(*threshold, *count)?
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+            None => vec![recovery_key.to_string()],
+        };
+        vault.add_slot(
+            RECOVERY_SLOT_LABEL,
+            &Credential::Recovery(recovery_key),
+            &params,
+        )?;
+        match split {
+            Some((threshold, _)) => print_recovery_shares(&printed, *threshold),
+            None => print_recovery_key(&printed[0]),
+        }
+    }
+    Ok(())
+}
+
+/// Recovers the key from the shares printed at `init --split` and adds a key
+/// slot for a new passphrase or keyfile with it.
+fn run_recover(matches: &ArgMatches) -> io::Result<()> {
+    let root_dir = matches.get_one::<PathBuf>("root_dir").unwrap();
+    let label = matches.get_one::<String>("label").unwrap();
+    validate_dir(root_dir)?;
+
+    let mut shares: Vec<RecoveryShare> = Vec::new();
+    loop {
+        let message = match shares.first() {
+            Some(first) => format!(
+                "Recovery share {} of {}: ",
+                shares.len() + 1,
+                first.threshold()
+            ),
+            None => "Recovery share: ".to_string(),
+        };
+        let share: RecoveryShare = passphrase::prompt(&message)?.parse()?;
+        if shares.iter().any(|other| other.index() == share.index()) {
+            eprintln!("Share {} was already given", share.index());
+            continue;
+        }
+        shares.push(share);
+        if shares.len() >= shares[0].threshold() as usize {
+            break;
+        }
+    }
+
+    let recovery_key = RecoveryKey::combine(&shares)?;
+    let mut vault = Vault::unlock(root_dir, &Credential::Recovery(recovery_key))?;
+    let credential = new_credential(matches.get_one::<PathBuf>("new_keyfile"))?;
+    let index = vault.add_slot(label, &credential, &KdfParams::default())?;
+    println!("recovered '{}', added key slot {index}", root_dir.display());
+    Ok(())
+}
+
+/// Changes the passphrase of the slot that opens with the current one.
+fn run_passwd(matches: &ArgMatches) -> io::Result<()> {
+    let root_dir = matches.get_one::<PathBuf>("root_dir").unwrap();
+    validate_dir(root_dir)?;
+    let current = passphrase::prompt("Current passphrase: ")?;
+    let mut vault = Vault::unlock(root_dir, &Credential::Passphrase(current))?;
+    let new_passphrase = passphrase::prompt_new("New passphrase: ")?;
+    vault.change_passphrase(&new_passphrase, &KdfParams::default())?;
+    println!("changed passphrase of '{}'", root_dir.display());
+    warn_about_upgrade_backup(root_dir);
+    Ok(())
+}
+
+/// Warns that the backup of an interrupted upgrade still holds the key slots
+/// from before it, which a changed or removed slot does not affect.
+fn warn_about_upgrade_backup(root_dir: &Path) {
+    if upgrade::has_backup(root_dir) {
+        eprintln!(
+            "warning: '{}' still holds the old key slots, which keep opening the vault, finish \
+             the upgrade with 'vylfs upgrade' to remove it",
+            root_dir.join(upgrade::UPGRADE_BACKUP_DIR).display()
+        );
+    }
+}
+
+/// Moves a vault to a new master key. A mounted vault is re-encrypted by its
+/// daemon in the background, otherwise the objects are re-encrypted here.
+fn run_rekey(matches: &ArgMatches) -> io::Result<()> {
+    let mut vault = unlock_vault(matches)?;
+    let root_dir = fs::canonicalize(vault.root_dir())?;
+    let mount_point = active_mounts()?
+        .into_iter()
+        .find(|mount| {
+            mount
+                .daemon
+                .as_ref()
+                .is_some_and(|daemon| daemon.root_dir == root_dir)
+        })
+        .map(|mount| mount.mount_point);
+
+    let vault_lock = match &mount_point {
+        Some(mount_point) => match control::send(mount_point, &Request::Status, CONTROL_TIMEOUT)? {
+            Response::Status(status) if status.read_only => {
+                return Err(io::Error::new(
+                    io::ErrorKind::ReadOnlyFilesystem,
+                    format!("vault is mounted read-only at '{}'", mount_point.display()),
+                ));
+            }
+            Response::Status(_) => None,
+            response => return Err(control::unexpected(response)),
+        },
+        None => Some(VaultLock::acquire(&root_dir, None, false)?),
+    };
+
+    for slot in vault.begin_rekey(matches.get_flag("drop_slots"))? {
+        println!(
+            "removed key slot {} ({}), it only opened the old key, add it again with 'vylfs key \
+             add'",
+            slot.index, slot.label
+        );
+        if slot.kind == SlotKind::Recovery {
+            println!(
+                "create a new recovery key with 'vylfs key add --label recovery \
+                 --new-recovery-key'"
+            );
+        }
+    }
+    let keyring = vault.keyring();
+    let generation = keyring.current_generation();
+
+    if let Some(mount_point) = mount_point {
+        let request = Request::Rekey {
+            generation,
+            key: keyring.current().clone(),
+        };
+        run_control(&mount_point, &request)?;
+        println!(
+            "re-encrypting in the daemon serving '{}'",
+            mount_point.display()
+        );
+        wait_for_rekey(&mount_point)?;
+        vault.finish_rekey()?;
+        println!(
+            "re-encrypted '{}' under key generation {generation}",
+            root_dir.display()
+        );
+        warn_about_upgrade_backup(&root_dir);
+        return Ok(());
+    }
+
+    let store = Store::open(&root_dir, keyring.clone())?;
+    rekey::run(
+        &root_dir,
+        store.names()?,
+        |name| store.reencrypt(name).map(drop),
+        |progress| {
+            eprintln!(
+                "re-encrypted {}/{} objects",
+                progress.objects_done, progress.objects_total
+            )
+        },
+    )?;
+    vault.finish_rekey()?;
+    drop(vault_lock);
+    println!(
+        "re-encrypted '{}' under key generation {generation}",
+        root_dir.display()
+    );
+    warn_about_upgrade_backup(&root_dir);
+    Ok(())
+}
+
+/// Checks an unmounted vault and, with `--repair`, fixes what was found.
+/// Objects or a whole vault that were rolled back are only repaired with
+/// `--accept-rollback`, as repairing makes their old contents current.
+fn run_fsck(matches: &ArgMatches) -> io::Result<()> {
+    let vault = unlock_vault(matches)?;
+    let repair = matches.get_flag("repair");
+    let accept_rollback = matches.get_flag("accept_rollback");
+    let _vault_lock = VaultLock::acquire(vault.root_dir(), None, false)?;
+    let open = || Store::open(vault.root_dir(), vault.keyring().clone());
+    let seen_epoch = SeenEpoch::open(&vault.id())?;
+
+    let mut report = fsck::check(open()?, false)?;
+    if repair && !report.problems.is_empty() {
+        let rolled_back = report
+            .problems
+            .iter()
+            .filter(|problem| matches!(problem, Problem::RolledBack { .. }))
+            .count();
+        let vault_rolled_back = seen_epoch.check(report.epoch).is_err();
+        if (rolled_back > 0 || vault_rolled_back) && !accept_rollback {
+            println!("{report}");
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "the vault or {rolled_back} of its object(s) were rolled back, run with \
+                     --accept-rollback to repair it anyway"
+                ),
+            ));
+        }
+        report = fsck::check(open()?, true)?;
+    }
+    println!("{report}");
+
+    match seen_epoch.check(report.epoch) {
+        Err(err) if !(report.repaired && accept_rollback) => println!("note: {err}"),
+        _ if report.repaired => seen_epoch.record(report.epoch)?,
+        _ => {}
+    }
+    if !report.repaired && !report.problems.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "found {} problem(s), run with --repair to fix them",
+                report.problems.len()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Migrates a vault made by an older build to the format of this one, after
+/// backing up its metadata.
+fn run_upgrade(matches: &ArgMatches) -> io::Result<()> {
+    let mut vault = unlock_vault(matches)?;
+    let root_dir = vault.root_dir().to_path_buf();
+    let _vault_lock = VaultLock::acquire(&root_dir, None, false)?;
+    if vault.check_format().is_ok() {
+        println!(
+            "'{}' is already in format version {FORMAT_VERSION}",
+            root_dir.display()
+        );
+        // Left behind if the last upgrade was interrupted once finished.
+        if upgrade::has_backup(&root_dir) {
+            upgrade::remove_backup(&root_dir)?;
+            println!("removed the backup of the upgrade");
+        }
+        return Ok(());
+    }
+
+    let store = Store::open(&root_dir, vault.keyring().clone())?;
+    let plan = upgrade::Plan::new(&store, vault.format_version())?;
+    println!("{plan}");
+    if matches.get_flag("dry_run") {
+        println!("dry run, nothing was changed");
+        return Ok(());
+    }
+
+    let backup_dir = upgrade::backup(&store, plan.from)?;
+    println!("backed up metadata to '{}'", backup_dir.display());
+    let epoch = upgrade::migrate(store)?;
+    vault.finish_upgrade()?;
+    SeenEpoch::open(&vault.id())?.record(epoch)?;
+    upgrade::remove_backup(&root_dir)?;
+    println!(
+        "upgraded '{}' to format version {FORMAT_VERSION}, removed the backup",
+        root_dir.display()
+    );
+    Ok(())
+}
+
+/// Waits for the daemon serving `mount_point` to re-encrypt every object.
+/// If the wait is interrupted, the next mount or rekey finishes the rekey.
+fn wait_for_rekey(mount_point: &Path) -> io::Result<()> {
+    loop {
+        let status = match control::send(mount_point, &Request::Status, CONTROL_TIMEOUT)? {
+            Response::Status(status) => status,
+            response => return Err(control::unexpected(response)),
+        };
+        match status.rekey {
+            None => return Ok(()),
+            Some(_) if !status.rekey_running => {
+                return Err(io::Error::other(format!(
+                    "the rekey stopped, see 'vylfs log {}', it resumes at the next mount",
+                    mount_point.display()
+                )));
+            }
+            Some(progress) => eprintln!(
+                "re-encrypted {}/{} objects",
+                progress.objects_done, progress.objects_total
+            ),
+        }
+        thread::sleep(REKEY_POLL_INTERVAL);
+    }
+}
+
+fn run_key_add(matches: &ArgMatches) -> io::Result<()> {
+    let mut vault = unlock_vault(matches)?;
+    let label = matches.get_one::<String>("label").unwrap();
+    let params = KdfParams::default();
+
+    if matches.get_flag("new_recovery_key") {
+        let recovery_key = RecoveryKey::generate()?;
+        let printed = recovery_key.to_string();
+        let index = vault.add_slot(label, &Credential::Recovery(recovery_key), &params)?;
+        println!("added key slot {index}");
+        print_recovery_key(&printed);
+        return Ok(());
+    }
+
+    let credential = new_credential(matches.get_one::<PathBuf>("new_keyfile"))?;
+    let index = vault.add_slot(label, &credential, &params)?;
+    println!("added key slot {index}");
+    Ok(())
+}
+
+fn run_key_remove(matches: &ArgMatches) -> io::Result<()> {
+    let mut vault = unlock_vault(matches)?;
+    let slot = vault.remove_slot(matches.get_one::<String>("slot").unwrap())?;
+    println!("removed key slot {} ({})", slot.index, slot.label);
+    warn_about_upgrade_backup(vault.root_dir());
+    Ok(())
+}
+
+fn run_key_list(matches: &ArgMatches) -> io::Result<()> {
+    let root_dir = matches.get_one::<PathBuf>("root_dir").unwrap();
+    println!("{:<6} {:<13} LABEL", "INDEX", "KIND");
+    for slot in Vault::slots(root_dir)? {
+        let recipient = match slot.key.recipient {
+            Some(recipient) if recipient.to_string() != slot.label => format!(" ({recipient})"),
+            _ => String::new(),
+        };
+        println!(
+            "{:<6} {:<13} {}{recipient}",
+            slot.index,
+            slot.kind.to_string(),
+            slot.label
+        );
+    }
+    Ok(())
+}
+
+fn run_recipient_add(matches: &ArgMatches) -> io::Result<()> {
+    let mut vault = unlock_vault(matches)?;
+    let recipient_arg = matches.get_one::<String>("recipient").unwrap();
+    let recipient: Recipient = recipient_arg.parse()?;
+    let label = matches.get_one::<String>("label").unwrap_or(recipient_arg);
+    let index = vault.add_recipient(label, &recipient)?;
+    println!("added key slot {index} for {recipient}");
+    Ok(())
+}
+
+/// Writes a new identity file and prints the recipient to share with the
+/// owner of a vault.
+fn run_recipient_keygen(matches: &ArgMatches) -> io::Result<()> {
+    let path = matches.get_one::<PathBuf>("output").unwrap();
+    let identity = Identity::generate()?;
+    identity.write_new(path)?;
+    println!("{}", identity.recipient());
+    Ok(())
+}
+
+fn print_recovery_shares(printed: &[String], threshold: u8) {
+    println!();
+    println!(
+        "Recovery key split into {} shares, any {threshold} of which recover the vault:",
+        printed.len()
+    );
+    println!();
+    for (number, share) in printed.iter().enumerate() {
+        println!("    {}. {share}", number + 1);
+    }
+    println!();
+    println!("Hand each share to a different person. They open the vault with 'vylfs recover'.");
+}
+
+/// Parses the `N/M` of `--split`: a threshold of N out of M shares.
+fn parse_split(value: &str) -> Result<(u8, u8), String> {
+    let (threshold, count) = value
+        .split_once('/')
+        .ok_or_else(|| format!("'{value}' is not of the form N/M"))?;
+    let threshold: u8 = threshold
+        .parse()
+        .map_err(|_| format!("'{threshold}' is not a number of shares"))?;
+    let count: u8 = count
+        .parse()
+        .map_err(|_| format!("'{count}' is not a number of shares"))?;
+    if threshold < 2 || threshold > count {
+        return Err("the threshold N must be at least 2 and at most M".to_string());
+    }
+    Ok((threshold, count))
+}
+
+fn print_recovery_key(printed: &str) {
+    println!();
+    println!("Recovery key, write it down and keep it apart from the vault:");
+    println!();
+    println!("    {printed}");
+    println!();
+    println!("It opens the vault with 'vylfs --recovery' if all passphrases are lost.");
+}
+
+/// Unlocks the vault named by a subcommand's `root_dir` and credential
+/// arguments.
+fn unlock_vault(matches: &ArgMatches) -> io::Result<Vault> {
+    let root_dir = matches.get_one::<PathBuf>("root_dir").unwrap();
+    validate_dir(root_dir)?;
+    let credential = passphrase::credential(root_dir, &credential_source(matches))?;
+    Vault::unlock(root_dir, &credential)
+}
+
+/// Returns where the credential that unlocks a vault is read from, as chosen
+/// by `keyfile_arg`, `recovery_arg` and `identity_arg`.
+fn credential_source(matches: &ArgMatches) -> CredentialSource {
+    if let Some(keyfile) = matches.get_one::<PathBuf>("keyfile") {
+        CredentialSource::Keyfile(keyfile.clone())
+    } else if let Some(identity) = matches.get_one::<PathBuf>("identity") {
+        CredentialSource::Identity(identity.clone())
+    } else if matches.get_flag("recovery") {
+        CredentialSource::RecoveryKey
+    } else {
+        CredentialSource::Passphrase
+    }
+}
+
+/// Returns where passphrases are read from: a descriptor or file given on the
+/// command line, the program named by `VYLFS_ASKPASS`, or the terminal.
+fn passphrase_input(matches: &ArgMatches) -> PassphraseInput {
+    if let Some(fd) = matches.get_one::<RawFd>("passphrase_fd") {
+        PassphraseInput::Fd(*fd)
+    } else if let Some(path) = matches.get_one::<PathBuf>("passphrase_file") {
+        PassphraseInput::File(path.clone())
+    } else if let Some(program) = env::var_os(passphrase::ASKPASS_VAR).filter(|var| !var.is_empty())
+    {
+        PassphraseInput::Askpass(PathBuf::from(program))
+    } else {
+        PassphraseInput::Terminal
+    }
+}
+
+/// Reads the secret for a new key slot, from `keyfile` if one is given and by
+/// asking for a new passphrase otherwise.
+fn new_credential(keyfile: Option<&PathBuf>) -> io::Result<Credential> {
+    match keyfile {
+        Some(keyfile) => Credential::from_keyfile(keyfile),
+        None => Ok(Credential::Passphrase(passphrase::prompt_new(
+            "New passphrase: ",
+        )?)),
+    }
+}
+
+/// Prints the status of the daemon serving `mount_point`, or lists all active
+/// mounts if none is given.
+fn run_status(mount_point: Option<&Path>, json: bool) -> io::Result<()> {
+    let Some(mount_point) = mount_point else {
+        let mounts = active_mounts()?;
+        if json {
+            println!("{}", serde_json::to_string_pretty(&mounts)?);
+        } else if mounts.is_empty() {
+            println!("no active mounts");
+        } else {
+            let listing: Vec<String> = mounts.iter().map(ToString::to_string).collect();
+            print!("{}", listing.join("\n"));
+        }
+        return Ok(());
+    };
+
+    match control::send(mount_point, &Request::Status, CONTROL_TIMEOUT)? {
+        Response::Status(status) if json => {
+            println!("{}", serde_json::to_string_pretty(&status)?);
+        }
+        Response::Status(status) => print!("{status}"),
+        response => return Err(control::unexpected(response)),
+    }
+    Ok(())
+}
+
+/// Prompts for a credential of the vault behind a locked mount and hands the
+/// keys it unlocks to the daemon.
+fn run_unlock(mount_point: &Path, matches: &ArgMatches) -> io::Result<()> {
+    let status = match control::send(mount_point, &Request::Status, CONTROL_TIMEOUT)? {
+        Response::Status(status) => status,
+        response => return Err(control::unexpected(response)),
+    };
+    if !status.locked {
+        println!("'{}' is not locked", mount_point.display());
+        return Ok(());
+    }
+
+    let credential = passphrase::credential(&status.root_dir, &credential_source(matches))?;
+    let vault = Vault::unlock(&status.root_dir, &credential)?;
+    let request = Request::Unlock {
+        keyring: vault.keyring().clone(),
+    };
+    run_control(mount_point, &request)
+}
+
+/// Sends a control request to the daemon serving `mount_point` and prints its
+/// response.
+fn run_control(mount_point: &Path, request: &Request) -> io::Result<()> {
+    match control::send(mount_point, request, CONTROL_TIMEOUT)? {
+        Response::Flushed { open_handles } => {
+            println!("flushed, {open_handles} open handle(s)");
+        }
+        Response::Ok => {}
+        response => return Err(control::unexpected(response)),
+    }
+    Ok(())
+}
+
+fn vault_command(name: &'static str, about: &'static str) -> Command {
+    Command::new(name).about(about).arg(
+        Arg::new("root_dir")
+            .help("Root directory of the encrypted storage")
+            .value_parser(value_parser!(PathBuf))
+            .required(true),
+    )
+}
+
+fn keyfile_arg() -> Arg {
+    Arg::new("keyfile")
+        .long("keyfile")
+        .value_name("PATH")
+        .help("Unlock the vault with this keyfile instead of a passphrase")
+        .value_parser(value_parser!(PathBuf))
+}
+
+fn recovery_arg() -> Arg {
+    Arg::new("recovery")
+        .long("recovery")
+        .action(ArgAction::SetTrue)
+        .help("Unlock the vault with its recovery key")
+        .conflicts_with("keyfile")
+}
+
+fn identity_arg() -> Arg {
+    Arg::new("identity")
+        .long("identity")
+        .value_name("PATH")
+        .help("Unlock the vault with the private key in this identity file")
+        .value_parser(value_parser!(PathBuf))
+        .conflicts_with_all(["keyfile", "recovery"])
+}
+
+fn control_command(name: &'static str, about: &'static str) -> Command {
+    Command::new(name).about(about).arg(
+        Arg::new("mount_point")
+            .help("Mount point of the daemon")
+            .value_parser(value_parser!(PathBuf))
+            .required(true),
+    )
+}
+
 fn build_command() -> Command {
     Command::new("vylfs")
         .version(env!("CARGO_PKG_VERSION"))
         .about(env!("CARGO_PKG_DESCRIPTION"))
-        .subcommand(Command::new("log").about("Print the log from the last run if it exists"))
+        .subcommand(
+            Command::new("log")
+                .about("Print the log of a mount, or of the most recent mount if none is given")
+                .arg(
+                    Arg::new("mount_point")
+                        .help("Mount point whose log should be printed")
+                        .value_parser(value_parser!(PathBuf))
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("follow")
+                        .short('f')
+                        .long("follow")
+                        .action(ArgAction::SetTrue)
+                        .help("Keep printing new lines as they are written"),
+                )
+                .arg(
+                    Arg::new("lines")
+                        .short('n')
+                        .long("lines")
+                        .value_name("N")
+                        .help("Only print the last N lines")
+                        .value_parser(value_parser!(usize)),
+                )
+                .arg(
+                    Arg::new("level")
+                        .long("level")
+                        .help("Only print lines at this level or more severe")
+                        .value_parser(
+                            PossibleValuesParser::new(["error", "warn", "info", "debug", "trace"])
+                                .try_map(|level| level.parse::<Level>()),
+                        ),
+                ),
+        )
+        .subcommand(
+            vault_command(
+                "init",
+                "Create a new vault protected by a passphrase or keyfile",
+            )
+            .arg(
+                Arg::new("label")
+                    .long("label")
+                    .help("Label of the first key slot")
+                    .default_value("primary"),
+            )
+            .arg(keyfile_arg().help("Protect the vault with this keyfile instead"))
+            .arg(
+                Arg::new("no_recovery_key")
+                    .long("no-recovery-key")
+                    .action(ArgAction::SetTrue)
+                    .help("Do not generate a recovery key"),
+            )
+            .arg(
+                Arg::new("split")
+                    .long("split")
+                    .value_name("N/M")
+                    .help("Split the recovery key into M shares, any N of which recover it")
+                    .value_parser(parse_split)
+                    .conflicts_with("no_recovery_key"),
+            )
+            .arg(
+                Arg::new("cipher")
+                    .long("cipher")
+                    .help(
+                        "Cipher that file contents and directories, with the names in them, are \
+                         encrypted with",
+                    )
+                    .default_value("xchacha20-poly1305")
+                    .value_parser(
+                        PossibleValuesParser::new(Cipher::NAMES)
+                            .try_map(|name| name.parse::<Cipher>()),
+                    ),
+            ),
+        )
+        .subcommand(
+            vault_command(
+                "recover",
+                "Open a vault with the shares of its recovery key and add a key slot",
+            )
+            .arg(
+                Arg::new("label")
+                    .long("label")
+                    .help("Label of the new slot")
+                    .default_value("recovered"),
+            )
+            .arg(
+                Arg::new("new_keyfile")
+                    .long("new-keyfile")
+                    .value_name("PATH")
+                    .help("Open the new slot with this keyfile instead of a passphrase")
+                    .value_parser(value_parser!(PathBuf)),
+            ),
+        )
+        .subcommand(vault_command(
+            "passwd",
+            "Change the passphrase of a vault without re-encrypting its data",
+        ))
+        .subcommand(
+            vault_command(
+                "rekey",
+                "Re-encrypt a vault under a new master key, resuming an interrupted rekey",
+            )
+            .arg(keyfile_arg())
+            .arg(recovery_arg())
+            .arg(identity_arg())
+            .arg(
+                Arg::new("drop_slots")
+                    .long("drop-slots")
+                    .action(ArgAction::SetTrue)
+                    .help(
+                        "Remove the key slots that cannot be moved to the new key, such as other \
+                         passphrases, instead of refusing to rekey",
+                    ),
+            ),
+        )
+        .subcommand(
+            vault_command(
+                "fsck",
+                "Verify every object of an unmounted vault and cross-check its directories",
+            )
+            .arg(
+                Arg::new("repair")
+                    .long("repair")
+                    .action(ArgAction::SetTrue)
+                    .help(
+                        "Fix link counts and block maps, drop dangling entries and move orphans \
+                         to lost+found",
+                    ),
+            )
+            .arg(
+                Arg::new("accept_rollback")
+                    .long("accept-rollback")
+                    .action(ArgAction::SetTrue)
+                    .requires("repair")
+                    .help("Repair the vault even if it or some of its objects were rolled back"),
+            )
+            .arg(keyfile_arg())
+            .arg(recovery_arg())
+            .arg(identity_arg()),
+        )
+        .subcommand(
+            vault_command(
+                "upgrade",
+                "Migrate a vault made by an older version of vylfs to the current format",
+            )
+            .arg(
+                Arg::new("dry_run")
+                    .long("dry-run")
+                    .action(ArgAction::SetTrue)
+                    .help("Only print what the upgrade would change"),
+            )
+            .arg(keyfile_arg())
+            .arg(recovery_arg())
+            .arg(identity_arg()),
+        )
+        .subcommand(
+            Command::new("key")
+                .about("Manage the key slots of a vault")
+                .subcommand_required(true)
+                .subcommand(
+                    vault_command("add", "Add a key slot for a new passphrase or keyfile")
+                        .arg(
+                            Arg::new("label")
+                                .long("label")
+                                .help("Label of the new slot")
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::new("new_keyfile")
+                                .long("new-keyfile")
+                                .value_name("PATH")
+                                .help("Open the new slot with this keyfile instead of a passphrase")
+                                .value_parser(value_parser!(PathBuf)),
+                        )
+                        .arg(
+                            Arg::new("new_recovery_key")
+                                .long("new-recovery-key")
+                                .action(ArgAction::SetTrue)
+                                .help("Generate and print a recovery key for the new slot")
+                                .conflicts_with("new_keyfile"),
+                        )
+                        .arg(keyfile_arg())
+                        .arg(recovery_arg())
+                        .arg(identity_arg()),
+                )
+                .subcommand(
+                    vault_command("remove", "Remove a key slot, unless it is the last one")
+                        .arg(
+                            Arg::new("slot")
+                                .help("Index or label of the slot")
+                                .required(true),
+                        )
+                        .arg(keyfile_arg())
+                        .arg(recovery_arg())
+                        .arg(identity_arg()),
+                )
+                .subcommand(vault_command("list", "List the key slots of a vault")),
+        )
+        .subcommand(
+            Command::new("recipient")
+                .about("Share a vault with the holders of X25519 identities")
+                .subcommand_required(true)
+                .subcommand(
+                    vault_command("add", "Add a key slot wrapped to a recipient's public key")
+                        .arg(
+                            Arg::new("recipient")
+                                .help("Public key of the recipient, starting with 'age1'")
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::new("label")
+                                .long("label")
+                                .help("Label of the new slot, the public key by default"),
+                        )
+                        .arg(keyfile_arg())
+                        .arg(recovery_arg())
+                        .arg(identity_arg()),
+                )
+                .subcommand(
+                    Command::new("keygen")
+                        .about("Generate an identity file and print its public key")
+                        .arg(
+                            Arg::new("output")
+                                .help("Path of the new identity file")
+                                .value_parser(value_parser!(PathBuf))
+                                .required(true),
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("status")
+                .about("List active mounts, or show the state of the daemon serving a mount point")
+                .arg(
+                    Arg::new("mount_point")
+                        .help("Mount point whose daemon should be queried")
+                        .value_parser(value_parser!(PathBuf))
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("json")
+                        .long("json")
+                        .action(ArgAction::SetTrue)
+                        .help("Print the status as JSON"),
+                ),
+        )
+        .subcommand(control_command(
+            "flush",
+            "Write all buffered data of a mount to storage",
+        ))
+        .subcommand(control_command(
+            "lock",
+            "Drop the keys of a mounted vault and deny access without unmounting it",
+        ))
+        .subcommand(
+            control_command(
+                "unlock",
+                "Prompt for the vault's passphrase and allow access to a locked mount again",
+            )
+            .arg(keyfile_arg())
+            .arg(recovery_arg())
+            .arg(identity_arg()),
+        )
+        .subcommand(
+            control_command("reload", "Reload the configuration of a running daemon").arg(
+                Arg::new("level")
+                    .long("level")
+                    .help("Log level for the daemon, unchanged if not given")
+                    .value_parser(["error", "warn", "info", "debug", "trace"]),
+            ),
+        )
         .arg(
             Arg::new("root_dir")
                 .help("Set the root directory for the encrypted storage")
@@ -90,20 +1085,133 @@ fn build_command() -> Command {
                 .required(false)
                 .requires("root_dir"),
         )
+        .arg(
+            Arg::new("options")
+                .short('o')
+                .long("options")
+                .help(
+                    "Comma-separated mount options (ro, allow_other, default_permissions, noexec, \
+                     nosuid, nodev, noatime, fsname=NAME, subtype=TYPE)",
+                )
+                .value_parser(parse_mount_option)
+                .value_delimiter(',')
+                .action(ArgAction::Append)
+                .requires("root_dir"),
+        )
+        .arg(
+            Arg::new("foreground")
+                .short('f')
+                .long("foreground")
+                .action(ArgAction::SetTrue)
+                .help("Stay in the foreground instead of running as a daemon")
+                .requires("root_dir"),
+        )
+        .arg(keyfile_arg().requires("root_dir"))
+        .arg(recovery_arg().requires("root_dir"))
+        .arg(identity_arg().requires("root_dir"))
+        .arg(
+            Arg::new("idle_lock")
+                .long("idle-lock")
+                .value_name("MINUTES")
+                .help("Lock the vault after this many minutes without filesystem requests")
+                .value_parser(value_parser!(u64).range(1..=MAX_IDLE_MINUTES))
+                .requires("root_dir"),
+        )
+        .arg(
+            Arg::new("idle_timeout")
+                .long("idle-timeout")
+                .value_name("MINUTES")
+                .help(
+                    "Unmount and stop the daemon after this many minutes without filesystem \
+                     requests, once no files are open",
+                )
+                .value_parser(value_parser!(u64).range(1..=MAX_IDLE_MINUTES))
+                .requires("root_dir"),
+        )
+        .arg(
+            Arg::new("break_lock")
+                .long("break-lock")
+                .action(ArgAction::SetTrue)
+                .help("Take over the vault's lock file if it was left behind by another host")
+                .requires("root_dir"),
+        )
+        .arg(
+            Arg::new("accept_rollback")
+                .long("accept-rollback")
+                .action(ArgAction::SetTrue)
+                .help(
+                    "Mount the vault even if it is older than a copy of it that was mounted before",
+                )
+                .requires("root_dir"),
+        )
         .arg(
             Arg::new("unmount")
                 .short('u')
                 .long("unmount")
-                .help("Unmount a specific mount point (requires root)")
+                .help("Unmount a specific mount point")
                 .value_parser(value_parser!(PathBuf))
                 .required(false)
                 .conflicts_with_all(["root_dir", "mount_point"]),
         )
+        .arg(
+            Arg::new("lazy")
+                .long("lazy")
+                .action(ArgAction::SetTrue)
+                .help("Detach the mount point now and finish unmounting once it is no longer busy")
+                .requires("unmount"),
+        )
+        .arg(
+            Arg::new("force")
+                .long("force")
+                .action(ArgAction::SetTrue)
+                .help("Force the unmount even if the filesystem is busy (requires root)")
+                .requires("unmount")
+                .conflicts_with("lazy"),
+        )
+        .arg(
+            Arg::new("timeout")
+                .long("timeout")
+                .value_name("SECONDS")
+                .help("How long to wait for the daemon to flush before giving up")
+                .value_parser(value_parser!(u64))
+                .default_value("10")
+                .requires("unmount"),
+        )
+        .arg(
+            Arg::new("passphrase_fd")
+                .long("passphrase-fd")
+                .value_name("FD")
+                .help("Read passphrases from this file descriptor, one line per prompt")
+                .value_parser(value_parser!(RawFd))
+                .global(true),
+        )
+        .arg(
+            Arg::new("passphrase_file")
+                .long("passphrase-file")
+                .value_name("PATH")
+                .help("Read passphrases from this file, one line per prompt")
+                .value_parser(value_parser!(PathBuf))
+                .conflicts_with("passphrase_fd")
+                .global(true),
+        )
+        .arg(
+            Arg::new("log_format")
+                .long("log-format")
+                .help("Format of log output, json writes one parseable event per line")
+                .value_parser(["text", "json"])
+                .default_value("text"),
+        )
+        .arg(
+            Arg::new("log_plaintext_names")
+                .long("log-plaintext-names")
+                .action(ArgAction::SetTrue)
+                .help("Write file names and paths to the log in plaintext, for debugging only"),
+        )
         .arg(
             Arg::new("verbose")
                 .short('v')
                 .long("verbose")
-                .action(ArgAction::SetTrue)
-                .help("Enable verbose output"),
+                .action(ArgAction::Count)
+                .help("Enable verbose output, repeat for debug output"),
         )
 }
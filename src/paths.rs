@@ -0,0 +1,166 @@
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::fs::DirBuilder;
+use std::io;
+use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::fs::MetadataExt;
+use std::path;
+use std::path::Path;
+use std::path::PathBuf;
+
+use libc::geteuid;
+
+/// Returns the per-user `vylfs` state directory, creating it with mode `0700`
+/// if needed.
+///
+/// Follows the XDG base directory specification, using `$XDG_STATE_HOME/vylfs`
+/// and falling back to `$HOME/.local/state/vylfs`.
+pub fn state_dir() -> io::Result<PathBuf> {
+    let base = match env::var_os("XDG_STATE_HOME").map(PathBuf::from) {
+        Some(dir) if dir.is_absolute() => dir,
+        _ => match env::var_os("HOME") {
+            Some(home) => PathBuf::from(home).join(".local/state"),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "neither XDG_STATE_HOME nor HOME is set",
+                ));
+            }
+        },
+    };
+
+    let dir = base.join("vylfs");
+    ensure_private_dir(&dir)?;
+    Ok(dir)
+}
+
+/// Returns the per-user `vylfs` runtime directory for sockets, creating it with
+/// mode `0700` if needed.
+///
+/// Uses `$XDG_RUNTIME_DIR/vylfs` and falls back to the state directory.
+pub fn runtime_dir() -> io::Result<PathBuf> {
+    match env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from) {
+        Some(dir) if dir.is_absolute() => {
+            let dir = dir.join("vylfs");
+            ensure_private_dir(&dir)?;
+            Ok(dir)
+        }
+        _ => state_dir(),
+    }
+}
+
+/// Creates `dir` with mode `0700` if missing and checks that no other user can
+/// write to it.
+pub fn ensure_private_dir(dir: &Path) -> io::Result<()> {
+    DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+
+    let metadata = fs::symlink_metadata(dir)?;
+    let uid = unsafe { geteuid() };
+    if !metadata.is_dir() || metadata.uid() != uid || metadata.mode() & 0o022 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "'{}' is not a private directory owned by the current user",
+                dir.display()
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Derives a stable identifier for a mount point that is safe to use as a file
+/// name.
+///
+/// The path is made absolute and escaped like `systemd-escape --path`, so
+/// `/home/me/vault` becomes `home-me-vault`.
+pub fn mount_id(mount_point: &Path) -> io::Result<String> {
+    let absolute = fs::canonicalize(mount_point).or_else(|_| path::absolute(mount_point))?;
+    Ok(escape_path(&absolute))
+}
+
+/// Escapes an absolute path the way `mount_id` does, without resolving it.
+pub fn escape_path(path: &Path) -> String {
+    let trimmed = path.to_string_lossy();
+    let trimmed = trimmed.trim_matches('/');
+    if trimmed.is_empty() {
+        return "-".to_string();
+    }
+
+    let mut escaped = String::with_capacity(trimmed.len());
+    for (i, byte) in trimmed.bytes().enumerate() {
+        match byte {
+            b'/' => escaped.push('-'),
+            b'.' if i == 0 => escaped.push_str("\\x2e"),
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'_' | b'.' => escaped.push(byte as char),
+            _ => {
+                let _ = write!(escaped, "\\x{byte:02x}");
+            }
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn test_escape_path() {
+        let cases = [
+            ("/", "-"),
+            ("/home/me/vault", "home-me-vault"),
+            ("/mnt/my-vault/", "mnt-my\\x2dvault"),
+            ("/.hidden/dir", "\\x2ehidden-dir"),
+            ("/mnt/with space", "mnt-with\\x20space"),
+        ];
+
+        for (input, expected) in cases {
+            assert_eq!(
+                escape_path(Path::new(input)),
+                expected,
+                "failed to escape '{input}'"
+            );
+        }
+    }
+
+    #[test]
+    fn test_mount_id_is_absolute() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let id = mount_id(temp_dir.path())?;
+        assert_eq!(id, escape_path(&fs::canonicalize(temp_dir.path())?));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ensure_private_dir_creates_dir() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let dir = temp_dir.path().join("state/vylfs");
+
+        ensure_private_dir(&dir)?;
+        let mode = fs::metadata(&dir)?.permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ensure_private_dir_rejects_shared_dir() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let dir = temp_dir.path().join("shared");
+        fs::create_dir(&dir)?;
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o777))?;
+
+        let result = ensure_private_dir(&dir);
+        assert!(result.is_err(), "Expected an error, but got Ok(())");
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,416 @@
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+use aes_gcm::Aes256Gcm;
+use aes_gcm_siv::Aes256GcmSiv;
+use argon2::Algorithm;
+use argon2::Argon2;
+use argon2::Params;
+use argon2::Version;
+use chacha20poly1305::KeyInit;
+use chacha20poly1305::XChaCha20Poly1305;
+use chacha20poly1305::aead::Aead;
+use chacha20poly1305::aead::Nonce;
+use chacha20poly1305::aead::Payload;
+use serde::Deserialize;
+use serde::Serialize;
+
+/// Length of all symmetric keys.
+pub const KEY_LEN: usize = 32;
+
+/// Length of an XChaCha20-Poly1305 nonce, which key slots are sealed with.
+pub const NONCE_LEN: usize = 24;
+
+/// Length of a KDF salt.
+pub const SALT_LEN: usize = 16;
+
+/// The most memory `KdfParams` may ask for, 4 GiB.
+const MAX_KDF_MEMORY_KIB: u32 = 4 * 1024 * 1024;
+
+/// The most passes `KdfParams` may ask for.
+const MAX_KDF_ITERATIONS: u32 = 16;
+
+/// The most lanes `KdfParams` may ask for.
+const MAX_KDF_PARALLELISM: u32 = 16;
+
+/// Argon2id cost parameters, stored next to each salt so they can be raised
+/// for new passphrases without breaking existing ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KdfParams {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl KdfParams {
+    /// Fails with `InvalidData` if the parameters, which come from a header
+    /// anyone could have written, exceed what unlocking is allowed to cost.
+    pub fn check(&self) -> io::Result<()> {
+        if self.memory_kib > MAX_KDF_MEMORY_KIB
+            || self.iterations > MAX_KDF_ITERATIONS
+            || self.parallelism > MAX_KDF_PARALLELISM
+        {
+            return Err(invalid_data(format!(
+                "KDF parameters {} KiB, {} iterations and {} lanes exceed the limits of \
+                 {MAX_KDF_MEMORY_KIB} KiB, {MAX_KDF_ITERATIONS} iterations and \
+                 {MAX_KDF_PARALLELISM} lanes",
+                self.memory_kib, self.iterations, self.parallelism
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl Default for KdfParams {
+    /// 64 MiB and three passes, which takes well under a second on current
+    /// hardware.
+    fn default() -> Self {
+        Self {
+            memory_kib: 64 * 1024,
+            iterations: 3,
+            parallelism: 1,
+        }
+    }
+}
+
+/// The AEAD that the objects of a vault are encrypted with, chosen when the
+/// vault is created.
+///
+/// There is no separate scheme for file names. Names only exist inside the
+/// records of their directories, which this cipher encrypts like file
+/// contents, and never appear in object names or elsewhere in the clear.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Cipher {
+    /// AES in Galois/Counter Mode, fastest on CPUs with AES instructions. Its
+    /// random 96-bit nonces limit a key to about 2^32 writes, after which the
+    /// vault must be rekeyed, see `write_limit`.
+    #[serde(rename = "aes-256-gcm")]
+    Aes256Gcm,
+    /// Fast in software, and its 192-bit nonces are safe to pick at random.
+    #[default]
+    #[serde(rename = "xchacha20-poly1305")]
+    XChaCha20Poly1305,
+    /// Like AES-256-GCM, but a repeated nonce only reveals whether the same
+    /// data was written twice.
+    #[serde(rename = "aes-256-gcm-siv")]
+    Aes256GcmSiv,
+}
+
+impl Cipher {
+    /// The names accepted by `FromStr`, in the order of the variants.
+    pub const NAMES: [&str; 3] = ["aes-256-gcm", "xchacha20-poly1305", "aes-256-gcm-siv"];
+
+    /// How many objects one key may encrypt before its random nonces are
+    /// likely enough to repeat that it must be replaced, if there is a limit.
+    pub fn write_limit(self) -> Option<u64> {
+        match self {
+            Self::Aes256Gcm => Some(1 << 32),
+            Self::XChaCha20Poly1305 | Self::Aes256GcmSiv => None,
+        }
+    }
+
+    pub fn nonce_len(self) -> usize {
+        match self {
+            Self::Aes256Gcm | Self::Aes256GcmSiv => 12,
+            Self::XChaCha20Poly1305 => NONCE_LEN,
+        }
+    }
+
+    /// Encrypts `plaintext` under `key` like `seal`, with this cipher.
+    pub fn seal(
+        self,
+        key: &[u8; KEY_LEN],
+        aad: &[u8],
+        plaintext: &[u8],
+    ) -> io::Result<(Vec<u8>, Vec<u8>)> {
+        let mut nonce = vec![0; self.nonce_len()];
+        fill_random(&mut nonce)?;
+        let ciphertext = self.seal_with_nonce(key, &nonce, aad, plaintext)?;
+        Ok((nonce, ciphertext))
+    }
+
+    /// Decrypts and authenticates a ciphertext produced by `seal`, see
+    /// `open`.
+    pub fn open(
+        self,
+        key: &[u8; KEY_LEN],
+        aad: &[u8],
+        nonce: &[u8],
+        ciphertext: &[u8],
+    ) -> io::Result<Vec<u8>> {
+        if nonce.len() != self.nonce_len() {
+            return Err(invalid_data(format!(
+                "nonce must be {} bytes, got {}",
+                self.nonce_len(),
+                nonce.len()
+            )));
+        }
+        let payload = Payload {
+            msg: ciphertext,
+            aad,
+        };
+        match self {
+            Self::Aes256Gcm => decrypt::<Aes256Gcm>(key, nonce, payload),
+            Self::XChaCha20Poly1305 => decrypt::<XChaCha20Poly1305>(key, nonce, payload),
+            Self::Aes256GcmSiv => decrypt::<Aes256GcmSiv>(key, nonce, payload),
+        }
+    }
+
+    fn seal_with_nonce(
+        self,
+        key: &[u8; KEY_LEN],
+        nonce: &[u8],
+        aad: &[u8],
+        plaintext: &[u8],
+    ) -> io::Result<Vec<u8>> {
+        let payload = Payload {
+            msg: plaintext,
+            aad,
+        };
+        match self {
+            Self::Aes256Gcm => encrypt::<Aes256Gcm>(key, nonce, payload),
+            Self::XChaCha20Poly1305 => encrypt::<XChaCha20Poly1305>(key, nonce, payload),
+            Self::Aes256GcmSiv => encrypt::<Aes256GcmSiv>(key, nonce, payload),
+        }
+    }
+}
+
+impl fmt::Display for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let index = match self {
+            Self::Aes256Gcm => 0,
+            Self::XChaCha20Poly1305 => 1,
+            Self::Aes256GcmSiv => 2,
+        };
+        f.write_str(Self::NAMES[index])
+    }
+}
+
+impl FromStr for Cipher {
+    type Err = io::Error;
+
+    fn from_str(name: &str) -> io::Result<Self> {
+        match name {
+            "aes-256-gcm" => Ok(Self::Aes256Gcm),
+            "xchacha20-poly1305" => Ok(Self::XChaCha20Poly1305),
+            "aes-256-gcm-siv" => Ok(Self::Aes256GcmSiv),
+            _ => Err(invalid_data(format!("unknown cipher '{name}'"))),
+        }
+    }
+}
+
+fn encrypt<C: KeyInit + Aead>(
+    key: &[u8; KEY_LEN],
+    nonce: &[u8],
+    payload: Payload,
+) -> io::Result<Vec<u8>> {
+    C::new_from_slice(key)
+        .map_err(|_| invalid_data("invalid key length".to_string()))?
+        .encrypt(Nonce::<C>::from_slice(nonce), payload)
+        .map_err(|_| io::Error::other("encryption failed"))
+}
+
+fn decrypt<C: KeyInit + Aead>(
+    key: &[u8; KEY_LEN],
+    nonce: &[u8],
+    payload: Payload,
+) -> io::Result<Vec<u8>> {
+    C::new_from_slice(key)
+        .map_err(|_| invalid_data("invalid key length".to_string()))?
+        .decrypt(Nonce::<C>::from_slice(nonce), payload)
+        .map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "wrong key or corrupted data",
+            )
+        })
+}
+
+/// Derives a key-encryption key from a passphrase with Argon2id.
+pub fn derive_key(passphrase: &[u8], salt: &[u8], params: &KdfParams) -> io::Result<[u8; KEY_LEN]> {
+    let params = Params::new(
+        params.memory_kib,
+        params.iterations,
+        params.parallelism,
+        Some(KEY_LEN),
+    )
+    .map_err(|err| invalid_data(format!("invalid KDF parameters: {err}")))?;
+
+    let mut key = [0u8; KEY_LEN];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase, salt, &mut key)
+        .map_err(|err| invalid_data(format!("key derivation failed: {err}")))?;
+    Ok(key)
+}
+
+/// Encrypts `plaintext` under `key` with XChaCha20-Poly1305, returning a
+/// fresh random nonce and the ciphertext with its tag. `aad` is authenticated
+/// but not encrypted.
+pub fn seal(
+    key: &[u8; KEY_LEN],
+    aad: &[u8],
+    plaintext: &[u8],
+) -> io::Result<([u8; NONCE_LEN], Vec<u8>)> {
+    let nonce = random_bytes::<NONCE_LEN>()?;
+    let ciphertext = Cipher::XChaCha20Poly1305.seal_with_nonce(key, &nonce, aad, plaintext)?;
+    Ok((nonce, ciphertext))
+}
+
+/// Decrypts and authenticates a ciphertext produced by `seal`.
+///
+/// Fails with `PermissionDenied` if the key is wrong or the data was modified,
+/// as the two cannot be told apart.
+pub fn open(
+    key: &[u8; KEY_LEN],
+    aad: &[u8],
+    nonce: &[u8],
+    ciphertext: &[u8],
+) -> io::Result<Vec<u8>> {
+    Cipher::XChaCha20Poly1305.open(key, aad, nonce, ciphertext)
+}
+
+/// Returns `N` bytes from the operating system's random number generator.
+pub fn random_bytes<const N: usize>() -> io::Result<[u8; N]> {
+    let mut bytes = [0u8; N];
+    fill_random(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Fills `bytes` from the operating system's random number generator.
+pub fn fill_random(bytes: &mut [u8]) -> io::Result<()> {
+    getrandom::fill(bytes)
+        .map_err(|err| io::Error::other(format!("failed to get random bytes: {err}")))
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use data_encoding::HEXLOWER_PERMISSIVE;
+
+    use super::*;
+    use crate::vault::tests::TEST_PARAMS;
+
+    fn hex(encoded: &str) -> Vec<u8> {
+        HEXLOWER_PERMISSIVE
+            .decode(encoded.as_bytes())
+            .expect("test vector is hex")
+    }
+
+    /// Checks a published test vector, given as key, nonce, associated data,
+    /// plaintext and ciphertext with its tag.
+    fn check_vector(cipher: Cipher, vector: [&str; 5]) -> io::Result<()> {
+        let [key, nonce, aad, plaintext, ciphertext] = vector.map(hex);
+        let key: [u8; KEY_LEN] = key.try_into().expect("test key is 32 bytes");
+        assert_eq!(
+            cipher.seal_with_nonce(&key, &nonce, &aad, &plaintext)?,
+            ciphertext,
+            "{cipher}"
+        );
+        assert_eq!(cipher.open(&key, &aad, &nonce, &ciphertext)?, plaintext);
+        Ok(())
+    }
+
+    #[test]
+    fn test_aes_256_gcm_known_answer() -> io::Result<()> {
+        // Test case 14 of the GCM specification.
+        check_vector(
+            Cipher::Aes256Gcm,
+            [
+                &"00".repeat(32),
+                &"00".repeat(12),
+                "",
+                &"00".repeat(16),
+                "cea7403d4d606b6e074ec5d3baf39d18d0d1c8a799996bf0265b98b5d48ab919",
+            ],
+        )
+    }
+
+    #[test]
+    fn test_xchacha20_poly1305_known_answer() -> io::Result<()> {
+        // Appendix A.3.1 of draft-irtf-cfrg-xchacha.
+        let plaintext =
+            b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip \
+                          for the future, sunscreen would be it.";
+        check_vector(
+            Cipher::XChaCha20Poly1305,
+            [
+                "808182838485868788898a8b8c8d8e8f909192939495969798999a9b9c9d9e9f",
+                "404142434445464748494a4b4c4d4e4f5051525354555657",
+                "50515253c0c1c2c3c4c5c6c7",
+                &HEXLOWER_PERMISSIVE.encode(plaintext),
+                "bd6d179d3e83d43b9576579493c0e939572a1700252bfaccbed2902c21396cbb731c7f1b0b4aa6440bf3a82f\
+                 4eda7e39ae64c6708c54c216cb96b72e1213b4522f8c9ba40db5d945b11b69b982c1bb9e3f3fac2bc36948\
+                 8f76b2383565d3fff921f9664c97637da9768812f615c68b13b52ec0875924c1c7987947deafd8780acf49",
+            ],
+        )
+    }
+
+    #[test]
+    fn test_aes_256_gcm_siv_known_answer() -> io::Result<()> {
+        // The first AES-256 vector of RFC 8452, appendix C.2.
+        check_vector(
+            Cipher::Aes256GcmSiv,
+            [
+                "0100000000000000000000000000000000000000000000000000000000000000",
+                "030000000000000000000000",
+                "",
+                "",
+                "07f5f4169bbf55a8400cd47ea6fd400f",
+            ],
+        )
+    }
+
+    #[test]
+    fn test_cipher_names_round_trip() -> io::Result<()> {
+        for name in Cipher::NAMES {
+            let cipher: Cipher = name.parse()?;
+            assert_eq!(cipher.to_string(), name);
+            assert_eq!(serde_json::to_value(cipher)?, name);
+        }
+        let result = "aes-128-gcm".parse::<Cipher>();
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_seal_open_round_trip() -> io::Result<()> {
+        let key = random_bytes::<KEY_LEN>()?;
+        let (nonce, ciphertext) = seal(&key, b"aad", b"secret")?;
+        assert_eq!(open(&key, b"aad", &nonce, &ciphertext)?, b"secret");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_rejects_wrong_key_and_aad() -> io::Result<()> {
+        let key = random_bytes::<KEY_LEN>()?;
+        let (nonce, ciphertext) = seal(&key, b"aad", b"secret")?;
+
+        let result = open(&random_bytes::<KEY_LEN>()?, b"aad", &nonce, &ciphertext);
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+
+        let result = open(&key, b"other", &nonce, &ciphertext);
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_derive_key_depends_on_salt() -> io::Result<()> {
+        let first = derive_key(b"passphrase", &[1; SALT_LEN], &TEST_PARAMS)?;
+        let second = derive_key(b"passphrase", &[2; SALT_LEN], &TEST_PARAMS)?;
+        assert_eq!(
+            first,
+            derive_key(b"passphrase", &[1; SALT_LEN], &TEST_PARAMS)?
+        );
+        assert_ne!(first, second);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,116 @@
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::paths::ensure_private_dir;
+use crate::paths::state_dir;
+
+/// Directory inside the state directory that holds the last epoch seen of
+/// each vault.
+const EPOCHS_DIR_NAME: &str = "epochs";
+
+/// The last epoch of a vault that the current user has seen.
+///
+/// A vault's epoch only grows and is authenticated with its master key, see
+/// `TreeRoot`. It is kept outside of the vault, so that restoring an old copy
+/// of the vault cannot restore it, too.
+#[derive(Debug)]
+pub struct SeenEpoch {
+    path: PathBuf,
+}
+
+impl SeenEpoch {
+    /// Opens the record of the vault with identifier `vault_id` in the state
+    /// directory.
+    pub fn open(vault_id: &str) -> io::Result<Self> {
+        let dir = state_dir()?.join(EPOCHS_DIR_NAME);
+        ensure_private_dir(&dir)?;
+        Ok(Self::in_dir(&dir, vault_id))
+    }
+
+    /// Opens the record of the vault with identifier `vault_id` in `dir`.
+    pub(crate) fn in_dir(dir: &Path, vault_id: &str) -> Self {
+        Self {
+            path: dir.join(vault_id),
+        }
+    }
+
+    /// Returns the epoch last recorded, or `None` if the vault was never seen.
+    pub fn get(&self) -> io::Result<Option<u64>> {
+        let contents = match fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        contents.trim().parse().map(Some).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("'{}' does not hold an epoch", self.path.display()),
+            )
+        })
+    }
+
+    /// Fails if `epoch` is older than the last one seen, which means that the
+    /// vault was replaced by an old copy of itself.
+    pub fn check(&self, epoch: u64) -> io::Result<()> {
+        match self.get()? {
+            Some(seen) if epoch < seen => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "the vault was rolled back to epoch {epoch}, but epoch {seen} was seen \
+                     before; use --accept-rollback if an old copy was restored on purpose"
+                ),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Records `epoch` as the last one seen, replacing the previous record
+    /// atomically.
+    pub fn record(&self, epoch: u64) -> io::Result<()> {
+        let mut temp_path = self.path.clone().into_os_string();
+        temp_path.push(".tmp");
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(&temp_path)?;
+        writeln!(file, "{epoch}")?;
+        file.sync_data()?;
+        fs::rename(temp_path, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn test_older_epochs_are_refused() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let seen = SeenEpoch::in_dir(temp_dir.path(), "0123abcd");
+        assert_eq!(seen.get()?, None);
+        seen.check(5)?;
+
+        seen.record(5)?;
+        assert_eq!(seen.get()?, Some(5));
+        seen.check(5)?;
+        seen.check(6)?;
+        let result = seen.check(4);
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+
+        // Accepting a rollback records the older epoch.
+        seen.record(4)?;
+        seen.check(4)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,592 @@
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::warn;
+use zeroize::Zeroizing;
+
+use crate::log::Redacted;
+use crate::vault::Keyring;
+use crate::vault::crypto;
+use crate::vault::crypto::Cipher;
+use crate::vault::crypto::KEY_LEN;
+use crate::vault::crypto::KdfParams;
+use crate::vault::crypto::SALT_LEN;
+use crate::vault::recipient;
+use crate::vault::recipient::Recipient;
+use crate::vault::secret::SecretBytes;
+
+/// Name of the header file inside the vault's root directory.
+pub const HEADER_FILE_NAME: &str = "vylfs.header";
+
+/// Copy of the previous header, kept while a new one is being written.
+pub const HEADER_BACKUP_NAME: &str = "vylfs.header.bak";
+
+/// A new header is written here and then renamed over the old one.
+const HEADER_TEMP_NAME: &str = "vylfs.header.tmp";
+
+/// Version of the vault format written by this build.
+///
+/// Version 1 vaults have no feature bitmaps, and may hold objects without
+/// versions and lack an authenticated tree. `vylfs upgrade` migrates them.
+pub const FORMAT_VERSION: u32 = 2;
+
+/// The oldest vault format this build can still read and upgrade.
+pub const OLDEST_FORMAT_VERSION: u32 = 1;
+
+/// Every object is versioned and the vault has an authenticated tree, see
+/// `TreeRoot`.
+pub const FEATURE_VERSIONED_OBJECTS: u64 = 1 << 0;
+
+/// Objects are encrypted with a cipher other than XChaCha20-Poly1305, see
+/// `Header::cipher`.
+pub const FEATURE_CIPHER_CHOICE: u64 = 1 << 1;
+
+/// The required features this build supports. Vaults that require any other
+/// feature are refused.
+pub const KNOWN_REQUIRED_FEATURES: u64 = FEATURE_VERSIONED_OBJECTS | FEATURE_CIPHER_CHOICE;
+
+/// The required features of vaults created by this build.
+pub const DEFAULT_REQUIRED_FEATURES: u64 = FEATURE_VERSIONED_OBJECTS;
+
+/// Domain separation for the associated data of wrapped keys.
+const KEY_SLOT_AAD: &[u8] = b"vylfs key slot";
+
+/// The vault header, stored as JSON in `root_dir`.
+///
+/// The master keys never change when a passphrase does, only the key slot that
+/// wraps them is rewritten.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Header {
+    pub version: u32,
+    /// Features that builds which do not know them can safely ignore.
+    #[serde(default)]
+    pub compatible_features: u64,
+    /// Features that a build must support to open the vault at all.
+    #[serde(default)]
+    pub required_features: u64,
+    /// What objects are encrypted with. Key slots are always sealed with
+    /// XChaCha20-Poly1305.
+    #[serde(default)]
+    pub cipher: Cipher,
+    /// Random identifier that binds key slots to this vault.
+    #[serde(with = "hex")]
+    pub vault_id: Vec<u8>,
+    /// Every slot wraps the same keyring under a different secret.
+    pub slots: Vec<KeySlot>,
+}
+
+/// What kind of secret a key slot is opened with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SlotKind {
+    Passphrase,
+    /// The contents of a file, such as one kept on a USB stick.
+    Keyfile,
+    /// A random key printed for the user to keep, see `RecoveryKey`.
+    Recovery,
+    /// An X25519 public key, opened with the matching identity file.
+    Recipient,
+}
+
+impl fmt::Display for SlotKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SlotKind::Passphrase => "passphrase",
+            SlotKind::Keyfile => "keyfile",
+            SlotKind::Recovery => "recovery key",
+            SlotKind::Recipient => "identity",
+        })
+    }
+}
+
+/// A copy of the master keys that can be opened with one secret.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KeySlot {
+    /// Stable number of the slot, kept when other slots are removed.
+    pub index: u32,
+    pub label: String,
+    pub kind: SlotKind,
+    #[serde(flatten)]
+    pub key: WrappedKey,
+}
+
+/// The keyring encrypted under a key derived from a secret.
+///
+/// Recipient slots have no KDF parameters. Their salt holds the ephemeral
+/// public key the key-encryption key is agreed with instead, and they name
+/// their recipient, so that new keys can be wrapped to it without its
+/// identity.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WrappedKey {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kdf: Option<KdfParams>,
+    /// The recipient of a recipient slot. Slots added before recipients were
+    /// recorded have none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recipient: Option<Recipient>,
+    #[serde(with = "hex")]
+    pub salt: Vec<u8>,
+    #[serde(with = "hex")]
+    pub nonce: Vec<u8>,
+    #[serde(with = "hex")]
+    pub wrapped_key: Vec<u8>,
+}
+
+impl WrappedKey {
+    /// Wraps `keyring` under `secret` with a fresh salt.
+    pub fn wrap(
+        keyring: &Keyring,
+        secret: &[u8],
+        params: &KdfParams,
+        vault_id: &[u8],
+    ) -> io::Result<Self> {
+        let salt = crypto::random_bytes::<SALT_LEN>()?;
+        let kek = SecretBytes::new(crypto::derive_key(secret, &salt, params)?);
+        Self::seal(
+            keyring,
+            kek.as_bytes(),
+            Some(*params),
+            salt.to_vec(),
+            vault_id,
+        )
+    }
+
+    /// Wraps `keyring` to `recipient`, so that only its identity opens it.
+    pub fn wrap_to(keyring: &Keyring, recipient: &Recipient, vault_id: &[u8]) -> io::Result<Self> {
+        let (ephemeral_public, kek) = recipient::wrap_kek(recipient)?;
+        Ok(Self {
+            recipient: Some(*recipient),
+            ..Self::seal(
+                keyring,
+                kek.as_bytes(),
+                None,
+                ephemeral_public.to_vec(),
+                vault_id,
+            )?
+        })
+    }
+
+    /// Wraps `keyring` under the same secret as this slot, given the key it
+    /// derives to.
+    pub fn rewrap(
+        &self,
+        keyring: &Keyring,
+        kek: &[u8; KEY_LEN],
+        vault_id: &[u8],
+    ) -> io::Result<Self> {
+        Ok(Self {
+            recipient: self.recipient,
+            ..Self::seal(keyring, kek, self.kdf, self.salt.clone(), vault_id)?
+        })
+    }
+
+    /// Derives the key-encryption key of this slot from `secret`, which is
+    /// the private key of an identity for recipient slots.
+    pub fn derive_kek(&self, secret: &[u8]) -> io::Result<SecretBytes<KEY_LEN>> {
+        match &self.kdf {
+            Some(params) => Ok(SecretBytes::new(crypto::derive_key(
+                secret, &self.salt, params,
+            )?)),
+            None => recipient::unwrap_kek(secret, &self.salt),
+        }
+    }
+
+    /// Recovers the keyring with a key from `derive_kek`, failing with
+    /// `PermissionDenied` if it was derived from the wrong secret.
+    pub fn open(&self, kek: &[u8; KEY_LEN], vault_id: &[u8]) -> io::Result<Keyring> {
+        let keyring = Zeroizing::new(crypto::open(
+            kek,
+            &slot_aad(vault_id),
+            &self.nonce,
+            &self.wrapped_key,
+        )?);
+        Keyring::from_bytes(&keyring)
+    }
+
+    fn seal(
+        keyring: &Keyring,
+        kek: &[u8; KEY_LEN],
+        kdf: Option<KdfParams>,
+        salt: Vec<u8>,
+        vault_id: &[u8],
+    ) -> io::Result<Self> {
+        let (nonce, wrapped_key) = crypto::seal(kek, &slot_aad(vault_id), &keyring.to_bytes())?;
+        Ok(Self {
+            kdf,
+            recipient: None,
+            salt,
+            nonce: nonce.to_vec(),
+            wrapped_key,
+        })
+    }
+}
+
+impl Header {
+    /// Returns whether `root_dir` contains a vault header.
+    pub fn exists(root_dir: &Path) -> bool {
+        root_dir.join(HEADER_FILE_NAME).exists() || root_dir.join(HEADER_BACKUP_NAME).exists()
+    }
+
+    /// Reads the header of the vault at `root_dir`.
+    ///
+    /// If a previous write was interrupted after the old header was moved
+    /// aside, the backup is read instead.
+    pub fn read(root_dir: &Path) -> io::Result<Self> {
+        let contents = match fs::read(root_dir.join(HEADER_FILE_NAME)) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                match fs::read(root_dir.join(HEADER_BACKUP_NAME)) {
+                    Ok(contents) => {
+                        warn!("Vault header is missing, using its backup");
+                        contents
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::NotFound,
+                            format!(
+                                "'{}' is not a vylfs vault, run 'vylfs init' first",
+                                Redacted::path(root_dir)
+                            ),
+                        ));
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+            Err(err) => return Err(err),
+        };
+
+        let header: Self = serde_json::from_slice(&contents)?;
+        header.check_supported()?;
+        for slot in &header.slots {
+            if let Some(params) = &slot.key.kdf {
+                params.check()?;
+            }
+        }
+        Ok(header)
+    }
+
+    /// Fails with `Unsupported` if the header was written by a newer build,
+    /// naming the version or the required features this build does not know.
+    pub fn check_supported(&self) -> io::Result<()> {
+        if !(OLDEST_FORMAT_VERSION..=FORMAT_VERSION).contains(&self.version) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "unsupported vault format version {}, this build supports versions {} to {}",
+                    self.version, OLDEST_FORMAT_VERSION, FORMAT_VERSION
+                ),
+            ));
+        }
+        let unknown = self.required_features & !KNOWN_REQUIRED_FEATURES;
+        if unknown != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "the vault requires features unknown to this build ({unknown:#x}), a newer \
+                     version of vylfs is needed"
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns whether the vault is in the format written by this build, with
+    /// all of its default features.
+    pub fn is_current(&self) -> bool {
+        self.version == FORMAT_VERSION
+            && self.required_features & DEFAULT_REQUIRED_FEATURES == DEFAULT_REQUIRED_FEATURES
+    }
+
+    /// Replaces the header of the vault at `root_dir` atomically.
+    ///
+    /// The new header is written to a temporary file and renamed over the old
+    /// one. A copy of the old header is kept until the rename is durable, so an
+    /// interrupted write never leaves the vault without a readable header.
+    pub fn write(&self, root_dir: &Path) -> io::Result<()> {
+        let header_path = root_dir.join(HEADER_FILE_NAME);
+        let backup_path = root_dir.join(HEADER_BACKUP_NAME);
+        let temp_path = root_dir.join(HEADER_TEMP_NAME);
+
+        let mut file = create_private(&temp_path)?;
+        serde_json::to_writer_pretty(&mut file, self)?;
+        file.write_all(b"\n")?;
+        file.sync_all()?;
+
+        let has_header = header_path.exists();
+        if has_header {
+            fs::copy(&header_path, &backup_path)?;
+            File::open(&backup_path)?.sync_all()?;
+        }
+        fs::rename(&temp_path, &header_path)?;
+        sync_dir(root_dir)?;
+
+        if has_header {
+            fs::remove_file(&backup_path)?;
+            sync_dir(root_dir)?;
+        }
+        Ok(())
+    }
+}
+
+fn slot_aad(vault_id: &[u8]) -> Vec<u8> {
+    [KEY_SLOT_AAD, vault_id].concat()
+}
+
+fn create_private(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)
+}
+
+/// Makes a rename or removal in `dir` durable.
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+/// Serializes byte fields as lowercase hex strings.
+mod hex {
+    use data_encoding::HEXLOWER_PERMISSIVE;
+    use serde::Deserialize;
+    use serde::Deserializer;
+    use serde::Serializer;
+    use serde::de::Error;
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&HEXLOWER_PERMISSIVE.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        HEXLOWER_PERMISSIVE
+            .decode(encoded.as_bytes())
+            .map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::vault::MasterKey;
+    use crate::vault::recipient::Identity;
+    use crate::vault::tests::TEST_PARAMS;
+
+    fn unwrap(key: &WrappedKey, secret: &[u8], vault_id: &[u8]) -> io::Result<Keyring> {
+        key.open(key.derive_kek(secret)?.as_bytes(), vault_id)
+    }
+
+    fn test_header() -> io::Result<(Header, Keyring)> {
+        let keyring = Keyring::new(0, MasterKey::generate()?);
+        let vault_id = vec![7; 16];
+        let slot = KeySlot {
+            index: 0,
+            label: "personal".to_string(),
+            kind: SlotKind::Passphrase,
+            key: WrappedKey::wrap(&keyring, b"old", &TEST_PARAMS, &vault_id)?,
+        };
+        let header = Header {
+            version: FORMAT_VERSION,
+            compatible_features: 0,
+            required_features: DEFAULT_REQUIRED_FEATURES,
+            cipher: Cipher::default(),
+            vault_id,
+            slots: vec![slot],
+        };
+        Ok((header, keyring))
+    }
+
+    #[test]
+    fn test_wrapped_key_unwrap() -> io::Result<()> {
+        let (header, keyring) = test_header()?;
+        let key = &header.slots[0].key;
+
+        assert_eq!(unwrap(key, b"old", &header.vault_id)?, keyring);
+
+        let result = unwrap(key, b"wrong", &header.vault_id);
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+
+        // A slot copied from another vault does not open this one.
+        let result = unwrap(key, b"old", &[8; 16]);
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rewrap_keeps_secret() -> io::Result<()> {
+        let (header, mut keyring) = test_header()?;
+        let key = &header.slots[0].key;
+        keyring.insert(1, MasterKey::generate()?);
+
+        let kek = key.derive_kek(b"old")?;
+        let rewrapped = key.rewrap(&keyring, kek.as_bytes(), &header.vault_id)?;
+        assert_eq!(rewrapped.salt, key.salt);
+        assert_ne!(rewrapped.nonce, key.nonce);
+        assert_eq!(unwrap(&rewrapped, b"old", &header.vault_id)?, keyring);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_recipient_slot_unwrap() -> io::Result<()> {
+        let (header, keyring) = test_header()?;
+        let identity = Identity::generate()?;
+        let key = WrappedKey::wrap_to(&keyring, &identity.recipient(), &header.vault_id)?;
+        assert_eq!(key.kdf, None);
+
+        assert_eq!(
+            unwrap(&key, identity.as_bytes(), &header.vault_id)?,
+            keyring
+        );
+        let other = Identity::generate()?;
+        let result = unwrap(&key, other.as_bytes(), &header.vault_id);
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+
+        let json = serde_json::to_value(&key)?;
+        assert!(json.get("kdf").is_none());
+        assert_eq!(json["recipient"], identity.recipient().to_string());
+        let parsed: WrappedKey = serde_json::from_value(json)?;
+        assert_eq!(parsed.recipient, Some(identity.recipient()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_replaces_header_and_removes_backup() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let (mut header, keyring) = test_header()?;
+        header.write(temp_dir.path())?;
+
+        header.slots[0].key = WrappedKey::wrap(&keyring, b"new", &TEST_PARAMS, &header.vault_id)?;
+        header.write(temp_dir.path())?;
+
+        assert_eq!(Header::read(temp_dir.path())?, header);
+        assert!(!temp_dir.path().join(HEADER_BACKUP_NAME).exists());
+        assert!(!temp_dir.path().join(HEADER_TEMP_NAME).exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_falls_back_to_backup() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let (header, _) = test_header()?;
+        header.write(temp_dir.path())?;
+        fs::rename(
+            temp_dir.path().join(HEADER_FILE_NAME),
+            temp_dir.path().join(HEADER_BACKUP_NAME),
+        )?;
+
+        assert!(Header::exists(temp_dir.path()));
+        assert_eq!(Header::read(temp_dir.path())?, header);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_slot_json_layout() -> io::Result<()> {
+        let (header, _) = test_header()?;
+
+        let json = serde_json::to_value(&header.slots[0])?;
+        assert_eq!(json["index"], 0);
+        assert_eq!(json["kind"], "passphrase");
+        assert_eq!(json["kdf"]["memory_kib"], TEST_PARAMS.memory_kib);
+        assert!(json["wrapped_key"].is_string());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_checks_version_and_features() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let (mut header, _) = test_header()?;
+
+        // Headers written before the feature bitmaps read as format 1.
+        let mut json = serde_json::to_value(&header)?;
+        json["version"] = 1.into();
+        json.as_object_mut()
+            .expect("header is an object")
+            .retain(|key, _| !key.ends_with("_features"));
+        fs::write(
+            temp_dir.path().join(HEADER_FILE_NAME),
+            serde_json::to_vec(&json)?,
+        )?;
+        let old = Header::read(temp_dir.path())?;
+        assert_eq!(old.required_features, 0);
+        assert!(!old.is_current());
+
+        header.compatible_features = 1 << 63;
+        header.write(temp_dir.path())?;
+        assert!(Header::read(temp_dir.path())?.is_current());
+
+        for (version, required_features) in [(FORMAT_VERSION + 1, 0), (FORMAT_VERSION, 1 << 63)] {
+            header.version = version;
+            header.required_features = required_features;
+            header.write(temp_dir.path())?;
+            let result = Header::read(temp_dir.path());
+            assert!(result.is_err(), "Expected an error, but got {:?}", result);
+            assert_eq!(result.unwrap_err().kind(), io::ErrorKind::Unsupported);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_refuses_costly_kdf_params() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let (mut header, _) = test_header()?;
+        let params = header.slots[0]
+            .key
+            .kdf
+            .expect("passphrase slot has KDF parameters");
+
+        for costly in [
+            KdfParams {
+                memory_kib: u32::MAX,
+                ..params
+            },
+            KdfParams {
+                iterations: 17,
+                ..params
+            },
+            KdfParams {
+                parallelism: 17,
+                ..params
+            },
+        ] {
+            header.slots[0].key.kdf = Some(costly);
+            header.write(temp_dir.path())?;
+            let result = Header::read(temp_dir.path());
+            assert!(result.is_err(), "Expected an error, but got {:?}", result);
+            assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_missing_header() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+
+        let result = Header::read(temp_dir.path());
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::NotFound);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,1051 @@
+pub mod crypto;
+pub mod epoch;
+pub mod header;
+pub mod passphrase;
+pub mod recipient;
+pub mod recovery;
+pub mod rekey;
+pub mod secret;
+pub mod shamir;
+pub mod store;
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+use std::path::PathBuf;
+
+use data_encoding::HEXLOWER_PERMISSIVE;
+use serde::Deserialize;
+use serde::Deserializer;
+use serde::Serialize;
+use serde::Serializer;
+use serde::de::Error;
+use tracing::info;
+use tracing::warn;
+use zeroize::Zeroizing;
+
+use crate::vault::crypto::Cipher;
+use crate::vault::crypto::KEY_LEN;
+use crate::vault::crypto::KdfParams;
+use crate::vault::header::DEFAULT_REQUIRED_FEATURES;
+use crate::vault::header::FEATURE_CIPHER_CHOICE;
+use crate::vault::header::FORMAT_VERSION;
+use crate::vault::header::Header;
+use crate::vault::header::KeySlot;
+use crate::vault::header::SlotKind;
+use crate::vault::header::WrappedKey;
+use crate::vault::recipient::Identity;
+use crate::vault::recipient::Recipient;
+use crate::vault::recovery::RecoveryKey;
+use crate::vault::rekey::RekeyProgress;
+use crate::vault::secret::SecretBytes;
+
+/// Length of the random vault identifier.
+const VAULT_ID_LEN: usize = 16;
+
+/// Keyfiles are read whole, so their size is capped.
+const MAX_KEYFILE_LEN: u64 = 1024 * 1024;
+
+/// Label of the key slot that `vylfs init` adds for the recovery key.
+pub const RECOVERY_SLOT_LABEL: &str = "recovery";
+
+/// The key all vault contents are encrypted under.
+#[derive(Clone, PartialEq, Eq)]
+pub struct MasterKey(SecretBytes<KEY_LEN>);
+
+impl MasterKey {
+    pub fn generate() -> io::Result<Self> {
+        Ok(Self(SecretBytes::random()?))
+    }
+
+    pub fn from_bytes(bytes: [u8; KEY_LEN]) -> Self {
+        Self(SecretBytes::new(bytes))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; KEY_LEN] {
+        self.0.as_bytes()
+    }
+}
+
+impl fmt::Debug for MasterKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("MasterKey(..)")
+    }
+}
+
+/// Written as hex, so a new key can be handed to a running daemon.
+impl Serialize for MasterKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&Zeroizing::new(HEXLOWER_PERMISSIVE.encode(self.as_bytes())))
+    }
+}
+
+impl<'de> Deserialize<'de> for MasterKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let encoded = Zeroizing::new(String::deserialize(deserializer)?);
+        let key = Zeroizing::new(
+            HEXLOWER_PERMISSIVE
+                .decode(encoded.as_bytes())
+                .map_err(D::Error::custom)?,
+        );
+        let key: [u8; KEY_LEN] = key[..]
+            .try_into()
+            .map_err(|_| D::Error::custom("master key has wrong length"))?;
+        Ok(Self::from_bytes(key))
+    }
+}
+
+/// The master keys of a vault by generation.
+///
+/// Every object records the generation it was encrypted under. The keyring
+/// holds more than one key only while `rekey` moves objects to the newest one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Keyring {
+    keys: BTreeMap<u32, MasterKey>,
+}
+
+/// Length of one serialized keyring entry: the generation and its key.
+const KEYRING_ENTRY_LEN: usize = 4 + KEY_LEN;
+
+impl Keyring {
+    pub fn new(generation: u32, key: MasterKey) -> Self {
+        Self {
+            keys: BTreeMap::from([(generation, key)]),
+        }
+    }
+
+    /// The generation new objects are encrypted under.
+    pub fn current_generation(&self) -> u32 {
+        *self
+            .keys
+            .keys()
+            .next_back()
+            .expect("keyring is never empty")
+    }
+
+    pub fn current(&self) -> &MasterKey {
+        &self.keys[&self.current_generation()]
+    }
+
+    pub fn get(&self, generation: u32) -> Option<&MasterKey> {
+        self.keys.get(&generation)
+    }
+
+    pub fn insert(&mut self, generation: u32, key: MasterKey) {
+        self.keys.insert(generation, key);
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// A keyring with only the current key, for once no object uses an older
+    /// one anymore.
+    pub fn retain_current(&self) -> Self {
+        Self::new(self.current_generation(), self.current().clone())
+    }
+
+    pub fn to_bytes(&self) -> Zeroizing<Vec<u8>> {
+        let mut bytes = Zeroizing::new(Vec::with_capacity(self.keys.len() * KEYRING_ENTRY_LEN));
+        for (generation, key) in &self.keys {
+            bytes.extend_from_slice(&generation.to_le_bytes());
+            bytes.extend_from_slice(key.as_bytes());
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        if bytes.is_empty() || !bytes.len().is_multiple_of(KEYRING_ENTRY_LEN) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "wrapped keyring has wrong length",
+            ));
+        }
+        let keys = bytes
+            .chunks(KEYRING_ENTRY_LEN)
+            .map(|entry| {
+                let (generation, key) = entry.split_at(4);
+                (
+                    u32::from_le_bytes(generation.try_into().expect("length was checked")),
+                    MasterKey::from_bytes(key.try_into().expect("length was checked")),
+                )
+            })
+            .collect();
+        Ok(Self { keys })
+    }
+}
+
+/// Written as hex like `MasterKey`, so that `vylfs unlock` can hand the keys
+/// back to a locked daemon.
+impl Serialize for Keyring {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&Zeroizing::new(
+            HEXLOWER_PERMISSIVE.encode(&self.to_bytes()),
+        ))
+    }
+}
+
+impl<'de> Deserialize<'de> for Keyring {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let encoded = Zeroizing::new(String::deserialize(deserializer)?);
+        let bytes = Zeroizing::new(
+            HEXLOWER_PERMISSIVE
+                .decode(encoded.as_bytes())
+                .map_err(D::Error::custom)?,
+        );
+        Self::from_bytes(&bytes).map_err(D::Error::custom)
+    }
+}
+
+/// A secret that opens one kind of key slot.
+pub enum Credential {
+    Passphrase(Zeroizing<String>),
+    Keyfile(Zeroizing<Vec<u8>>),
+    Recovery(RecoveryKey),
+    /// The private key of a recipient the vault was shared with.
+    Identity(Identity),
+}
+
+impl Credential {
+    /// Reads a keyfile, which may hold up to `MAX_KEYFILE_LEN` bytes of any
+    /// content.
+    pub fn from_keyfile(path: &Path) -> io::Result<Self> {
+        let mut contents = Zeroizing::new(Vec::new());
+        File::open(path)?
+            .take(MAX_KEYFILE_LEN + 1)
+            .read_to_end(&mut contents)?;
+        if contents.is_empty() || contents.len() as u64 > MAX_KEYFILE_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "keyfile '{}' must hold between 1 and {MAX_KEYFILE_LEN} bytes",
+                    path.display()
+                ),
+            ));
+        }
+        Ok(Self::Keyfile(contents))
+    }
+
+    pub fn kind(&self) -> SlotKind {
+        match self {
+            Credential::Passphrase(_) => SlotKind::Passphrase,
+            Credential::Keyfile(_) => SlotKind::Keyfile,
+            Credential::Recovery(_) => SlotKind::Recovery,
+            Credential::Identity(_) => SlotKind::Recipient,
+        }
+    }
+
+    fn secret(&self) -> &[u8] {
+        match self {
+            Credential::Passphrase(passphrase) => passphrase.as_bytes(),
+            Credential::Keyfile(contents) => contents,
+            Credential::Recovery(key) => key.as_bytes(),
+            Credential::Identity(identity) => identity.as_bytes(),
+        }
+    }
+}
+
+impl fmt::Debug for Credential {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Credential::{:?}(..)", self.kind())
+    }
+}
+
+/// An unlocked vault, holding its header and master keys.
+pub struct Vault {
+    root_dir: PathBuf,
+    header: Header,
+    keyring: Keyring,
+    /// Index of the slot that was opened to unlock the vault.
+    unlocked_slot: u32,
+    /// The key derived from the credential that opened `unlocked_slot`, so the
+    /// slot can be rewrapped when the keyring changes.
+    slot_kek: SecretBytes<KEY_LEN>,
+}
+
+impl fmt::Debug for Vault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Vault")
+            .field("root_dir", &self.root_dir)
+            .field("header", &self.header)
+            .field("keyring", &self.keyring)
+            .field("unlocked_slot", &self.unlocked_slot)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Vault {
+    /// Creates a new vault in `root_dir` with a random master key and a single
+    /// key slot for `credential`, encrypted with the default cipher.
+    #[cfg(test)]
+    pub fn init(
+        root_dir: &Path,
+        credential: &Credential,
+        label: &str,
+        params: &KdfParams,
+    ) -> io::Result<Self> {
+        Self::init_with_cipher(root_dir, credential, label, params, Cipher::default())
+    }
+
+    /// Creates a new vault in `root_dir` with a random master key and a single
+    /// key slot for `credential`, whose objects are encrypted with `cipher`.
+    pub fn init_with_cipher(
+        root_dir: &Path,
+        credential: &Credential,
+        label: &str,
+        params: &KdfParams,
+        cipher: Cipher,
+    ) -> io::Result<Self> {
+        if Header::exists(root_dir) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("'{}' already contains a vault", root_dir.display()),
+            ));
+        }
+        validate_label(label)?;
+
+        let vault_id = crypto::random_bytes::<VAULT_ID_LEN>()?.to_vec();
+        let keyring = Keyring::new(0, MasterKey::generate()?);
+        let key = WrappedKey::wrap(&keyring, credential.secret(), params, &vault_id)?;
+        let slot_kek = key.derive_kek(credential.secret())?;
+        let slot = KeySlot {
+            index: 0,
+            label: label.to_string(),
+            kind: credential.kind(),
+            key,
+        };
+        let mut required_features = DEFAULT_REQUIRED_FEATURES;
+        if cipher != Cipher::default() {
+            required_features |= FEATURE_CIPHER_CHOICE;
+        }
+        let header = Header {
+            version: FORMAT_VERSION,
+            compatible_features: 0,
+            required_features,
+            cipher,
+            vault_id,
+            slots: vec![slot],
+        };
+        header.write(root_dir)?;
+        info!("Initialized vault");
+
+        Ok(Self {
+            root_dir: root_dir.to_path_buf(),
+            header,
+            keyring,
+            unlocked_slot: 0,
+            slot_kek,
+        })
+    }
+
+    /// Opens the vault at `root_dir` with the first slot of the credential's
+    /// kind that it unwraps, failing with `PermissionDenied` if there is none.
+    pub fn unlock(root_dir: &Path, credential: &Credential) -> io::Result<Self> {
+        let header = Header::read(root_dir)?;
+        let kind = credential.kind();
+
+        for slot in header.slots.iter().filter(|slot| slot.kind == kind) {
+            let slot_kek = match slot.key.derive_kek(credential.secret()) {
+                Ok(slot_kek) => slot_kek,
+                Err(err) => {
+                    warn!("Skipping key slot {}: {}", slot.index, err);
+                    continue;
+                }
+            };
+            match slot.key.open(slot_kek.as_bytes(), &header.vault_id) {
+                Ok(keyring) => {
+                    info!("Unlocked vault with key slot {}", slot.index);
+                    let unlocked_slot = slot.index;
+                    return Ok(Self {
+                        root_dir: root_dir.to_path_buf(),
+                        header,
+                        keyring,
+                        unlocked_slot,
+                        slot_kek,
+                    });
+                }
+                Err(err) if err.kind() == io::ErrorKind::PermissionDenied => continue,
+                Err(err) => return Err(err),
+            }
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("the {kind} does not open any key slot"),
+        ))
+    }
+
+    /// Lists the key slots of the vault at `root_dir` without unlocking it.
+    pub fn slots(root_dir: &Path) -> io::Result<Vec<KeySlot>> {
+        Ok(Header::read(root_dir)?.slots)
+    }
+
+    pub fn root_dir(&self) -> &Path {
+        &self.root_dir
+    }
+
+    /// The vault's random identifier, as hex.
+    pub fn id(&self) -> String {
+        HEXLOWER_PERMISSIVE.encode(&self.header.vault_id)
+    }
+
+    pub fn keyring(&self) -> &Keyring {
+        &self.keyring
+    }
+
+    /// The format version of the vault, see `FORMAT_VERSION`.
+    pub fn format_version(&self) -> u32 {
+        self.header.version
+    }
+
+    /// The cipher that the vault's objects are encrypted with.
+    pub fn cipher(&self) -> Cipher {
+        self.header.cipher
+    }
+
+    /// Fails unless the vault is in the format written by this build, which
+    /// older vaults must be upgraded to before they are mounted.
+    pub fn check_format(&self) -> io::Result<()> {
+        if self.header.is_current() {
+            return Ok(());
+        }
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "the vault is in format version {}, run 'vylfs upgrade' on it to migrate it to \
+                 version {FORMAT_VERSION}",
+                self.header.version
+            ),
+        ))
+    }
+
+    /// Records that the vault was migrated to the format of this build, once
+    /// its objects were.
+    pub fn finish_upgrade(&mut self) -> io::Result<()> {
+        let mut header = self.header.clone();
+        header.version = FORMAT_VERSION;
+        header.required_features |= DEFAULT_REQUIRED_FEATURES;
+        header.write(&self.root_dir)?;
+        info!("Upgraded vault to format version {FORMAT_VERSION}");
+        self.header = header;
+        Ok(())
+    }
+
+    /// The keys to wrap in a slot. Retired keys are dropped from slots as soon
+    /// as no rekey needs them anymore.
+    fn slot_keyring(&self) -> io::Result<Keyring> {
+        if RekeyProgress::read(&self.root_dir)?.is_some() {
+            Ok(self.keyring.clone())
+        } else {
+            Ok(self.keyring.retain_current())
+        }
+    }
+
+    /// Starts a rekey: adds a new master key generation and writes the progress
+    /// record that `rekey::run` works through. Returns the key slots that had
+    /// to be removed.
+    ///
+    /// The slot that unlocked the vault and recipient slots are wrapped with
+    /// the new key. The secrets of the other slots are unknown, so they would
+    /// keep opening the old key only. Unless `drop_slots` is set, the rekey is
+    /// refused while there are any, otherwise they are removed. If a rekey is
+    /// already in progress it is resumed instead and nothing is removed.
+    pub fn begin_rekey(&mut self, drop_slots: bool) -> io::Result<Vec<KeySlot>> {
+        let target = self.keyring.current_generation();
+        match RekeyProgress::read(&self.root_dir)? {
+            Some(progress) if progress.generation == target => {
+                info!("Resuming rekey to generation {}", target);
+                return Ok(Vec::new());
+            }
+            Some(progress) => {
+                warn!(
+                    "Discarding rekey record for generation {}, which never reached the header",
+                    progress.generation
+                );
+            }
+            None if self.keyring.len() > 1 => {
+                info!("Resuming rekey to generation {}", target);
+                RekeyProgress::new(target).write(&self.root_dir)?;
+                return Ok(Vec::new());
+            }
+            None => {}
+        }
+
+        let mut header = self.header.clone();
+        let (kept, removed): (Vec<KeySlot>, Vec<KeySlot>) = header
+            .slots
+            .into_iter()
+            .partition(|slot| slot.index == self.unlocked_slot || slot.key.recipient.is_some());
+        if !removed.is_empty() && !drop_slots {
+            let labels: Vec<String> = removed
+                .iter()
+                .map(|slot| format!("'{}'", slot.label))
+                .collect();
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "the secrets of the key slots {} are unknown, so they cannot open the new \
+                     key, pass --drop-slots to remove them and add them again afterwards",
+                    labels.join(", ")
+                ),
+            ));
+        }
+
+        let generation = target + 1;
+        let mut keyring = self.keyring.clone();
+        keyring.insert(generation, MasterKey::generate()?);
+        header.slots = kept;
+        self.wrap_slots(&mut header, &keyring)?;
+
+        // The record goes first, so the slots keep the old key until the
+        // objects no longer need it, however the rekey is interrupted.
+        RekeyProgress::new(generation).write(&self.root_dir)?;
+        header.write(&self.root_dir)?;
+        self.header = header;
+        self.keyring = keyring;
+        info!("Started rekey to generation {}", generation);
+        Ok(removed)
+    }
+
+    /// Drops retired keys from the slot that unlocked the vault once every
+    /// object was re-encrypted.
+    pub fn finish_rekey(&mut self) -> io::Result<()> {
+        if RekeyProgress::read(&self.root_dir)?.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::ResourceBusy,
+                "a rekey is still in progress",
+            ));
+        }
+
+        let keyring = self.keyring.retain_current();
+        let mut header = self.header.clone();
+        self.wrap_slots(&mut header, &keyring)?;
+        header.write(&self.root_dir)?;
+        self.header = header;
+        self.keyring = keyring;
+        info!(
+            "Finished rekey to generation {}",
+            self.keyring.current_generation()
+        );
+        Ok(())
+    }
+
+    /// Wraps `keyring` in the slot that unlocked the vault and in the recipient
+    /// slots, which are wrapped to their recipient again. Other slots are left
+    /// as they are.
+    fn wrap_slots(&self, header: &mut Header, keyring: &Keyring) -> io::Result<()> {
+        for slot in &mut header.slots {
+            slot.key = if slot.index == self.unlocked_slot {
+                slot.key
+                    .rewrap(keyring, self.slot_kek.as_bytes(), &header.vault_id)?
+            } else if let Some(recipient) = &slot.key.recipient {
+                WrappedKey::wrap_to(keyring, recipient, &header.vault_id)?
+            } else {
+                continue;
+            };
+        }
+        Ok(())
+    }
+
+    /// Finishes a rekey whose objects were all re-encrypted, but that was
+    /// never finished, such as one run by a daemon. Returns whether there was
+    /// one.
+    pub fn finish_completed_rekey(&mut self) -> io::Result<bool> {
+        if self.keyring.len() < 2 || RekeyProgress::read(&self.root_dir)?.is_some() {
+            return Ok(false);
+        }
+        self.finish_rekey()?;
+        Ok(true)
+    }
+
+    /// Rewraps the master key in the slot that unlocked the vault under a new
+    /// passphrase with a fresh salt and `params`, leaving all encrypted data
+    /// untouched.
+    pub fn change_passphrase(&mut self, passphrase: &str, params: &KdfParams) -> io::Result<()> {
+        let mut header = self.header.clone();
+        let slot = header
+            .slots
+            .iter_mut()
+            .find(|slot| slot.index == self.unlocked_slot)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "key slot was removed"))?;
+        if slot.kind != SlotKind::Passphrase {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("key slot {} is not a passphrase slot", slot.index),
+            ));
+        }
+        slot.key = WrappedKey::wrap(
+            &self.slot_keyring()?,
+            passphrase.as_bytes(),
+            params,
+            &header.vault_id,
+        )?;
+        let slot_kek = slot.key.derive_kek(passphrase.as_bytes())?;
+
+        header.write(&self.root_dir)?;
+        self.header = header;
+        self.slot_kek = slot_kek;
+        info!("Changed passphrase of key slot {}", self.unlocked_slot);
+        Ok(())
+    }
+
+    /// Adds a key slot for `credential` under the lowest free index, which is
+    /// returned.
+    pub fn add_slot(
+        &mut self,
+        label: &str,
+        credential: &Credential,
+        params: &KdfParams,
+    ) -> io::Result<u32> {
+        self.check_label(label)?;
+        let key = WrappedKey::wrap(
+            &self.slot_keyring()?,
+            credential.secret(),
+            params,
+            &self.header.vault_id,
+        )?;
+        self.push_slot(label, credential.kind(), key)
+    }
+
+    /// Adds a key slot wrapped to `recipient`, which its identity opens
+    /// without anyone sharing a passphrase. Returns the index of the slot.
+    pub fn add_recipient(&mut self, label: &str, recipient: &Recipient) -> io::Result<u32> {
+        self.check_label(label)?;
+        let key = WrappedKey::wrap_to(&self.slot_keyring()?, recipient, &self.header.vault_id)?;
+        self.push_slot(label, SlotKind::Recipient, key)
+    }
+
+    fn check_label(&self, label: &str) -> io::Result<()> {
+        validate_label(label)?;
+        if self.header.slots.iter().any(|slot| slot.label == label) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("a key slot labelled '{label}' already exists"),
+            ));
+        }
+        Ok(())
+    }
+
+    fn push_slot(&mut self, label: &str, kind: SlotKind, key: WrappedKey) -> io::Result<u32> {
+        let index = (0..)
+            .find(|index| self.header.slots.iter().all(|slot| slot.index != *index))
+            .expect("slot indices are exhausted");
+        let mut header = self.header.clone();
+        header.slots.push(KeySlot {
+            index,
+            label: label.to_string(),
+            kind,
+            key,
+        });
+        header.slots.sort_by_key(|slot| slot.index);
+
+        header.write(&self.root_dir)?;
+        self.header = header;
+        info!("Added key slot {}", index);
+        Ok(index)
+    }
+
+    /// Removes the key slot with the given index or label, refusing to remove
+    /// the last one.
+    pub fn remove_slot(&mut self, slot: &str) -> io::Result<KeySlot> {
+        let position = self
+            .header
+            .slots
+            .iter()
+            .position(|candidate| candidate.label == slot || slot.parse() == Ok(candidate.index))
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, format!("no key slot '{slot}'"))
+            })?;
+        if self.header.slots.len() == 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "refusing to remove the last key slot, the vault could not be opened anymore",
+            ));
+        }
+
+        let mut header = self.header.clone();
+        let removed = header.slots.remove(position);
+        header.write(&self.root_dir)?;
+        self.header = header;
+        info!("Removed key slot {}", removed.index);
+        Ok(removed)
+    }
+}
+
+/// Fails if `label` cannot name a key slot, as slots are also selected by
+/// their index.
+fn validate_label(label: &str) -> io::Result<()> {
+    if label.is_empty() || label.parse::<u32>().is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "slot labels must not be empty or a number",
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::fs;
+
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::vault::store::Store;
+
+    /// Cheap KDF parameters, so tests do not spend seconds in Argon2.
+    pub const TEST_PARAMS: KdfParams = KdfParams {
+        memory_kib: 64,
+        iterations: 1,
+        parallelism: 1,
+    };
+
+    fn passphrase(passphrase: &str) -> Credential {
+        Credential::Passphrase(passphrase.to_string().into())
+    }
+
+    #[test]
+    fn test_init_and_unlock() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let vault = Vault::init(
+            temp_dir.path(),
+            &passphrase("passphrase"),
+            "personal",
+            &TEST_PARAMS,
+        )?;
+
+        let unlocked = Vault::unlock(temp_dir.path(), &passphrase("passphrase"))?;
+        assert_eq!(unlocked.keyring, vault.keyring);
+
+        let result = Vault::unlock(temp_dir.path(), &passphrase("wrong"));
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_init_records_cipher() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let vault = Vault::init_with_cipher(
+            temp_dir.path(),
+            &passphrase("passphrase"),
+            "personal",
+            &TEST_PARAMS,
+            Cipher::Aes256Gcm,
+        )?;
+        let header = Header::read(temp_dir.path())?;
+        assert_eq!(header.cipher, Cipher::Aes256Gcm);
+        assert_ne!(header.required_features & FEATURE_CIPHER_CHOICE, 0);
+
+        let store = Store::open(temp_dir.path(), vault.keyring().clone())?;
+        store.write("a", 1, b"secret")?;
+        let store =
+            Store::with_cipher(temp_dir.path(), vault.keyring().clone(), Cipher::Aes256Gcm)?;
+        assert_eq!(
+            store.read("a")?.map(|object| object.data),
+            Some(b"secret".to_vec())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_old_format_must_be_upgraded() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let vault = Vault::init(
+            temp_dir.path(),
+            &passphrase("passphrase"),
+            "personal",
+            &TEST_PARAMS,
+        )?;
+        vault.check_format()?;
+
+        let mut header = vault.header.clone();
+        header.version = 1;
+        header.required_features = 0;
+        header.write(temp_dir.path())?;
+        let mut vault = Vault::unlock(temp_dir.path(), &passphrase("passphrase"))?;
+        let result = vault.check_format();
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::Unsupported);
+
+        vault.finish_upgrade()?;
+        let vault = Vault::unlock(temp_dir.path(), &passphrase("passphrase"))?;
+        assert_eq!(vault.format_version(), FORMAT_VERSION);
+        vault.check_format()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_init_refuses_existing_vault() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        Vault::init(temp_dir.path(), &passphrase("a"), "personal", &TEST_PARAMS)?;
+
+        let result = Vault::init(temp_dir.path(), &passphrase("b"), "personal", &TEST_PARAMS);
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_init_checks_label() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        for label in ["", "3"] {
+            let result = Vault::init(temp_dir.path(), &passphrase("a"), label, &TEST_PARAMS);
+            assert!(result.is_err(), "Expected an error, but got {:?}", result);
+            assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+            assert!(!Header::exists(temp_dir.path()));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_change_passphrase_keeps_master_key() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let mut vault = Vault::init(
+            temp_dir.path(),
+            &passphrase("old"),
+            "personal",
+            &TEST_PARAMS,
+        )?;
+        let old_salt = vault.header.slots[0].key.salt.clone();
+
+        vault.change_passphrase("new", &TEST_PARAMS)?;
+        assert_ne!(vault.header.slots[0].key.salt, old_salt);
+
+        let result = Vault::unlock(temp_dir.path(), &passphrase("old"));
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+        let unlocked = Vault::unlock(temp_dir.path(), &passphrase("new"))?;
+        assert_eq!(unlocked.keyring, vault.keyring);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_and_remove_slots() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let keyfile = temp_dir.path().join("usb.key");
+        fs::write(&keyfile, [0x5a; 64])?;
+        let mut vault = Vault::init(temp_dir.path(), &passphrase("a"), "personal", &TEST_PARAMS)?;
+
+        let keyfile_slot =
+            vault.add_slot("usb", &Credential::from_keyfile(&keyfile)?, &TEST_PARAMS)?;
+        let second_slot = vault.add_slot("spare", &passphrase("b"), &TEST_PARAMS)?;
+        assert_eq!((keyfile_slot, second_slot), (1, 2));
+
+        let unlocked = Vault::unlock(temp_dir.path(), &Credential::from_keyfile(&keyfile)?)?;
+        assert_eq!(unlocked.unlocked_slot, 1);
+        let unlocked = Vault::unlock(temp_dir.path(), &passphrase("b"))?;
+        assert_eq!(unlocked.unlocked_slot, 2);
+
+        // A passphrase never opens a keyfile slot and vice versa.
+        let result = Vault::unlock(temp_dir.path(), &Credential::Keyfile(b"b".to_vec().into()));
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+
+        vault.remove_slot("0")?;
+        vault.remove_slot("usb")?;
+        let result = Vault::unlock(temp_dir.path(), &passphrase("a"));
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+
+        // Freed indices are reused.
+        assert_eq!(vault.add_slot("again", &passphrase("c"), &TEST_PARAMS)?, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unlock_with_recovery_key() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let mut vault = Vault::init(temp_dir.path(), &passphrase("a"), "personal", &TEST_PARAMS)?;
+        let recovery_key = RecoveryKey::generate()?;
+        let printed = recovery_key.to_string();
+        vault.add_slot(
+            "recovery",
+            &Credential::Recovery(recovery_key),
+            &TEST_PARAMS,
+        )?;
+
+        let credential = Credential::Recovery(printed.parse()?);
+        let unlocked = Vault::unlock(temp_dir.path(), &credential)?;
+        assert_eq!(unlocked.keyring, vault.keyring);
+
+        let other = Credential::Recovery(RecoveryKey::generate()?);
+        let result = Vault::unlock(temp_dir.path(), &other);
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unlock_with_identity() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let mut vault = Vault::init(temp_dir.path(), &passphrase("a"), "personal", &TEST_PARAMS)?;
+        let identity = Identity::generate()?;
+        let index = vault.add_recipient("laptop", &identity.recipient())?;
+
+        let unlocked = Vault::unlock(temp_dir.path(), &Credential::Identity(identity))?;
+        assert_eq!(unlocked.unlocked_slot, index);
+        assert_eq!(unlocked.keyring, vault.keyring);
+
+        let other = Credential::Identity(Identity::generate()?);
+        let result = Vault::unlock(temp_dir.path(), &other);
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+
+        let result = vault.add_recipient("laptop", &Identity::generate()?.recipient());
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unlock_skips_broken_slots() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let mut vault = Vault::init(temp_dir.path(), &passphrase("a"), "personal", &TEST_PARAMS)?;
+        let broken = vault.add_recipient("broken", &Identity::generate()?.recipient())?;
+        let identity = Identity::generate()?;
+        let index = vault.add_recipient("laptop", &identity.recipient())?;
+
+        // A low-order ephemeral key makes deriving the slot's key fail.
+        let mut header = Header::read(temp_dir.path())?;
+        for slot in &mut header.slots {
+            if slot.index == broken {
+                slot.key.salt = vec![0; KEY_LEN];
+            }
+        }
+        header.write(temp_dir.path())?;
+
+        let unlocked = Vault::unlock(temp_dir.path(), &Credential::Identity(identity))?;
+        assert_eq!(unlocked.unlocked_slot, index);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_last_slot_is_refused() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let mut vault = Vault::init(temp_dir.path(), &passphrase("a"), "personal", &TEST_PARAMS)?;
+
+        let result = vault.remove_slot("personal");
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(Vault::slots(temp_dir.path())?.len(), 1);
+
+        let result = vault.remove_slot("7");
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::NotFound);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_slot_rejects_duplicate_label() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let mut vault = Vault::init(temp_dir.path(), &passphrase("a"), "personal", &TEST_PARAMS)?;
+
+        let result = vault.add_slot("personal", &passphrase("b"), &TEST_PARAMS);
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+        let result = vault.add_slot("3", &passphrase("b"), &TEST_PARAMS);
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rekey_keeps_only_unlocking_slot() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let mut vault = Vault::init(temp_dir.path(), &passphrase("a"), "personal", &TEST_PARAMS)?;
+        vault.add_slot("spare", &passphrase("b"), &TEST_PARAMS)?;
+        let old_key = vault.keyring.current().clone();
+
+        // Slots whose secret is unknown are only removed when asked to.
+        let mut vault = Vault::unlock(temp_dir.path(), &passphrase("b"))?;
+        let result = vault.begin_rekey(false);
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+        assert_eq!(Vault::slots(temp_dir.path())?.len(), 2);
+        assert!(RekeyProgress::read(temp_dir.path())?.is_none());
+
+        let removed = vault.begin_rekey(true)?;
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].label, "personal");
+        assert_eq!(vault.keyring.current_generation(), 1);
+        assert_eq!(vault.keyring.get(0), Some(&old_key));
+        assert_eq!(
+            RekeyProgress::read(temp_dir.path())?.map(|progress| progress.generation),
+            Some(1)
+        );
+
+        // While objects still use the old key, slots wrap both keys.
+        let unlocked = Vault::unlock(temp_dir.path(), &passphrase("b"))?;
+        assert_eq!(unlocked.keyring, vault.keyring);
+        let result = Vault::unlock(temp_dir.path(), &passphrase("a"));
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+
+        // Starting again resumes the rekey in progress.
+        assert!(vault.begin_rekey(false)?.is_empty());
+        assert_eq!(vault.keyring.current_generation(), 1);
+
+        let result = vault.finish_rekey();
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+        assert!(!vault.finish_completed_rekey()?);
+        fs::remove_file(temp_dir.path().join(rekey::REKEY_FILE_NAME))?;
+
+        // A rekey that completed while mounted is finished on the next unlock.
+        let mut vault = Vault::unlock(temp_dir.path(), &passphrase("b"))?;
+        assert!(vault.finish_completed_rekey()?);
+        assert!(!vault.finish_completed_rekey()?);
+        let unlocked = Vault::unlock(temp_dir.path(), &passphrase("b"))?;
+        assert_eq!(unlocked.keyring.len(), 1);
+        assert_eq!(unlocked.keyring.current_generation(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rekey_rewraps_recipient_slots() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let mut vault = Vault::init(temp_dir.path(), &passphrase("a"), "personal", &TEST_PARAMS)?;
+        let identity = Identity::generate()?;
+        let recipient = identity.recipient();
+        let identity = Credential::Identity(identity);
+        vault.add_recipient("laptop", &recipient)?;
+
+        let mut vault = Vault::unlock(temp_dir.path(), &passphrase("a"))?;
+        assert!(vault.begin_rekey(false)?.is_empty());
+        let unlocked = Vault::unlock(temp_dir.path(), &identity)?;
+        assert_eq!(unlocked.keyring, vault.keyring);
+
+        fs::remove_file(temp_dir.path().join(rekey::REKEY_FILE_NAME))?;
+        vault.finish_rekey()?;
+        let unlocked = Vault::unlock(temp_dir.path(), &identity)?;
+        assert_eq!(unlocked.keyring.len(), 1);
+        assert_eq!(unlocked.keyring.current_generation(), 1);
+        let slots = Vault::slots(temp_dir.path())?;
+        assert_eq!(slots[1].key.recipient, Some(recipient));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_keyring_bytes_round_trip() -> io::Result<()> {
+        let mut keyring = Keyring::new(3, MasterKey::generate()?);
+        keyring.insert(4, MasterKey::generate()?);
+        assert_eq!(Keyring::from_bytes(&keyring.to_bytes())?, keyring);
+
+        let result = Keyring::from_bytes(&keyring.to_bytes()[1..]);
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+        let result = Keyring::from_bytes(&[]);
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,356 @@
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Write;
+use std::os::fd::AsRawFd;
+use std::os::fd::FromRawFd;
+use std::os::fd::RawFd;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::process::Stdio;
+use std::sync::Mutex;
+
+use tracing::warn;
+use zeroize::Zeroizing;
+
+use crate::vault::Credential;
+use crate::vault::recipient::Identity;
+
+/// Environment variable naming a program that asks for passphrases, for
+/// example in a graphical session without a terminal.
+pub const ASKPASS_VAR: &str = "VYLFS_ASKPASS";
+
+/// Passphrases are never longer than this, which also bounds what is read from
+/// a file or descriptor by mistake.
+const MAX_PASSPHRASE_LEN: u64 = 4096;
+
+/// Where passphrases are read from. Passphrases are never taken from the
+/// command line, where every user could read them in `/proc/*/cmdline`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum PassphraseInput {
+    /// The controlling terminal with echo turned off, or stdin if the process
+    /// has no terminal.
+    #[default]
+    Terminal,
+    /// One line per prompt from an inherited file descriptor.
+    Fd(RawFd),
+    /// One line per prompt from a file.
+    File(PathBuf),
+    /// The output of a program, which is given the prompt as its argument.
+    Askpass(PathBuf),
+}
+
+/// Reads passphrases from a `PassphraseInput`.
+///
+/// Each prompt takes the next line of a descriptor or file, so a command that
+/// asks twice, such as `passwd`, reads the current passphrase from the first
+/// line and the new one from the second.
+#[derive(Debug)]
+pub struct PassphraseReader {
+    input: PassphraseInput,
+    lines: Option<BufReader<File>>,
+}
+
+/// The reader all prompts of this process go through, see `set_input`.
+static READER: Mutex<Option<PassphraseReader>> = Mutex::new(None);
+
+/// Chooses where `prompt` reads passphrases from for the rest of the process.
+pub fn set_input(input: PassphraseInput) {
+    *READER.lock().expect("passphrase reader lock poisoned") = Some(PassphraseReader::new(input));
+}
+
+/// Asks for a passphrase, or reads it from the input chosen with `set_input`.
+pub fn prompt(message: &str) -> io::Result<Zeroizing<String>> {
+    READER
+        .lock()
+        .expect("passphrase reader lock poisoned")
+        .get_or_insert_with(|| PassphraseReader::new(PassphraseInput::Terminal))
+        .read(message)
+}
+
+/// Asks for a new passphrase twice and checks that both entries match. A
+/// passphrase read from a descriptor or file is taken as it is.
+pub fn prompt_new(message: &str) -> io::Result<Zeroizing<String>> {
+    let mut reader = READER.lock().expect("passphrase reader lock poisoned");
+    let reader = reader.get_or_insert_with(|| PassphraseReader::new(PassphraseInput::Terminal));
+    let passphrase = reader.read(message)?;
+    if passphrase.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "passphrase must not be empty",
+        ));
+    }
+    if reader.is_interactive() && *reader.read("Repeat passphrase: ")? != *passphrase {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "passphrases do not match",
+        ));
+    }
+    Ok(passphrase)
+}
+
+/// Where the secret that unlocks a vault comes from.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum CredentialSource {
+    /// Ask for the passphrase.
+    #[default]
+    Passphrase,
+    /// Read the contents of a keyfile.
+    Keyfile(PathBuf),
+    /// Ask for the recovery key printed at `init`.
+    RecoveryKey,
+    /// Read the private key of a recipient from an identity file.
+    Identity(PathBuf),
+}
+
+/// Reads the credential that unlocks the vault at `root_dir` from `source`.
+pub fn credential(root_dir: &Path, source: &CredentialSource) -> io::Result<Credential> {
+    match source {
+        CredentialSource::Passphrase => {
+            let message = format!("Passphrase for '{}': ", root_dir.display());
+            Ok(Credential::Passphrase(prompt(&message)?))
+        }
+        CredentialSource::Keyfile(path) => Credential::from_keyfile(path),
+        CredentialSource::RecoveryKey => {
+            let message = format!("Recovery key for '{}': ", root_dir.display());
+            Ok(Credential::Recovery(prompt(&message)?.parse()?))
+        }
+        CredentialSource::Identity(path) => Ok(Credential::Identity(Identity::from_file(path)?)),
+    }
+}
+
+impl PassphraseReader {
+    pub fn new(input: PassphraseInput) -> Self {
+        Self { input, lines: None }
+    }
+
+    /// Whether someone answers the prompts, who can be asked to repeat a new
+    /// passphrase.
+    pub fn is_interactive(&self) -> bool {
+        matches!(
+            self.input,
+            PassphraseInput::Terminal | PassphraseInput::Askpass(_)
+        )
+    }
+
+    pub fn read(&mut self, message: &str) -> io::Result<Zeroizing<String>> {
+        match &self.input {
+            PassphraseInput::Terminal => {
+                match OpenOptions::new().read(true).write(true).open("/dev/tty") {
+                    Ok(tty) => read_from_tty(tty, message),
+                    Err(_) => read_from_stdin(message),
+                }
+            }
+            PassphraseInput::Askpass(program) => run_askpass(program, message),
+            PassphraseInput::Fd(_) | PassphraseInput::File(_) => {
+                let line = read_line(self.lines()?)?;
+                line.ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        format!("no passphrase left in {}", self.describe()),
+                    )
+                })
+            }
+        }
+    }
+
+    fn lines(&mut self) -> io::Result<&mut BufReader<File>> {
+        if self.lines.is_none() {
+            let file = match &self.input {
+                PassphraseInput::Fd(fd) => {
+                    // SAFETY: F_GETFD only checks whether the descriptor is
+                    // open, and the file takes ownership of it.
+                    if unsafe { libc::fcntl(*fd, libc::F_GETFD) } == -1 {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("file descriptor {fd} is not open"),
+                        ));
+                    }
+                    unsafe { File::from_raw_fd(*fd) }
+                }
+                PassphraseInput::File(path) => {
+                    let file = File::open(path)?;
+                    if file.metadata()?.permissions().mode() & 0o077 != 0 {
+                        warn!(
+                            "Passphrase file '{}' can be read by other users",
+                            path.display()
+                        );
+                    }
+                    file
+                }
+                _ => unreachable!("only descriptors and files are read line by line"),
+            };
+            self.lines = Some(BufReader::new(file));
+        }
+        Ok(self.lines.as_mut().expect("reader was just opened"))
+    }
+
+    fn describe(&self) -> String {
+        match &self.input {
+            PassphraseInput::Fd(fd) => format!("file descriptor {fd}"),
+            PassphraseInput::File(path) => format!("'{}'", path.display()),
+            PassphraseInput::Askpass(program) => format!("'{}'", program.display()),
+            PassphraseInput::Terminal => "the terminal".to_string(),
+        }
+    }
+}
+
+/// Restores the terminal settings when a passphrase was read, however the
+/// read ended.
+struct EchoGuard {
+    fd: RawFd,
+    saved: libc::termios,
+}
+
+impl Drop for EchoGuard {
+    fn drop(&mut self) {
+        // SAFETY: `saved` was filled in by tcgetattr on the same descriptor.
+        unsafe { libc::tcsetattr(self.fd, libc::TCSAFLUSH, &self.saved) };
+    }
+}
+
+/// Writes the prompt to the terminal and reads a line with echo turned off.
+fn read_from_tty(tty: File, message: &str) -> io::Result<Zeroizing<String>> {
+    let fd = tty.as_raw_fd();
+    (&tty).write_all(message.as_bytes())?;
+    (&tty).flush()?;
+
+    // SAFETY: termios is plain data that tcgetattr fills in completely.
+    let mut saved: libc::termios = unsafe { std::mem::zeroed() };
+    if unsafe { libc::tcgetattr(fd, &mut saved) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut silent = saved;
+    silent.c_lflag &= !libc::ECHO;
+    silent.c_lflag |= libc::ECHONL;
+    if unsafe { libc::tcsetattr(fd, libc::TCSAFLUSH, &silent) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let _guard = EchoGuard { fd, saved };
+
+    read_line(&mut BufReader::new(&tty))?.ok_or_else(no_passphrase)
+}
+
+/// Asks on stderr and reads from stdin, for processes without a terminal.
+fn read_from_stdin(message: &str) -> io::Result<Zeroizing<String>> {
+    let mut stderr = io::stderr();
+    stderr.write_all(message.as_bytes())?;
+    stderr.flush()?;
+    read_line(&mut io::stdin().lock())?.ok_or_else(no_passphrase)
+}
+
+/// Runs an askpass program the way ssh and sudo do: with the prompt as its
+/// only argument, printing the passphrase on stdout.
+fn run_askpass(program: &Path, message: &str) -> io::Result<Zeroizing<String>> {
+    let output = Command::new(program)
+        .arg(message.trim_end())
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("failed to run '{}': {err}", program.display()),
+            )
+        })?;
+    if !output.status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("'{}' exited with {}", program.display(), output.status),
+        ));
+    }
+    let stdout = Zeroizing::new(output.stdout);
+    read_line(&mut &stdout[..])?.ok_or_else(no_passphrase)
+}
+
+/// Reads one line without its line ending, or `None` at the end of input.
+fn read_line(reader: &mut impl BufRead) -> io::Result<Option<Zeroizing<String>>> {
+    let mut line = Zeroizing::new(String::with_capacity(MAX_PASSPHRASE_LEN as usize + 1));
+    if reader.take(MAX_PASSPHRASE_LEN + 1).read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    if line.len() as u64 > MAX_PASSPHRASE_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("passphrase is longer than {MAX_PASSPHRASE_LEN} bytes"),
+        ));
+    }
+    for ending in ['\n', '\r'] {
+        if line.ends_with(ending) {
+            line.pop();
+        }
+    }
+    Ok(Some(line))
+}
+
+fn no_passphrase() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "no passphrase was given")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn test_file_gives_one_line_per_prompt() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let path = temp_dir.path().join("passphrases");
+        fs::write(&path, "current\r\nnew passphrase\n")?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+
+        let mut reader = PassphraseReader::new(PassphraseInput::File(path));
+        assert!(!reader.is_interactive());
+        assert_eq!(*reader.read("Current passphrase: ")?, "current");
+        assert_eq!(*reader.read("New passphrase: ")?, "new passphrase");
+
+        let result = reader.read("Passphrase: ");
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_from_fd() -> io::Result<()> {
+        let (reader, mut writer) = io::pipe()?;
+        writer.write_all(b"from a pipe\n")?;
+        drop(writer);
+
+        let fd = std::os::fd::IntoRawFd::into_raw_fd(reader);
+        let mut reader = PassphraseReader::new(PassphraseInput::Fd(fd));
+        assert_eq!(*reader.read("Passphrase: ")?, "from a pipe");
+
+        let mut closed = PassphraseReader::new(PassphraseInput::Fd(9999));
+        let result = closed.read("Passphrase: ");
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_askpass_gets_prompt_as_argument() -> io::Result<()> {
+        // System programs stand in for askpass scripts, as executing a file
+        // this process just wrote fails with ETXTBSY if another test forked
+        // while it was open for writing.
+        let mut reader = PassphraseReader::new(PassphraseInput::Askpass("/bin/echo".into()));
+        assert!(reader.is_interactive());
+        assert_eq!(*reader.read("Passphrase: ")?, "Passphrase:");
+
+        let result = PassphraseReader::new(PassphraseInput::Askpass("/bin/false".into()))
+            .read("Passphrase: ");
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+
+        Ok(())
+    }
+}
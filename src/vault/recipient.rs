@@ -0,0 +1,407 @@
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::str::FromStr;
+
+use hkdf::Hkdf;
+use serde::Deserialize;
+use serde::Deserializer;
+use serde::Serialize;
+use serde::Serializer;
+use serde::de::Error;
+use sha2::Sha256;
+use x25519_dalek::PublicKey;
+use x25519_dalek::SharedSecret;
+use x25519_dalek::StaticSecret;
+use zeroize::Zeroize;
+use zeroize::Zeroizing;
+
+use crate::vault::crypto;
+use crate::vault::crypto::KEY_LEN;
+use crate::vault::secret::SecretBytes;
+
+/// Human-readable part of an encoded recipient, as used by age.
+const RECIPIENT_HRP: &str = "age";
+
+/// Human-readable part of an encoded identity, as used by age. Identities are
+/// written in upper case.
+const IDENTITY_HRP: &str = "age-secret-key-";
+
+/// Domain separation for the key-encryption keys of recipient slots.
+const RECIPIENT_SLOT_INFO: &[u8] = b"vylfs recipient key slot";
+
+/// An X25519 public key that a key slot can be wrapped to.
+///
+/// Recipients and identities use the same bech32 encoding as age, so keys made
+/// with `age-keygen` work as well.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Recipient(PublicKey);
+
+/// The X25519 private key of a recipient, kept in an identity file.
+pub struct Identity(StaticSecret);
+
+impl Identity {
+    pub fn generate() -> io::Result<Self> {
+        Ok(Self(StaticSecret::from(crypto::random_bytes::<KEY_LEN>()?)))
+    }
+
+    /// Reads the first identity from a file, skipping blank lines and
+    /// comments. Files that other users can read are refused, as the identity
+    /// opens every vault shared with its recipient.
+    pub fn from_file(path: &Path) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+        if file.metadata()?.permissions().mode() & 0o077 != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "identity file '{}' can be accessed by other users, run 'chmod 600' on it",
+                    path.display()
+                ),
+            ));
+        }
+        let mut contents = Zeroizing::new(String::new());
+        file.read_to_string(&mut contents)?;
+        contents
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty() && !line.starts_with('#'))
+            .ok_or_else(|| {
+                invalid_key(format!(
+                    "identity file '{}' holds no identity",
+                    path.display()
+                ))
+            })?
+            .parse()
+    }
+
+    pub fn recipient(&self) -> Recipient {
+        Recipient(PublicKey::from(&self.0))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; KEY_LEN] {
+        self.0.as_bytes()
+    }
+
+    /// Writes the identity to a new file that only its owner can read, with
+    /// its recipient in a comment.
+    pub fn write_new(&self, path: &Path) -> io::Result<()> {
+        use std::io::Write;
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)?;
+        writeln!(file, "# public key: {}", self.recipient())?;
+        writeln!(file, "{self}")?;
+        file.sync_all()
+    }
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&bech32::encode(IDENTITY_HRP, self.0.as_bytes()).to_uppercase())
+    }
+}
+
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Identity({})", self.recipient())
+    }
+}
+
+impl FromStr for Identity {
+    type Err = io::Error;
+
+    fn from_str(input: &str) -> io::Result<Self> {
+        let key = decode_key(input, IDENTITY_HRP, "identity")?;
+        Ok(Self(StaticSecret::from(key)))
+    }
+}
+
+impl fmt::Display for Recipient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&bech32::encode(RECIPIENT_HRP, self.0.as_bytes()))
+    }
+}
+
+impl FromStr for Recipient {
+    type Err = io::Error;
+
+    fn from_str(input: &str) -> io::Result<Self> {
+        let key = decode_key(input, RECIPIENT_HRP, "recipient")?;
+        Ok(Self(PublicKey::from(key)))
+    }
+}
+
+/// Written in the same encoding as it is printed.
+impl Serialize for Recipient {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Recipient {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(D::Error::custom)
+    }
+}
+
+/// Creates the key-encryption key for a new slot wrapped to `recipient`,
+/// returning the ephemeral public key to store next to it.
+pub fn wrap_kek(recipient: &Recipient) -> io::Result<([u8; KEY_LEN], SecretBytes<KEY_LEN>)> {
+    let ephemeral = StaticSecret::from(crypto::random_bytes::<KEY_LEN>()?);
+    let ephemeral_public = PublicKey::from(&ephemeral);
+    let shared = ephemeral.diffie_hellman(&recipient.0);
+    let kek = derive_kek(&shared, &ephemeral_public, &recipient.0)?;
+    Ok((*ephemeral_public.as_bytes(), kek))
+}
+
+/// Derives the key-encryption key of a recipient slot from the identity's
+/// private key and the slot's ephemeral public key.
+pub fn unwrap_kek(identity: &[u8], ephemeral_public: &[u8]) -> io::Result<SecretBytes<KEY_LEN>> {
+    let mut identity: [u8; KEY_LEN] = identity
+        .try_into()
+        .map_err(|_| invalid_key("identity has wrong length".to_string()))?;
+    let ephemeral_public: [u8; KEY_LEN] = ephemeral_public.try_into().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "ephemeral key of recipient slot has wrong length",
+        )
+    })?;
+    let secret = StaticSecret::from(identity);
+    identity.zeroize();
+    let identity = secret;
+    let ephemeral_public = PublicKey::from(ephemeral_public);
+    let shared = identity.diffie_hellman(&ephemeral_public);
+    derive_kek(&shared, &ephemeral_public, &PublicKey::from(&identity))
+}
+
+/// Both sides of the exchange arrive at the same shared secret, which is bound
+/// to the ephemeral key and the recipient like age does.
+fn derive_kek(
+    shared: &SharedSecret,
+    ephemeral_public: &PublicKey,
+    recipient: &PublicKey,
+) -> io::Result<SecretBytes<KEY_LEN>> {
+    if !shared.was_contributory() {
+        return Err(invalid_key("recipient is a low-order point".to_string()));
+    }
+
+    let salt = [&ephemeral_public.as_bytes()[..], recipient.as_bytes()].concat();
+    let mut kek = [0u8; KEY_LEN];
+    Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes())
+        .expand(RECIPIENT_SLOT_INFO, &mut kek)
+        .expect("key length is valid for HKDF");
+    Ok(SecretBytes::new(kek))
+}
+
+fn decode_key(input: &str, hrp: &str, what: &str) -> io::Result<[u8; KEY_LEN]> {
+    let (decoded_hrp, data) = bech32::decode(input.trim())
+        .map_err(|reason| invalid_key(format!("{what} is malformed: {reason}")))?;
+    if decoded_hrp != hrp {
+        return Err(invalid_key(format!(
+            "{what} must start with '{hrp}1', not '{decoded_hrp}1'"
+        )));
+    }
+    data.try_into()
+        .map_err(|_| invalid_key(format!("{what} has wrong length")))
+}
+
+fn invalid_key(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+/// The bech32 encoding of BIP 173, without its length limit.
+mod bech32 {
+    const CHARSET: &[u8; 32] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+    const GENERATOR: [u32; 5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+    const CHECKSUM_LEN: usize = 6;
+
+    pub fn encode(hrp: &str, data: &[u8]) -> String {
+        let values = regroup(data, 8, 5).expect("padding is allowed");
+        let checksum =
+            polymod(&[expand_hrp(hrp), values.clone(), vec![0; CHECKSUM_LEN]].concat()) ^ 1;
+
+        let mut encoded = format!("{hrp}1");
+        for value in values {
+            encoded.push(CHARSET[value as usize] as char);
+        }
+        for i in 0..CHECKSUM_LEN {
+            let value = (checksum >> (5 * (CHECKSUM_LEN - 1 - i))) & 31;
+            encoded.push(CHARSET[value as usize] as char);
+        }
+        encoded
+    }
+
+    /// Returns the lower-case human-readable part and the data.
+    pub fn decode(input: &str) -> Result<(String, Vec<u8>), &'static str> {
+        if input.chars().any(|c| c.is_ascii_lowercase())
+            && input.chars().any(|c| c.is_ascii_uppercase())
+        {
+            return Err("it mixes upper and lower case");
+        }
+        let input = input.to_ascii_lowercase();
+        let (hrp, data) = input.rsplit_once('1').ok_or("it has no separator")?;
+        if hrp.is_empty() || data.len() < CHECKSUM_LEN {
+            return Err("it is too short");
+        }
+
+        let values = data
+            .bytes()
+            .map(|c| {
+                CHARSET
+                    .iter()
+                    .position(|&d| d == c)
+                    .map(|value| value as u8)
+            })
+            .collect::<Option<Vec<u8>>>()
+            .ok_or("it contains an invalid character")?;
+        if polymod(&[expand_hrp(hrp), values.clone()].concat()) != 1 {
+            return Err("its checksum does not match");
+        }
+
+        let data = regroup(&values[..values.len() - CHECKSUM_LEN], 5, 8)
+            .ok_or("it has invalid padding")?;
+        Ok((hrp.to_string(), data))
+    }
+
+    fn expand_hrp(hrp: &str) -> Vec<u8> {
+        let high = hrp.bytes().map(|c| c >> 5);
+        let low = hrp.bytes().map(|c| c & 31);
+        high.chain([0]).chain(low).collect()
+    }
+
+    fn polymod(values: &[u8]) -> u32 {
+        values.iter().fold(1, |checksum, value| {
+            let top = checksum >> 25;
+            let checksum = ((checksum & 0x1ffffff) << 5) ^ *value as u32;
+            GENERATOR
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| (top >> i) & 1 == 1)
+                .fold(checksum, |checksum, (_, generator)| checksum ^ generator)
+        })
+    }
+
+    /// Converts between groups of `from` and `to` bits. Going to larger
+    /// groups, leftover bits must be zero padding.
+    fn regroup(data: &[u8], from: u32, to: u32) -> Option<Vec<u8>> {
+        let mut accumulator = 0u32;
+        let mut bits = 0;
+        let mut regrouped = Vec::new();
+        for value in data {
+            accumulator = (accumulator << from) | *value as u32;
+            bits += from;
+            while bits >= to {
+                bits -= to;
+                regrouped.push(((accumulator >> bits) & ((1 << to) - 1)) as u8);
+            }
+        }
+        if to < from {
+            if bits > 0 {
+                regrouped.push(((accumulator << (to - bits)) & ((1 << to) - 1)) as u8);
+            }
+        } else if bits >= from || (accumulator & ((1 << bits) - 1)) != 0 {
+            return None;
+        }
+        Some(regrouped)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_bip173_vectors() {
+            for valid in [
+                "A12UEL5L",
+                "a12uel5l",
+                "abcdef1qpzry9x8gf2tvdw0s3jn54khce6mua7lmqqqxw",
+                "split1checkupstagehandshakeupstreamerranterredcaperred2y9e3w",
+            ] {
+                let (hrp, _) = decode(valid).unwrap_or_else(|err| panic!("{valid}: {err}"));
+                assert_eq!(hrp, valid.to_lowercase().rsplit_once('1').unwrap().0);
+            }
+
+            for invalid in ["a12uel5m", "A12UEl5L", "pzry9x0s0muk", "1pzry9x0s0muk"] {
+                let result = decode(invalid);
+                assert!(result.is_err(), "Expected an error, but got {:?}", result);
+            }
+        }
+
+        #[test]
+        fn test_round_trip() {
+            let data: Vec<u8> = (0..32).collect();
+            let encoded = encode("age", &data);
+            assert_eq!(decode(&encoded), Ok(("age".to_string(), data)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn test_identity_file_round_trip() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let path = temp_dir.path().join("identity.txt");
+        let identity = Identity::generate()?;
+        identity.write_new(&path)?;
+
+        let contents = fs::read_to_string(&path)?;
+        assert!(contents.contains(&identity.recipient().to_string()));
+        assert!(contents.contains("AGE-SECRET-KEY-1"));
+        let read = Identity::from_file(&path)?;
+        assert_eq!(read.as_bytes(), identity.as_bytes());
+
+        // An existing identity is never overwritten.
+        let result = Identity::generate()?.write_new(&path);
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+
+        // Nor read once other users can read it.
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640))?;
+        let result = Identity::from_file(&path);
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_recipient_parse() -> io::Result<()> {
+        let recipient = Identity::generate()?.recipient();
+        let printed = recipient.to_string();
+        assert!(printed.starts_with("age1"));
+        assert_eq!(printed.parse::<Recipient>()?, recipient);
+
+        let result = printed.replace("age1", "agf1").parse::<Recipient>();
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+        let result = Identity::generate()?.to_string().parse::<Recipient>();
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_only_matching_identity_derives_kek() -> io::Result<()> {
+        let identity = Identity::generate()?;
+        let (ephemeral, kek) = wrap_kek(&identity.recipient())?;
+
+        assert_eq!(unwrap_kek(identity.as_bytes(), &ephemeral)?, kek);
+        let other = Identity::generate()?;
+        assert_ne!(unwrap_kek(other.as_bytes(), &ephemeral)?, kek);
+
+        Ok(())
+    }
+}
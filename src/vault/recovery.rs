@@ -0,0 +1,322 @@
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+use data_encoding::BASE32_NOPAD;
+use sha2::Digest;
+use sha2::Sha256;
+use zeroize::Zeroize;
+use zeroize::ZeroizeOnDrop;
+use zeroize::Zeroizing;
+
+use crate::vault::crypto;
+use crate::vault::crypto::KEY_LEN;
+use crate::vault::secret::SecretBytes;
+use crate::vault::shamir;
+
+/// Length of the checksum appended to the key before encoding.
+const CHECKSUM_LEN: usize = 4;
+
+/// Number of characters per group in the printed key.
+const GROUP_LEN: usize = 4;
+
+/// Length of the random identifier shared by all shares of one split.
+const SPLIT_ID_LEN: usize = 4;
+
+/// Length of an encoded share: the split identifier, the threshold, the
+/// share's index and its part of the key.
+const SHARE_LEN: usize = SPLIT_ID_LEN + 2 + KEY_LEN;
+
+/// A random key printed at `init`, which opens the vault when its passphrase
+/// is lost.
+///
+/// It is written as base32 in groups of four characters, with a checksum so
+/// that typos are reported before the key is tried against any slot.
+pub struct RecoveryKey(SecretBytes<KEY_LEN>);
+
+impl RecoveryKey {
+    pub fn generate() -> io::Result<Self> {
+        Ok(Self(SecretBytes::random()?))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; KEY_LEN] {
+        self.0.as_bytes()
+    }
+
+    /// Splits the key into `count` shares, any `threshold` of which recover
+    /// it with `combine`.
+    pub fn split(&self, threshold: u8, count: u8) -> io::Result<Vec<RecoveryShare>> {
+        let split_id = crypto::random_bytes::<SPLIT_ID_LEN>()?;
+        shamir::split(self.as_bytes(), threshold, count)?
+            .into_iter()
+            .map(|(index, data)| {
+                Ok(RecoveryShare {
+                    split_id,
+                    threshold,
+                    index,
+                    data: data.try_into().expect("shares are as long as the key"),
+                })
+            })
+            .collect()
+    }
+
+    /// Recovers a key from the shares of one split, failing unless at least
+    /// its threshold of distinct shares is given.
+    pub fn combine(shares: &[RecoveryShare]) -> io::Result<Self> {
+        let Some(first) = shares.first() else {
+            return Err(invalid_key("no recovery shares were given"));
+        };
+        if shares.iter().any(|share| share.split_id != first.split_id) {
+            return Err(invalid_key(
+                "recovery shares belong to different splits of the key",
+            ));
+        }
+        if shares.len() < first.threshold as usize {
+            return Err(invalid_key(&format!(
+                "{} of {} recovery shares were given",
+                shares.len(),
+                first.threshold
+            )));
+        }
+
+        let parts: Vec<(u8, &[u8])> = shares
+            .iter()
+            .map(|share| (share.index, &share.data[..]))
+            .collect();
+        let key = shamir::combine(&parts)?;
+        Ok(Self(SecretBytes::new(
+            key[..].try_into().expect("shares are as long as the key"),
+        )))
+    }
+}
+
+/// One of the shares a recovery key is split into at `init --split`.
+///
+/// Shares are printed like the key itself, with a checksum, and also record
+/// the threshold and which split they belong to, so that mixed-up or missing
+/// shares are reported instead of recovering a wrong key.
+#[derive(Zeroize, ZeroizeOnDrop)]
+pub struct RecoveryShare {
+    split_id: [u8; SPLIT_ID_LEN],
+    threshold: u8,
+    index: u8,
+    data: [u8; KEY_LEN],
+}
+
+impl RecoveryShare {
+    /// Number of shares needed to recover the key.
+    pub fn threshold(&self) -> u8 {
+        self.threshold
+    }
+
+    pub fn index(&self) -> u8 {
+        self.index
+    }
+
+    fn to_bytes(&self) -> Zeroizing<Vec<u8>> {
+        Zeroizing::new(
+            [
+                &self.split_id[..],
+                &[self.threshold, self.index],
+                &self.data,
+            ]
+            .concat(),
+        )
+    }
+}
+
+impl fmt::Display for RecoveryShare {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&encode(&self.to_bytes()))
+    }
+}
+
+impl fmt::Debug for RecoveryShare {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "RecoveryShare({} of threshold {})",
+            self.index, self.threshold
+        )
+    }
+}
+
+impl FromStr for RecoveryShare {
+    type Err = io::Error;
+
+    fn from_str(input: &str) -> io::Result<Self> {
+        let decoded = decode(input, SHARE_LEN, "recovery share")?;
+        let (split_id, rest) = decoded.split_at(SPLIT_ID_LEN);
+        let (&[threshold, index], data) = rest.split_first_chunk().expect("length was checked");
+        if threshold < 2 || index == 0 {
+            return Err(invalid_key("recovery share is malformed"));
+        }
+        Ok(Self {
+            split_id: split_id.try_into().expect("length was checked"),
+            threshold,
+            index,
+            data: data.try_into().expect("length was checked"),
+        })
+    }
+}
+
+impl fmt::Display for RecoveryKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&encode(self.as_bytes()))
+    }
+}
+
+impl fmt::Debug for RecoveryKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RecoveryKey(..)")
+    }
+}
+
+impl FromStr for RecoveryKey {
+    type Err = io::Error;
+
+    fn from_str(input: &str) -> io::Result<Self> {
+        let decoded = decode(input, KEY_LEN, "recovery key")?;
+        Ok(Self(SecretBytes::new(
+            decoded[..].try_into().expect("length was checked"),
+        )))
+    }
+}
+
+/// Writes `data` and its checksum as base32 in groups of `GROUP_LEN`.
+fn encode(data: &[u8]) -> String {
+    let encoded =
+        Zeroizing::new(BASE32_NOPAD.encode(&Zeroizing::new([data, &checksum(data)].concat())));
+    let groups: Vec<&str> = encoded
+        .as_bytes()
+        .chunks(GROUP_LEN)
+        .map(|group| std::str::from_utf8(group).expect("base32 is ASCII"))
+        .collect();
+    groups.join("-")
+}
+
+/// Parses `len` bytes written by `encode`, ignoring case, spaces and dashes.
+/// Digits that are easily mistaken for letters of the base32 alphabet are read
+/// as those.
+fn decode(input: &str, len: usize, what: &str) -> io::Result<Zeroizing<Vec<u8>>> {
+    let normalized: Zeroizing<String> = input
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .map(|c| match c.to_ascii_uppercase() {
+            '0' => 'O',
+            '1' => 'I',
+            '8' => 'B',
+            c => c,
+        })
+        .collect::<String>()
+        .into();
+
+    let mut decoded = BASE32_NOPAD
+        .decode(normalized.as_bytes())
+        .ok()
+        .map(Zeroizing::new)
+        .filter(|decoded| decoded.len() == len + CHECKSUM_LEN)
+        .ok_or_else(|| invalid_key(&format!("{what} is malformed")))?;
+    let sum = decoded.split_off(len);
+    if sum != checksum(&decoded) {
+        return Err(invalid_key(&format!(
+            "{what} checksum does not match, check it for typos"
+        )));
+    }
+    Ok(decoded)
+}
+
+fn checksum(data: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let digest = Sha256::digest(data);
+    digest[..CHECKSUM_LEN]
+        .try_into()
+        .expect("digest is longer than the checksum")
+}
+
+fn invalid_key(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() -> io::Result<()> {
+        let key = RecoveryKey::generate()?;
+        let printed = key.to_string();
+        assert_eq!(printed.len(), 58 + 14);
+        assert!(printed.split('-').all(|group| group.len() <= GROUP_LEN));
+
+        let parsed: RecoveryKey = printed.parse()?;
+        assert_eq!(parsed.as_bytes(), key.as_bytes());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_is_lenient_about_formatting() -> io::Result<()> {
+        let key = RecoveryKey(SecretBytes::new([0; KEY_LEN]));
+        let printed = key.to_string();
+        assert!(printed.starts_with("AAAA-AAAA"));
+
+        let sloppy = printed.to_lowercase().replace('-', " ");
+        assert_eq!(sloppy.parse::<RecoveryKey>()?.as_bytes(), key.as_bytes());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_shares_recover_key() -> io::Result<()> {
+        let key = RecoveryKey::generate()?;
+        let printed: Vec<String> = key.split(2, 3)?.iter().map(ToString::to_string).collect();
+        let shares = printed
+            .iter()
+            .map(|share| share.parse())
+            .collect::<io::Result<Vec<RecoveryShare>>>()?;
+        assert_eq!(shares[2].threshold(), 2);
+        assert_eq!(shares[2].index(), 3);
+
+        let recovered = RecoveryKey::combine(&shares[1..])?;
+        assert_eq!(recovered.as_bytes(), key.as_bytes());
+
+        // A single share is not enough, and a key is not a share.
+        let result = RecoveryKey::combine(&shares[..1]);
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+        let result = key.to_string().parse::<RecoveryShare>();
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_shares_of_different_splits_do_not_combine() -> io::Result<()> {
+        let key = RecoveryKey::generate()?;
+        let mut shares = key.split(2, 2)?;
+        let other = key.split(2, 2)?;
+        shares[1] = other.into_iter().nth(1).unwrap();
+
+        let result = RecoveryKey::combine(&shares);
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+        assert!(result.unwrap_err().to_string().contains("different splits"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_rejects_typos() -> io::Result<()> {
+        let printed = RecoveryKey::generate()?.to_string();
+        let first = printed.chars().next().unwrap();
+        let typo = if first == 'A' { 'B' } else { 'A' };
+        let mistyped = format!("{typo}{}", &printed[1..]);
+
+        let result = mistyped.parse::<RecoveryKey>();
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+        assert!(result.unwrap_err().to_string().contains("checksum"));
+
+        let result = printed[..20].parse::<RecoveryKey>();
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,191 @@
+use std::fmt;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::info;
+
+/// Name of the progress record inside the vault's root directory. It exists
+/// exactly while a rekey is in progress.
+pub const REKEY_FILE_NAME: &str = "vylfs.rekey";
+
+/// A new record is written here and then renamed over the old one.
+const REKEY_TEMP_NAME: &str = "vylfs.rekey.tmp";
+
+/// Number of objects re-encrypted between two writes of the progress record.
+const CHECKPOINT_INTERVAL: u64 = 64;
+
+/// How far a rekey got, so an interrupted one resumes where it stopped.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RekeyProgress {
+    /// The key generation objects are moved to.
+    pub generation: u32,
+    pub objects_done: u64,
+    pub objects_total: u64,
+    /// Objects are re-encrypted in sorted order, up to and including this one.
+    pub last_object: Option<String>,
+}
+
+impl RekeyProgress {
+    pub fn new(generation: u32) -> Self {
+        Self {
+            generation,
+            objects_done: 0,
+            objects_total: 0,
+            last_object: None,
+        }
+    }
+
+    /// Reads the progress record of the vault at `root_dir`, returning `None`
+    /// if no rekey is in progress.
+    pub fn read(root_dir: &Path) -> io::Result<Option<Self>> {
+        match fs::read(root_dir.join(REKEY_FILE_NAME)) {
+            Ok(contents) => Ok(Some(serde_json::from_slice(&contents)?)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Replaces the progress record atomically.
+    pub fn write(&self, root_dir: &Path) -> io::Result<()> {
+        let temp_path = root_dir.join(REKEY_TEMP_NAME);
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(&temp_path)?;
+        serde_json::to_writer(&mut file, self)?;
+        file.write_all(b"\n")?;
+        file.sync_all()?;
+        fs::rename(temp_path, root_dir.join(REKEY_FILE_NAME))
+    }
+}
+
+impl fmt::Display for RekeyProgress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "to key generation {}, {}/{} objects",
+            self.generation, self.objects_done, self.objects_total
+        )
+    }
+}
+
+/// Re-encrypts the objects `names`, resuming after the last object recorded in
+/// the progress record of the vault at `root_dir`.
+///
+/// `reencrypt` is called once per object and `report` after every checkpoint.
+/// The progress record is removed when all objects are done.
+pub fn run<F, R>(
+    root_dir: &Path,
+    mut names: Vec<String>,
+    mut reencrypt: F,
+    mut report: R,
+) -> io::Result<()>
+where
+    F: FnMut(&str) -> io::Result<()>,
+    R: FnMut(&RekeyProgress),
+{
+    let mut progress = RekeyProgress::read(root_dir)?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no rekey is in progress"))?;
+    names.sort();
+    let start = match &progress.last_object {
+        Some(last) => names.partition_point(|name| name <= last),
+        None => 0,
+    };
+    progress.objects_done = start as u64;
+    progress.objects_total = names.len() as u64;
+    info!("Re-encrypting objects {}", progress);
+
+    for name in &names[start..] {
+        reencrypt(name)?;
+        progress.objects_done += 1;
+        progress.last_object = Some(name.clone());
+        if progress.objects_done % CHECKPOINT_INTERVAL == 0 {
+            progress.write(root_dir)?;
+            report(&progress);
+        }
+    }
+
+    report(&progress);
+    fs::remove_file(root_dir.join(REKEY_FILE_NAME))?;
+    info!(
+        "Re-encrypted all objects to key generation {}",
+        progress.generation
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn object_names(count: usize) -> Vec<String> {
+        (0..count).map(|index| format!("{index:04}")).collect()
+    }
+
+    #[test]
+    fn test_run_resumes_after_interruption() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        RekeyProgress::new(1).write(temp_dir.path())?;
+        let names = object_names(200);
+
+        let mut done = Vec::new();
+        let result = run(
+            temp_dir.path(),
+            names.clone(),
+            |name| {
+                if name == "0150" {
+                    return Err(io::Error::other("interrupted"));
+                }
+                done.push(name.to_string());
+                Ok(())
+            },
+            |_| {},
+        );
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+
+        let progress = RekeyProgress::read(temp_dir.path())?.expect("rekey is still in progress");
+        assert_eq!(progress.objects_done, 128);
+        assert_eq!(progress.last_object.as_deref(), Some("0127"));
+
+        // Objects after the last checkpoint are re-encrypted again, which
+        // leaves objects that are already done untouched.
+        let mut resumed = Vec::new();
+        let mut reports = Vec::new();
+        run(
+            temp_dir.path(),
+            names.clone(),
+            |name| {
+                resumed.push(name.to_string());
+                Ok(())
+            },
+            |progress| reports.push(progress.objects_done),
+        )?;
+        assert_eq!(resumed, &names[128..]);
+        assert_eq!(reports, [192, 200]);
+        assert_eq!(RekeyProgress::read(temp_dir.path())?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_without_progress_record() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+
+        let result = run(temp_dir.path(), object_names(1), |_| Ok(()), |_| {});
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::NotFound);
+
+        Ok(())
+    }
+}
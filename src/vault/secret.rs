@@ -0,0 +1,165 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::sync::Mutex;
+use std::sync::Once;
+
+use tracing::warn;
+use zeroize::Zeroize;
+
+use crate::vault::crypto;
+
+/// Fixed-size key material on the heap, locked into memory so that it is never
+/// written to swap, and wiped when dropped.
+///
+/// The bytes live in their own allocation, so moving a `SecretBytes` never
+/// leaves a copy behind.
+pub struct SecretBytes<const N: usize>(Box<[u8; N]>);
+
+/// How many live secrets lie on each locked page, as `mlock` does not count.
+static LOCKED_PAGES: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
+
+/// Failing to lock memory is reported once, not for every key.
+static LOCK_WARNING: Once = Once::new();
+
+impl<const N: usize> SecretBytes<N> {
+    /// Takes over `bytes`, wiping the copy that was passed in.
+    pub fn new(mut bytes: [u8; N]) -> Self {
+        let mut secret = Self::zeroed();
+        secret.0.copy_from_slice(&bytes);
+        bytes.zeroize();
+        secret
+    }
+
+    /// Fills a new secret from the operating system's random number generator
+    /// without copying it.
+    pub fn random() -> io::Result<Self> {
+        let mut secret = Self::zeroed();
+        crypto::fill_random(&mut secret.0[..])?;
+        Ok(secret)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; N] {
+        &self.0
+    }
+
+    fn zeroed() -> Self {
+        let secret = Self(Box::new([0u8; N]));
+        lock_pages(secret.0.as_ptr() as usize, N);
+        secret
+    }
+}
+
+impl<const N: usize> Clone for SecretBytes<N> {
+    fn clone(&self) -> Self {
+        let mut secret = Self::zeroed();
+        secret.0.copy_from_slice(&self.0[..]);
+        secret
+    }
+}
+
+impl<const N: usize> PartialEq for SecretBytes<N> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl<const N: usize> Eq for SecretBytes<N> {}
+
+impl<const N: usize> Drop for SecretBytes<N> {
+    fn drop(&mut self) {
+        self.0.zeroize();
+        unlock_pages(self.0.as_ptr() as usize, N);
+    }
+}
+
+impl<const N: usize> fmt::Debug for SecretBytes<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretBytes(..)")
+    }
+}
+
+fn page_range(address: usize, len: usize) -> impl Iterator<Item = usize> {
+    // SAFETY: sysconf has no preconditions.
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let first = address / page_size;
+    let last = (address + len.max(1) - 1) / page_size;
+    (first..=last).map(move |page| page * page_size)
+}
+
+fn lock_pages(address: usize, len: usize) {
+    let mut locked = LOCKED_PAGES.lock().expect("locked pages lock poisoned");
+    for page in page_range(address, len) {
+        let count = locked.entry(page).or_insert(0);
+        if *count == 0 {
+            // SAFETY: the page belongs to a live allocation of this process.
+            if unsafe { libc::mlock(page as *const libc::c_void, 1) } != 0 {
+                let err = io::Error::last_os_error();
+                LOCK_WARNING.call_once(|| {
+                    warn!("Failed to lock key material into memory: {}", err);
+                });
+            }
+        }
+        *count += 1;
+    }
+}
+
+fn unlock_pages(address: usize, len: usize) {
+    let mut locked = LOCKED_PAGES.lock().expect("locked pages lock poisoned");
+    for page in page_range(address, len) {
+        let Some(count) = locked.get_mut(&page) else {
+            continue;
+        };
+        *count -= 1;
+        if *count == 0 {
+            locked.remove(&page);
+            // SAFETY: munlock only changes whether the page may be swapped.
+            unsafe { libc::munlock(page as *const libc::c_void, 1) };
+        }
+    }
+}
+
+/// Keeps the process from writing core dumps, which would contain the keys,
+/// and from being attached to by other processes of the same user.
+pub fn disable_core_dumps() -> io::Result<()> {
+    // SAFETY: PR_SET_DUMPABLE only changes a flag of this process.
+    if unsafe { libc::prctl(libc::PR_SET_DUMPABLE, 0, 0, 0, 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shared_page_stays_locked_until_last_secret_is_dropped() {
+        // SAFETY: sysconf has no preconditions.
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let buffer = vec![0u8; 3 * page_size];
+        let page = page_range(buffer.as_ptr() as usize + page_size, 1)
+            .next()
+            .unwrap();
+        let count = || LOCKED_PAGES.lock().unwrap().get(&page).copied();
+
+        lock_pages(page, 32);
+        lock_pages(page + 32, 32);
+        assert_eq!(count(), Some(2));
+        unlock_pages(page, 32);
+        assert_eq!(count(), Some(1));
+        unlock_pages(page + 32, 32);
+        assert_eq!(count(), None);
+    }
+
+    #[test]
+    fn test_clone_is_a_separate_copy() -> io::Result<()> {
+        let secret = SecretBytes::<32>::random()?;
+        let copy = secret.clone();
+        assert_eq!(copy, secret);
+        assert_ne!(copy.as_bytes().as_ptr(), secret.as_bytes().as_ptr());
+        assert_eq!(format!("{secret:?}"), "SecretBytes(..)");
+
+        Ok(())
+    }
+}
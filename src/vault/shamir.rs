@@ -0,0 +1,179 @@
+use std::io;
+
+use zeroize::Zeroize;
+use zeroize::Zeroizing;
+
+use crate::vault::crypto;
+
+/// Splits `secret` into `count` shares, any `threshold` of which recombine to
+/// it, using Shamir's scheme over GF(256).
+///
+/// Every byte of the secret is the constant term of its own random polynomial
+/// of degree `threshold - 1`. Share `x` holds the values of all polynomials at
+/// `x`, for `x` from 1 to `count`.
+pub fn split(secret: &[u8], threshold: u8, count: u8) -> io::Result<Vec<(u8, Vec<u8>)>> {
+    if threshold < 2 || threshold > count {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("cannot split into {count} shares with a threshold of {threshold}"),
+        ));
+    }
+
+    let mut shares: Vec<(u8, Vec<u8>)> = (1..=count)
+        .map(|x| (x, Vec::with_capacity(secret.len())))
+        .collect();
+    let mut coefficients = vec![0u8; threshold as usize];
+    for &byte in secret {
+        coefficients[0] = byte;
+        crypto::fill_random(&mut coefficients[1..])?;
+        for (x, share) in &mut shares {
+            share.push(evaluate(&coefficients, *x));
+        }
+    }
+    coefficients.zeroize();
+    Ok(shares)
+}
+
+/// Recombines a secret from shares made by `split`.
+///
+/// Fewer shares than the threshold yield an unrelated value rather than an
+/// error, so callers must know the threshold and check the result.
+pub fn combine(shares: &[(u8, &[u8])]) -> io::Result<Zeroizing<Vec<u8>>> {
+    let Some((_, first)) = shares.first() else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "no shares were given",
+        ));
+    };
+    for (i, (x, data)) in shares.iter().enumerate() {
+        if *x == 0 || shares[..i].iter().any(|(other, _)| other == x) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("share {x} is invalid or was given twice"),
+            ));
+        }
+        if data.len() != first.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "shares have different lengths",
+            ));
+        }
+    }
+
+    // Lagrange interpolation at x = 0. Subtraction is addition in GF(256).
+    let weights: Vec<u8> = shares
+        .iter()
+        .map(|(x, _)| {
+            shares
+                .iter()
+                .filter(|(other, _)| other != x)
+                .fold(1, |weight, (other, _)| {
+                    mul(weight, mul(*other, inverse(other ^ x)))
+                })
+        })
+        .collect();
+    Ok(Zeroizing::new(
+        (0..first.len())
+            .map(|i| {
+                shares
+                    .iter()
+                    .zip(&weights)
+                    .fold(0, |secret, ((_, data), weight)| {
+                        secret ^ mul(data[i], *weight)
+                    })
+            })
+            .collect(),
+    ))
+}
+
+/// Evaluates the polynomial with the given coefficients, constant term first,
+/// at `x` using Horner's method.
+fn evaluate(coefficients: &[u8], x: u8) -> u8 {
+    coefficients
+        .iter()
+        .rev()
+        .fold(0, |value, coefficient| mul(value, x) ^ coefficient)
+}
+
+/// Multiplies in GF(256) modulo the AES polynomial x^8 + x^4 + x^3 + x + 1,
+/// without branching on the operands.
+fn mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    for _ in 0..8 {
+        product ^= a & (b & 1).wrapping_neg();
+        let carry = (a >> 7).wrapping_neg();
+        a = (a << 1) ^ (carry & 0x1b);
+        b >>= 1;
+    }
+    product
+}
+
+/// The multiplicative inverse, as a^254 = a^-1 for every non-zero a.
+fn inverse(a: u8) -> u8 {
+    let mut result = 1;
+    let mut power = a;
+    let mut exponent = 254u8;
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = mul(result, power);
+        }
+        power = mul(power, power);
+        exponent >>= 1;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn borrowed(shares: &[(u8, Vec<u8>)]) -> Vec<(u8, &[u8])> {
+        shares.iter().map(|(x, data)| (*x, &data[..])).collect()
+    }
+
+    #[test]
+    fn test_field_arithmetic() {
+        // Known products in the AES field.
+        assert_eq!(mul(0x57, 0x83), 0xc1);
+        assert_eq!(mul(0x57, 0x13), 0xfe);
+        assert_eq!(inverse(0x53), 0xca);
+        for a in 1..=u8::MAX {
+            assert_eq!(mul(a, inverse(a)), 1);
+        }
+    }
+
+    #[test]
+    fn test_any_threshold_of_shares_combine() -> io::Result<()> {
+        let secret = crypto::random_bytes::<32>()?;
+        let shares = split(&secret, 3, 5)?;
+        assert_eq!(shares.len(), 5);
+
+        for (a, b, c) in [(0, 1, 2), (0, 2, 4), (4, 3, 1), (1, 2, 3)] {
+            let subset = [&shares[a], &shares[b], &shares[c]].map(Clone::clone);
+            assert_eq!(*combine(&borrowed(&subset))?, secret);
+        }
+        assert_eq!(*combine(&borrowed(&shares))?, secret);
+
+        // Two shares interpolate a different polynomial.
+        assert_ne!(*combine(&borrowed(&shares[..2]))?, secret);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_splits_and_shares() -> io::Result<()> {
+        for (threshold, count) in [(1, 3), (4, 3), (0, 0)] {
+            let result = split(b"secret", threshold, count);
+            assert!(result.is_err(), "Expected an error, but got {:?}", result);
+        }
+
+        let shares = split(b"secret", 2, 3)?;
+        let duplicated = [shares[0].clone(), shares[0].clone()];
+        let result = combine(&borrowed(&duplicated));
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+        let result = combine(&[]);
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+
+        Ok(())
+    }
+}
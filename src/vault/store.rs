@@ -0,0 +1,564 @@
+use std::fs;
+use std::fs::DirBuilder;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::vault::Keyring;
+use crate::vault::MasterKey;
+use crate::vault::crypto::Cipher;
+use crate::vault::header::Header;
+
+/// Directory inside the vault's root directory that holds all objects.
+pub const OBJECTS_DIR_NAME: &str = "objects";
+
+/// Objects are written under this suffix and renamed into place.
+const TEMP_SUFFIX: &str = ".tmp";
+
+/// Objects that fail to decrypt are set aside under this suffix by `fsck`.
+const QUARANTINE_SUFFIX: &str = ".corrupt";
+
+/// Identifies an encrypted object.
+const OBJECT_MAGIC: &[u8; 4] = b"VYLO";
+
+/// Layout of the objects written by this build, whose header carries the
+/// object's version.
+const OBJECT_FORMAT: u8 = 2;
+
+/// Layout of objects written before versions were added, which read as
+/// version 0.
+const LEGACY_OBJECT_FORMAT: u8 = 1;
+
+/// Length of the authenticated object header: magic, format, the key
+/// generation and the object's version.
+const OBJECT_HEADER_LEN: usize = LEGACY_HEADER_LEN + 8;
+
+/// Length of the header of legacy objects, which has no version.
+const LEGACY_HEADER_LEN: usize = OBJECT_MAGIC.len() + 1 + 4;
+
+/// Encrypted objects in a vault, each stored in its own file.
+///
+/// An object is its header, a nonce and the ciphertext. The header and the
+/// object's name are authenticated, so objects cannot be swapped for one
+/// another without the read failing, and an object's version cannot be
+/// changed. The filesystem records which version of each object it expects,
+/// which reveals rollbacks to older copies.
+#[derive(Debug)]
+pub struct Store {
+    dir: PathBuf,
+    keyring: Keyring,
+    cipher: Cipher,
+}
+
+/// The unencrypted header of an object.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ObjectHeader {
+    /// The master key generation the object is encrypted under.
+    pub generation: u32,
+    /// Chosen by the writer, 0 for legacy objects.
+    pub version: u64,
+}
+
+/// A decrypted object.
+#[derive(Debug, PartialEq, Eq)]
+pub struct StoredObject {
+    pub version: u64,
+    pub data: Vec<u8>,
+}
+
+impl Store {
+    /// Opens the objects of the vault at `root_dir`, creating their directory
+    /// if the vault has none yet. Objects are encrypted with the cipher named
+    /// by the vault's header, or the default one if it has no header.
+    pub fn open(root_dir: &Path, keyring: Keyring) -> io::Result<Self> {
+        let cipher = if Header::exists(root_dir) {
+            Header::read(root_dir)?.cipher
+        } else {
+            Cipher::default()
+        };
+        Self::with_cipher(root_dir, keyring, cipher)
+    }
+
+    /// Opens the objects of the vault at `root_dir` like `open`, encrypted
+    /// with `cipher`.
+    pub fn with_cipher(root_dir: &Path, keyring: Keyring, cipher: Cipher) -> io::Result<Self> {
+        let dir = root_dir.join(OBJECTS_DIR_NAME);
+        match DirBuilder::new().mode(0o700).create(&dir) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(err) => return Err(err),
+        }
+        Ok(Self {
+            dir,
+            keyring,
+            cipher,
+        })
+    }
+
+    /// The cipher the objects are encrypted with.
+    pub fn cipher(&self) -> Cipher {
+        self.cipher
+    }
+
+    /// The key generation new objects are encrypted under.
+    pub fn current_generation(&self) -> u32 {
+        self.keyring.current_generation()
+    }
+
+    /// The root directory of the vault the objects belong to.
+    pub fn root_dir(&self) -> &Path {
+        self.dir
+            .parent()
+            .expect("objects directory is inside the root directory")
+    }
+
+    /// Adds a master key generation, which new objects are then encrypted
+    /// under if it is the newest.
+    pub fn add_key(&mut self, generation: u32, key: MasterKey) {
+        self.keyring.insert(generation, key);
+    }
+
+    /// Drops every key generation but the current one, once no object is
+    /// encrypted under them anymore, so that objects under a retired key no
+    /// longer decrypt.
+    pub fn retire_keys(&mut self) {
+        self.keyring = self.keyring.retain_current();
+    }
+
+    /// Reads and decrypts an object, returning `None` if it does not exist.
+    pub fn read(&self, name: &str) -> io::Result<Option<StoredObject>> {
+        match fs::read(self.path(name)) {
+            Ok(contents) => self.decrypt(name, &contents).map(Some),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Encrypts and replaces an object atomically.
+    pub fn write(&self, name: &str, version: u64, plaintext: &[u8]) -> io::Result<()> {
+        let header = object_header(ObjectHeader {
+            generation: self.keyring.current_generation(),
+            version,
+        });
+        let (nonce, ciphertext) = self.cipher.seal(
+            self.keyring.current().as_bytes(),
+            &object_aad(&header, name),
+            plaintext,
+        )?;
+
+        let temp_path = self.path(&format!("{name}{TEMP_SUFFIX}"));
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(&temp_path)?;
+        file.write_all(&header)?;
+        file.write_all(&nonce)?;
+        file.write_all(&ciphertext)?;
+        file.sync_data()?;
+        fs::rename(temp_path, self.path(name))
+    }
+
+    /// Removes an object, succeeding if it does not exist.
+    pub fn remove(&self, name: &str) -> io::Result<()> {
+        match fs::remove_file(self.path(name)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    /// Moves an object out of the way without removing it, so that what is
+    /// left of it can still be inspected. Quarantined objects are not listed
+    /// by `names`.
+    pub fn quarantine(&self, name: &str) -> io::Result<()> {
+        match fs::rename(
+            self.path(name),
+            self.path(&format!("{name}{QUARANTINE_SUFFIX}")),
+        ) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    /// Makes the renames and removals of objects durable.
+    pub fn sync(&self) -> io::Result<()> {
+        File::open(&self.dir)?.sync_all()
+    }
+
+    /// Lists the names of all objects in sorted order.
+    pub fn names(&self) -> io::Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let name = entry?.file_name();
+            match name.to_str() {
+                Some(name)
+                    if !name.ends_with(TEMP_SUFFIX) && !name.ends_with(QUARANTINE_SUFFIX) =>
+                {
+                    names.push(name.to_string())
+                }
+                _ => {}
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Returns the header of an object without decrypting it, or `None` if it
+    /// does not exist.
+    pub fn header(&self, name: &str) -> io::Result<Option<ObjectHeader>> {
+        let mut header = [0u8; OBJECT_HEADER_LEN];
+        let mut file = match File::open(self.path(name)) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        // Legacy objects are longer than a header, too, as they hold a nonce.
+        io::Read::read_exact(&mut file, &mut header)
+            .map_err(|_| invalid_object(name, "it is truncated"))?;
+        parse_header(name, &header).map(|(header, _)| Some(header))
+    }
+
+    /// Returns the key generation an object is encrypted under, or `None` if
+    /// it does not exist.
+    pub fn generation(&self, name: &str) -> io::Result<Option<u32>> {
+        Ok(self.header(name)?.map(|header| header.generation))
+    }
+
+    /// Re-encrypts an object under the current key generation, keeping its
+    /// version, and returns whether it had to be rewritten.
+    pub fn reencrypt(&self, name: &str) -> io::Result<bool> {
+        match self.generation(name)? {
+            Some(generation) if generation != self.keyring.current_generation() => {}
+            _ => return Ok(false),
+        }
+        match self.read(name)? {
+            Some(object) => {
+                self.write(name, object.version, &object.data)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Writes an object in the legacy format, as builds before versioned
+    /// objects did.
+    #[cfg(test)]
+    pub fn write_legacy(&self, name: &str, plaintext: &[u8]) -> io::Result<()> {
+        let mut header = object_header(ObjectHeader {
+            generation: self.keyring.current_generation(),
+            version: 0,
+        })[..LEGACY_HEADER_LEN]
+            .to_vec();
+        header[OBJECT_MAGIC.len()] = LEGACY_OBJECT_FORMAT;
+        let (nonce, ciphertext) = self.cipher.seal(
+            self.keyring.current().as_bytes(),
+            &object_aad(&header, name),
+            plaintext,
+        )?;
+        fs::write(
+            self.path(name),
+            [header, nonce.to_vec(), ciphertext].concat(),
+        )
+    }
+
+    fn decrypt(&self, name: &str, contents: &[u8]) -> io::Result<StoredObject> {
+        let nonce_len = self.cipher.nonce_len();
+        if contents.len() < LEGACY_HEADER_LEN + nonce_len {
+            return Err(invalid_object(name, "it is truncated"));
+        }
+        let (header, header_len) = parse_header(name, contents)?;
+        if contents.len() < header_len + nonce_len {
+            return Err(invalid_object(name, "it is truncated"));
+        }
+        let (header_bytes, rest) = contents.split_at(header_len);
+        let (nonce, ciphertext) = rest.split_at(nonce_len);
+        let generation = header.generation;
+        let key = self.keyring.get(generation).ok_or_else(|| {
+            invalid_object(name, &format!("its key generation {generation} is unknown"))
+        })?;
+        let data = self
+            .cipher
+            .open(
+                key.as_bytes(),
+                &object_aad(header_bytes, name),
+                nonce,
+                ciphertext,
+            )
+            .map_err(|err| {
+                io::Error::new(
+                    err.kind(),
+                    format!("object '{name}' does not authenticate: {err}"),
+                )
+            })?;
+        Ok(StoredObject {
+            version: header.version,
+            data,
+        })
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+}
+
+fn object_header(header: ObjectHeader) -> [u8; OBJECT_HEADER_LEN] {
+    let mut bytes = [0u8; OBJECT_HEADER_LEN];
+    bytes[..OBJECT_MAGIC.len()].copy_from_slice(OBJECT_MAGIC);
+    bytes[OBJECT_MAGIC.len()] = OBJECT_FORMAT;
+    bytes[OBJECT_MAGIC.len() + 1..LEGACY_HEADER_LEN]
+        .copy_from_slice(&header.generation.to_le_bytes());
+    bytes[LEGACY_HEADER_LEN..].copy_from_slice(&header.version.to_le_bytes());
+    bytes
+}
+
+/// Parses the header at the start of `contents`, returning it and its length.
+fn parse_header(name: &str, contents: &[u8]) -> io::Result<(ObjectHeader, usize)> {
+    if contents.len() < LEGACY_HEADER_LEN || &contents[..OBJECT_MAGIC.len()] != OBJECT_MAGIC {
+        return Err(invalid_object(name, "it is not a vylfs object"));
+    }
+    let generation = u32::from_le_bytes(
+        contents[OBJECT_MAGIC.len() + 1..LEGACY_HEADER_LEN]
+            .try_into()
+            .expect("length was checked"),
+    );
+    match contents[OBJECT_MAGIC.len()] {
+        LEGACY_OBJECT_FORMAT => Ok((
+            ObjectHeader {
+                generation,
+                version: 0,
+            },
+            LEGACY_HEADER_LEN,
+        )),
+        OBJECT_FORMAT if contents.len() >= OBJECT_HEADER_LEN => {
+            let version = contents[LEGACY_HEADER_LEN..OBJECT_HEADER_LEN]
+                .try_into()
+                .expect("length was checked");
+            Ok((
+                ObjectHeader {
+                    generation,
+                    version: u64::from_le_bytes(version),
+                },
+                OBJECT_HEADER_LEN,
+            ))
+        }
+        OBJECT_FORMAT => Err(invalid_object(name, "it is truncated")),
+        format => Err(invalid_object(
+            name,
+            &format!("its format {format} is unsupported"),
+        )),
+    }
+}
+
+fn object_aad(header: &[u8], name: &str) -> Vec<u8> {
+    [header, name.as_bytes()].concat()
+}
+
+fn invalid_object(name: &str, reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("object '{name}' is invalid: {reason}"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use data_encoding::HEXLOWER_PERMISSIVE;
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn test_store(root_dir: &Path) -> io::Result<Store> {
+        Store::open(root_dir, Keyring::new(0, MasterKey::generate()?))
+    }
+
+    fn object(version: u64, data: &[u8]) -> StoredObject {
+        StoredObject {
+            version,
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_read_known_objects() -> io::Result<()> {
+        // Objects as this format stores them, so that a change to the layout
+        // or to what is authenticated fails here: the header of version 7
+        // under key generation 1, a nonce of 0x24 bytes, and "vylfs object"
+        // sealed under a key of 0x42 bytes with the header and the name as
+        // associated data.
+        let header = "56594c4f02010000000700000000000000";
+        let vectors = [
+            (
+                Cipher::Aes256Gcm,
+                "24".repeat(12),
+                "63e8a8279ae6a95c4c8d41de2ed3b8fa120d06261da6bfb0efc7ee4e",
+            ),
+            (
+                Cipher::XChaCha20Poly1305,
+                "24".repeat(24),
+                "d327bb031d0423c4c84a5c4e4de9117dbd16f8bf6324e16b72896949",
+            ),
+            (
+                Cipher::Aes256GcmSiv,
+                "24".repeat(12),
+                "e9ac32d4c02ae8a8c1543531cd92e60267e6044cb27fec115aa0f2a5",
+            ),
+        ];
+        for (cipher, nonce, ciphertext) in vectors {
+            let temp_dir = tempdir()?;
+            let keyring = Keyring::new(1, MasterKey::from_bytes([0x42; 32]));
+            let store = Store::with_cipher(temp_dir.path(), keyring, cipher)?;
+            let contents = HEXLOWER_PERMISSIVE
+                .decode(format!("{header}{nonce}{ciphertext}").as_bytes())
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            fs::write(store.path("inode-2"), &contents)?;
+
+            assert_eq!(
+                store.read("inode-2")?,
+                Some(object(7, b"vylfs object")),
+                "{cipher}"
+            );
+            // The name is authenticated, so the object does not read as
+            // another one.
+            fs::rename(store.path("inode-2"), store.path("inode-3"))?;
+            let result = store.read("inode-3");
+            assert!(result.is_err(), "Expected an error, but got {:?}", result);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_read_remove() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let store = test_store(temp_dir.path())?;
+
+        store.write("b", 2, b"second")?;
+        store.write("a", 1, b"first")?;
+        assert_eq!(store.read("a")?, Some(object(1, b"first")));
+        assert_eq!(store.names()?, ["a", "b"]);
+
+        store.remove("a")?;
+        store.remove("a")?;
+        assert_eq!(store.read("a")?, None);
+        assert_eq!(store.names()?, ["b"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_swapped_objects_do_not_authenticate() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let store = test_store(temp_dir.path())?;
+        store.write("a", 1, b"first")?;
+        store.write("b", 2, b"second")?;
+
+        fs::copy(store.path("a"), store.path("b"))?;
+        let result = store.read("b");
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+
+        fs::write(store.path("a"), b"VYLO")?;
+        let result = store.read("a");
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_version_is_authenticated() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let store = test_store(temp_dir.path())?;
+        store.write("a", 7, b"first")?;
+        assert_eq!(store.header("a")?.map(|header| header.version), Some(7));
+
+        let mut contents = fs::read(store.path("a"))?;
+        contents[LEGACY_HEADER_LEN] = 8;
+        fs::write(store.path("a"), contents)?;
+        let result = store.read("a");
+        assert!(result.is_err(), "Expected an error, but got {:?}", result);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_legacy_objects_read_as_version_zero() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let store = test_store(temp_dir.path())?;
+        store.write_legacy("a", b"legacy")?;
+
+        assert_eq!(store.read("a")?, Some(object(0, b"legacy")));
+        assert_eq!(
+            store.header("a")?,
+            Some(ObjectHeader {
+                generation: 0,
+                version: 0
+            })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_objects_use_the_vault_cipher() -> io::Result<()> {
+        let keyring = Keyring::new(0, MasterKey::generate()?);
+        for cipher in [Cipher::Aes256Gcm, Cipher::Aes256GcmSiv] {
+            let temp_dir = tempdir()?;
+            let store = Store::with_cipher(temp_dir.path(), keyring.clone(), cipher)?;
+            store.write("a", 1, b"secret")?;
+            assert_eq!(store.read("a")?, Some(object(1, b"secret")));
+            let contents = fs::read(store.path("a"))?;
+            assert_eq!(
+                contents.len(),
+                OBJECT_HEADER_LEN + cipher.nonce_len() + b"secret".len() + 16
+            );
+
+            let other = Store::open(temp_dir.path(), keyring.clone())?;
+            let result = other.read("a");
+            assert!(result.is_err(), "Expected an error, but got {:?}", result);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reencrypt_moves_to_current_generation() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let mut keyring = Keyring::new(0, MasterKey::generate()?);
+        let mut store = Store::open(temp_dir.path(), keyring.clone())?;
+        store.write("a", 1, b"first")?;
+
+        let key = MasterKey::generate()?;
+        keyring.insert(1, key.clone());
+        store.add_key(1, key);
+        store.write("b", 2, b"second")?;
+        assert_eq!(store.generation("a")?, Some(0));
+        assert_eq!(store.generation("b")?, Some(1));
+
+        assert!(store.reencrypt("a")?);
+        assert_eq!(store.header("a")?.map(|header| header.version), Some(1));
+        assert!(!store.reencrypt("b")?);
+        assert!(!store.reencrypt("missing")?);
+        assert_eq!(store.generation("a")?, Some(1));
+
+        // Once re-encrypted, objects no longer need the old key.
+        let store = Store::open(temp_dir.path(), keyring.retain_current())?;
+        assert_eq!(store.read("a")?, Some(object(1, b"first")));
+
+        // Retired keys are dropped from the store that re-encrypted.
+        let mut store = Store::open(temp_dir.path(), keyring)?;
+        store.write("c", 3, b"third")?;
+        store.retire_keys();
+        assert_eq!(store.read("c")?, Some(object(3, b"third")));
+        assert_eq!(store.keyring.len(), 1);
+
+        Ok(())
+    }
+}